@@ -0,0 +1,17 @@
+//! A single frame off a serial link, delimiter already stripped.
+
+#![no_main]
+
+use flock_api::framing;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(decoded) = framing::cobs_decode(data) {
+        assert_eq!(framing::cobs_decode(&framing::cobs_encode(&decoded)), Some(decoded));
+    }
+    if let Ok(payload) = framing::decode_frame(data) {
+        let mut frame = framing::encode_frame(&payload);
+        frame.pop();
+        assert_eq!(framing::decode_frame(&frame).unwrap(), payload);
+    }
+});
@@ -0,0 +1,16 @@
+//! Whatever arrives from the broker, as decoded by the host and (with its lower limit) the node.
+
+#![no_main]
+
+use flock_api::codec;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = codec::decode(data) {
+        // Anything that decodes has to survive a round trip
+        let encoded = codec::encode(&msg).unwrap();
+        let decoded = codec::decode(&encoded).unwrap();
+        assert_eq!(codec::encode(&decoded).unwrap(), encoded);
+    }
+    let _ = codec::decode_with_limit(data, codec::MAX_INSTRUCTION_LEN);
+});
@@ -0,0 +1,16 @@
+//! A whole serial byte stream, through frame splitting, CRC checks and message decoding.
+
+#![no_main]
+
+use flock_api::codec::MAX_INSTRUCTION_LEN;
+use flock_api::framing::FramedReceiver;
+use flock_api::transport::TransportReceiver;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut receiver = FramedReceiver::with_max_len(data, MAX_INSTRUCTION_LEN);
+    while let Some(incoming) = receiver.receive() {
+        // Reading from a slice can't fail, so neither can the receiver
+        incoming.unwrap();
+    }
+});
@@ -0,0 +1,423 @@
+//! Wire encoding of `Message`, shared by every transport.
+//!
+//! Input is untrusted, so decoding refuses anything longer than a limit before parsing it. The
+//! decoded message never takes much more memory than its encoding: every JSON value is at least
+//! one byte, and serde_json caps nesting depth.
+
+use crate::Message;
+use serde::de::Error;
+
+/// Longest message `decode` accepts. Readings carry frames as JSON arrays of numbers, so a QVGA
+/// RGB565 frame alone takes up to ~600 KB.
+pub const MAX_MESSAGE_LEN: usize = 4 * 1024 * 1024;
+
+/// Longest message a node needs to accept, since all it receives are instructions.
+pub const MAX_INSTRUCTION_LEN: usize = 16 * 1024;
+
+pub fn encode(msg: &Message) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(msg)
+}
+
+pub fn decode(data: &[u8]) -> serde_json::Result<Message> {
+    decode_with_limit(data, MAX_MESSAGE_LEN)
+}
+
+/// Like `decode`, but with a different size limit.
+pub fn decode_with_limit(data: &[u8], max_len: usize) -> serde_json::Result<Message> {
+    if data.len() > max_len {
+        return Err(serde_json::Error::custom(format!(
+            "message is {} bytes, more than the limit of {}",
+            data.len(),
+            max_len
+        )));
+    }
+    serde_json::from_slice(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use proptest::prelude::*;
+
+    // Adding a variant breaks the build here, as a reminder to add it to the strategies below
+    fn _payload_strategy_is_exhaustive(payload: &Payload) {
+        match payload {
+            Payload::Connected
+            | Payload::Disconnected
+            | Payload::Instruction(_)
+            | Payload::SensorReading(_)
+            | Payload::StoredReading(_)
+            | Payload::BurstReading(_)
+            | Payload::StoredCaptures(_)
+            | Payload::HttpServer(_)
+            | Payload::SensorConfig(_)
+            | Payload::SensorInfo(_)
+            | Payload::PowerMode(_)
+            | Payload::FrameEncoding(_)
+            | Payload::Roi(_)
+            | Payload::MotionDetection(_)
+            | Payload::Motion(_)
+            | Payload::Actuators(_)
+            | Payload::Error(_) => {}
+        }
+    }
+
+    fn _instruction_strategy_is_exhaustive(instruction: &Instruction) {
+        match instruction {
+            Instruction::ReadSensor
+            | Instruction::ReadSensorEncoded(_)
+            | Instruction::Burst(_)
+            | Instruction::ReadSensorConfig
+            | Instruction::WriteSensorConfig(_)
+            | Instruction::ReadSensorInfo
+            | Instruction::ReadPowerMode
+            | Instruction::WritePowerMode(_)
+            | Instruction::ReadFrameEncoding
+            | Instruction::WriteFrameEncoding(_)
+            | Instruction::ReadRoi
+            | Instruction::WriteRoi(_)
+            | Instruction::ReadMotionDetection
+            | Instruction::WriteMotionDetection(_)
+            | Instruction::ReadActuators
+            | Instruction::WriteActuator(_)
+            | Instruction::ListCaptures
+            | Instruction::FetchCapture(_)
+            | Instruction::DeleteCapture(_)
+            | Instruction::ReadHttpServer
+            | Instruction::EnableHttpServer(_)
+            | Instruction::DisableHttpServer => {}
+        }
+    }
+
+    fn camera_sensor_config() -> impl Strategy<Value = CameraSensorConfig> {
+        (
+            (any::<i8>(), any::<i8>(), any::<i8>(), any::<i8>()),
+            (any::<u8>(), any::<u8>(), any::<u8>(), any::<u8>()),
+            (any::<bool>(), any::<bool>(), any::<bool>()),
+            (any::<bool>(), any::<bool>()),
+        )
+            .prop_map(
+                |(
+                    (brightness, contrast, saturation, sharpness),
+                    (de_noise, special_effect, wb_mode, gain_ceiling),
+                    (awb, awb_gain, lens_correction),
+                    (horizontal_mirror, vertical_flip),
+                )| CameraSensorConfig {
+                    brightness,
+                    contrast,
+                    saturation,
+                    sharpness,
+                    de_noise,
+                    special_effect,
+                    wb_mode,
+                    awb,
+                    awb_gain,
+                    gain_ceiling,
+                    lens_correction,
+                    horizontal_mirror,
+                    vertical_flip,
+                },
+            )
+    }
+
+    fn sensor_config() -> impl Strategy<Value = SensorConfig> {
+        camera_sensor_config().prop_map(SensorConfig::Camera)
+    }
+
+    fn frame_format() -> impl Strategy<Value = FrameFormat> {
+        let pixel_format = prop_oneof![
+            Just(PixelFormat::Rgb565),
+            Just(PixelFormat::Yuv422),
+            Just(PixelFormat::Yuv420),
+            Just(PixelFormat::Grayscale),
+            Just(PixelFormat::Jpeg),
+            Just(PixelFormat::Rgb888),
+            Just(PixelFormat::Raw),
+            Just(PixelFormat::Rgb444),
+            Just(PixelFormat::Rgb555),
+        ];
+        (pixel_format, any::<u32>(), any::<u32>()).prop_map(|(pixel_format, width, height)| {
+            FrameFormat {
+                pixel_format,
+                width,
+                height,
+            }
+        })
+    }
+
+    fn sensor_data() -> impl Strategy<Value = SensorData> {
+        (prop::collection::vec(any::<u8>(), 0..512), frame_format()).prop_map(
+            |(frame_buffer, format)| SensorData::Camera {
+                frame_buffer,
+                format,
+            },
+        )
+    }
+
+    fn power_mode() -> impl Strategy<Value = PowerMode> {
+        prop_oneof![
+            Just(PowerMode::AlwaysOn),
+            any::<u32>().prop_map(|sleep_interval_secs| PowerMode::LowPower {
+                sleep_interval_secs
+            }),
+        ]
+    }
+
+    fn frame_encoding() -> impl Strategy<Value = FrameEncoding> {
+        prop_oneof![
+            Just(FrameEncoding::Raw),
+            any::<u8>().prop_map(|quality| FrameEncoding::Jpeg { quality }),
+        ]
+    }
+
+    fn roi() -> impl Strategy<Value = Roi> {
+        (
+            (any::<u32>(), any::<u32>(), any::<u32>(), any::<u32>()),
+            (any::<Option<u32>>(), any::<Option<u32>>()),
+        )
+            .prop_map(
+                |((x, y, width, height), (output_width, output_height))| Roi {
+                    x,
+                    y,
+                    width,
+                    height,
+                    output_width,
+                    output_height,
+                },
+            )
+    }
+
+    fn shot_settings() -> impl Strategy<Value = ShotSettings> {
+        (
+            any::<Option<i8>>(),
+            any::<Option<u16>>(),
+            any::<Option<u8>>(),
+        )
+            .prop_map(|(ae_level, aec_value, agc_gain)| ShotSettings {
+                ae_level,
+                aec_value,
+                agc_gain,
+            })
+    }
+
+    fn burst() -> impl Strategy<Value = Burst> {
+        (
+            any::<u8>(),
+            any::<u32>(),
+            prop::collection::vec(shot_settings(), 0..8),
+            proptest::option::of(frame_encoding()),
+        )
+            .prop_map(|(count, interval_ms, shots, encoding)| Burst {
+                count,
+                interval_ms,
+                shots,
+                encoding,
+            })
+    }
+
+    fn motion_detection() -> impl Strategy<Value = MotionDetection> {
+        (
+            any::<u8>(),
+            any::<u16>(),
+            any::<u32>(),
+            any::<u32>(),
+            any::<bool>(),
+        )
+            .prop_map(
+                |(threshold, min_area, interval_ms, cooldown_ms, include_frame)| MotionDetection {
+                    threshold,
+                    min_area,
+                    interval_ms,
+                    cooldown_ms,
+                    include_frame,
+                },
+            )
+    }
+
+    fn actuator() -> impl Strategy<Value = Actuator> {
+        let state = prop_oneof![
+            Just(ActuatorState::Off),
+            Just(ActuatorState::On),
+            any::<u8>().prop_map(|duty| ActuatorState::Pwm { duty }),
+            (any::<u8>(), any::<u32>())
+                .prop_map(|(duty, lead_ms)| ActuatorState::CapturePulse { duty, lead_ms }),
+        ];
+        (any::<String>(), any::<i32>(), state).prop_map(|(name, pin, state)| Actuator {
+            name,
+            pin,
+            state,
+        })
+    }
+
+    fn stored_capture() -> impl Strategy<Value = StoredCapture> {
+        (
+            any::<u32>(),
+            any::<u64>(),
+            any::<u32>(),
+            any::<bool>(),
+            frame_format(),
+        )
+            .prop_map(|(id, captured_at, size, uploaded, format)| StoredCapture {
+                id,
+                captured_at,
+                size,
+                uploaded,
+                format,
+            })
+    }
+
+    fn instruction() -> impl Strategy<Value = Instruction> {
+        prop_oneof![
+            Just(Instruction::ReadSensor),
+            frame_encoding().prop_map(Instruction::ReadSensorEncoded),
+            burst().prop_map(Instruction::Burst),
+            Just(Instruction::ReadSensorConfig),
+            sensor_config().prop_map(Instruction::WriteSensorConfig),
+            Just(Instruction::ReadSensorInfo),
+            Just(Instruction::ReadPowerMode),
+            power_mode().prop_map(Instruction::WritePowerMode),
+            Just(Instruction::ReadFrameEncoding),
+            frame_encoding().prop_map(Instruction::WriteFrameEncoding),
+            Just(Instruction::ReadRoi),
+            proptest::option::of(roi()).prop_map(Instruction::WriteRoi),
+            Just(Instruction::ReadMotionDetection),
+            proptest::option::of(motion_detection()).prop_map(Instruction::WriteMotionDetection),
+            Just(Instruction::ReadActuators),
+            actuator().prop_map(Instruction::WriteActuator),
+            Just(Instruction::ListCaptures),
+            any::<u32>().prop_map(Instruction::FetchCapture),
+            any::<u32>().prop_map(Instruction::DeleteCapture),
+            Just(Instruction::ReadHttpServer),
+            (any::<u16>(), any::<String>()).prop_map(
+                |(port, token)| Instruction::EnableHttpServer(HttpServerConfig { port, token })
+            ),
+            Just(Instruction::DisableHttpServer),
+        ]
+    }
+
+    fn payload() -> impl Strategy<Value = Payload> {
+        prop_oneof![
+            Just(Payload::Connected),
+            Just(Payload::Disconnected),
+            instruction().prop_map(Payload::Instruction),
+            sensor_data().prop_map(Payload::SensorReading),
+            (stored_capture(), sensor_data()).prop_map(|(capture, reading)| {
+                Payload::StoredReading(StoredReading { capture, reading })
+            }),
+            (
+                any::<u8>(),
+                any::<u8>(),
+                any::<u32>(),
+                shot_settings(),
+                sensor_data()
+            )
+                .prop_map(|(index, count, offset_ms, settings, reading)| {
+                    Payload::BurstReading(BurstShot {
+                        index,
+                        count,
+                        offset_ms,
+                        settings,
+                        reading,
+                    })
+                }),
+            prop::collection::vec(stored_capture(), 0..8).prop_map(Payload::StoredCaptures),
+            (any::<bool>(), any::<Option<String>>())
+                .prop_map(|(enabled, url)| Payload::HttpServer(HttpServerStatus { enabled, url })),
+            sensor_config().prop_map(Payload::SensorConfig),
+            (
+                any::<u16>(),
+                any::<u16>(),
+                any::<u8>(),
+                any::<Option<String>>()
+            )
+                .prop_map(|(manufacturer_id, product_id, version, model)| {
+                    Payload::SensorInfo(SensorInfo {
+                        manufacturer_id,
+                        product_id,
+                        version,
+                        model,
+                    })
+                }),
+            power_mode().prop_map(Payload::PowerMode),
+            frame_encoding().prop_map(Payload::FrameEncoding),
+            (
+                proptest::option::of(roi()),
+                any::<bool>(),
+                any::<u32>(),
+                any::<u32>()
+            )
+                .prop_map(|(roi, windowed, width, height)| Payload::Roi(RoiStatus {
+                    roi,
+                    windowed,
+                    width,
+                    height,
+                })),
+            proptest::option::of(motion_detection()).prop_map(Payload::MotionDetection),
+            (any::<u16>(), proptest::option::of(sensor_data()))
+                .prop_map(|(area, reading)| Payload::Motion(MotionAlert { area, reading })),
+            prop::collection::vec(actuator(), 0..4).prop_map(Payload::Actuators),
+            any::<String>().prop_map(Payload::Error),
+        ]
+    }
+
+    fn message() -> impl Strategy<Value = Message> {
+        (any::<String>(), any::<String>(), payload())
+            .prop_map(|(client_id, recipient, payload)| Message::new(client_id, recipient, payload))
+    }
+
+    proptest! {
+        #[test]
+        fn messages_round_trip(msg in message()) {
+            // Message has no PartialEq, so compare encodings
+            let encoded = encode(&msg).unwrap();
+            let decoded = decode(&encoded).unwrap();
+            prop_assert_eq!(encode(&decoded).unwrap(), encoded);
+        }
+
+        #[test]
+        fn instructions_fit_the_node_limit(instruction in instruction()) {
+            let msg = Message::new(
+                "flock-controller".into(),
+                "flock-client-1".into(),
+                Payload::Instruction(instruction),
+            );
+            let encoded = encode(&msg).unwrap();
+            // Strings are unbounded, but realistic ones are far below the limit
+            prop_assume!(msg.client_id.len() + msg.recipient.len() < 1024);
+            prop_assert!(decode_with_limit(&encoded, MAX_INSTRUCTION_LEN).is_ok());
+        }
+
+        #[test]
+        fn garbage_never_panics(data in prop::collection::vec(any::<u8>(), 0..1024)) {
+            let _ = decode(&data);
+        }
+    }
+
+    #[test]
+    fn frames_from_older_nodes_default_to_qvga_rgb565() {
+        let msg = decode(
+            br#"{"clientId":"flock-client-1","recipient":"flock-controller",
+                "payload":{"sensorReading":{"camera":{"frame_buffer":[248,0]}}}}"#,
+        )
+        .unwrap();
+        let Payload::SensorReading(SensorData::Camera { format, .. }) = *msg.payload else {
+            panic!("not a reading");
+        };
+        assert_eq!(format, FrameFormat::default());
+        assert_eq!(format.pixel_format, PixelFormat::Rgb565);
+        assert_eq!((format.width, format.height), (320, 240));
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let msg = Message::new(
+            "flock-client-1".into(),
+            "flock-controller".into(),
+            Payload::Error("x".repeat(MAX_INSTRUCTION_LEN)),
+        );
+        let encoded = encode(&msg).unwrap();
+        assert!(decode(&encoded).is_ok());
+        assert!(decode_with_limit(&encoded, MAX_INSTRUCTION_LEN).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Operations on uncompressed frames, shared by nodes and the tools that simulate them.
+
+use crate::{FrameFormat, PixelFormat, Roi};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CropError {
+    /// Compressed or planar formats can't be cut up byte-wise
+    Unsupported(PixelFormat),
+    /// The region doesn't lie within the frame
+    OutOfBounds,
+    /// The output is empty or bigger than the region, only downscaling is supported
+    Output,
+    /// The frame is shorter or longer than its format says
+    Length,
+    /// YUV422 pixels come in pairs that share chroma, so horizontal positions and sizes have to
+    /// be even
+    Alignment,
+}
+
+impl fmt::Display for CropError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CropError::Unsupported(format) => write!(f, "can't crop {:?} frames", format),
+            CropError::OutOfBounds => write!(f, "region is outside the frame"),
+            CropError::Output => {
+                write!(f, "output must be non-empty and no bigger than the region")
+            }
+            CropError::Length => write!(f, "frame length doesn't match its format"),
+            CropError::Alignment => write!(f, "YUV422 regions need even x, width and output width"),
+        }
+    }
+}
+
+impl std::error::Error for CropError {}
+
+/// Bytes per unit and pixels per unit, where a unit is the smallest run of pixels that can be
+/// copied on its own. `None` for formats without one.
+fn unit(format: PixelFormat) -> Option<(usize, u32)> {
+    match format {
+        PixelFormat::Grayscale => Some((1, 1)),
+        PixelFormat::Rgb565 | PixelFormat::Rgb555 | PixelFormat::Rgb444 => Some((2, 1)),
+        PixelFormat::Rgb888 => Some((3, 1)),
+        PixelFormat::Yuv422 => Some((4, 2)),
+        PixelFormat::Yuv420 | PixelFormat::Jpeg | PixelFormat::Raw => None,
+    }
+}
+
+/// Whether frames in `format` can be cropped at all.
+pub fn can_crop(format: PixelFormat) -> bool {
+    unit(format).is_some()
+}
+
+/// Checks that `roi` can be cut out of frames of `width` x `height`.
+pub fn check_roi(roi: &Roi, width: u32, height: u32) -> Result<(), CropError> {
+    let (out_width, out_height) = roi.output_size();
+    let right = roi.x.checked_add(roi.width);
+    let bottom = roi.y.checked_add(roi.height);
+    if !matches!(right, Some(r) if r <= width) || !matches!(bottom, Some(b) if b <= height) {
+        return Err(CropError::OutOfBounds);
+    }
+    if out_width == 0 || out_height == 0 || out_width > roi.width || out_height > roi.height {
+        return Err(CropError::Output);
+    }
+    Ok(())
+}
+
+/// Cuts `roi` out of a frame and scales it down to the output size (nearest neighbour, which is
+/// cheap enough for a node). Returns the new frame and its format.
+pub fn crop(
+    data: &[u8],
+    format: &FrameFormat,
+    roi: &Roi,
+) -> Result<(Vec<u8>, FrameFormat), CropError> {
+    let (unit_len, unit_px) =
+        unit(format.pixel_format).ok_or(CropError::Unsupported(format.pixel_format))?;
+    check_roi(roi, format.width, format.height)?;
+    let (out_width, out_height) = roi.output_size();
+    if [format.width, roi.x, roi.width, out_width]
+        .iter()
+        .any(|v| v % unit_px != 0)
+    {
+        return Err(CropError::Alignment);
+    }
+    let row_len = (format.width / unit_px) as usize * unit_len;
+    if data.len() != row_len * format.height as usize {
+        return Err(CropError::Length);
+    }
+
+    // Work in units, so YUV422 pairs stay together
+    let (x, width, out_units) = (roi.x / unit_px, roi.width / unit_px, out_width / unit_px);
+    let mut out = Vec::with_capacity(out_units as usize * unit_len * out_height as usize);
+    for out_y in 0..out_height {
+        let y = roi.y + out_y * roi.height / out_height;
+        let row = &data[y as usize * row_len..][..row_len];
+        for out_x in 0..out_units {
+            let u = (x + out_x * width / out_units) as usize;
+            out.extend_from_slice(&row[u * unit_len..][..unit_len]);
+        }
+    }
+    let format = FrameFormat {
+        width: out_width,
+        height: out_height,
+        ..*format
+    };
+    Ok((out, format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roi(x: u32, y: u32, width: u32, height: u32, output: Option<(u32, u32)>) -> Roi {
+        Roi {
+            x,
+            y,
+            width,
+            height,
+            output_width: output.map(|o| o.0),
+            output_height: output.map(|o| o.1),
+        }
+    }
+
+    fn gray(width: u32, height: u32) -> (Vec<u8>, FrameFormat) {
+        let data = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (y * 16 + x) as u8))
+            .collect();
+        let format = FrameFormat {
+            pixel_format: PixelFormat::Grayscale,
+            width,
+            height,
+        };
+        (data, format)
+    }
+
+    #[test]
+    fn crops_and_downscales() {
+        let (data, format) = gray(8, 8);
+        let (out, out_format) = crop(&data, &format, &roi(2, 1, 4, 2, None)).unwrap();
+        assert_eq!((out_format.width, out_format.height), (4, 2));
+        assert_eq!(out, [0x12, 0x13, 0x14, 0x15, 0x22, 0x23, 0x24, 0x25]);
+
+        let (out, out_format) = crop(&data, &format, &roi(0, 0, 8, 8, Some((2, 2)))).unwrap();
+        assert_eq!((out_format.width, out_format.height), (2, 2));
+        assert_eq!(out, [0x00, 0x04, 0x40, 0x44]);
+    }
+
+    #[test]
+    fn keeps_yuv422_pairs_together() {
+        let format = FrameFormat {
+            pixel_format: PixelFormat::Yuv422,
+            width: 4,
+            height: 1,
+        };
+        let data = [0, 1, 2, 3, 4, 5, 6, 7];
+        let (out, _) = crop(&data, &format, &roi(2, 0, 2, 1, None)).unwrap();
+        assert_eq!(out, [4, 5, 6, 7]);
+        assert_eq!(
+            crop(&data, &format, &roi(1, 0, 2, 1, None)),
+            Err(CropError::Alignment)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_regions() {
+        let (data, format) = gray(8, 8);
+        let crop = |r| crop(&data, &format, &r).map(|_| ());
+        assert_eq!(crop(roi(4, 0, 5, 1, None)), Err(CropError::OutOfBounds));
+        assert_eq!(
+            crop(roi(u32::MAX, 0, 2, 1, None)),
+            Err(CropError::OutOfBounds)
+        );
+        assert_eq!(crop(roi(0, 0, 2, 2, Some((4, 4)))), Err(CropError::Output));
+        assert_eq!(crop(roi(0, 0, 2, 2, Some((0, 1)))), Err(CropError::Output));
+
+        let jpeg = FrameFormat {
+            pixel_format: PixelFormat::Jpeg,
+            ..format
+        };
+        assert_eq!(
+            super::crop(&data, &jpeg, &roi(0, 0, 1, 1, None)),
+            Err(CropError::Unsupported(PixelFormat::Jpeg))
+        );
+        assert!(!can_crop(PixelFormat::Jpeg));
+        assert!(can_crop(PixelFormat::Rgb565));
+    }
+}
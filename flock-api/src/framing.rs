@@ -0,0 +1,402 @@
+//! Framing for byte-stream links such as a UART.
+//!
+//! Each message is encoded with `codec`, followed by its CRC-32 (little endian), COBS-encoded and
+//! terminated by a zero byte. COBS guarantees the frame itself contains no zero bytes, so a reader
+//! can always resynchronize on the next delimiter after line noise or a partial frame.
+
+use crate::transport::{Incoming, TransportReceiver, TransportSender};
+use crate::Message;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const DELIMITER: u8 = 0;
+
+/// Longest frame a reader accepts before discarding it, so garbage on the line can't exhaust
+/// the heap. Holds any message `codec` accepts.
+pub const MAX_FRAME_LEN: usize = frame_len(crate::codec::MAX_MESSAGE_LEN);
+
+/// Longest frame for a message of `message_len` bytes: the CRC and COBS overhead on top of it.
+pub const fn frame_len(message_len: usize) -> usize {
+    let data_len = message_len + 4;
+    data_len + data_len / 254 + 1
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    /// The frame isn't valid COBS
+    Encoding,
+    /// The frame is too short to hold a CRC, or the CRC doesn't match
+    Checksum,
+    TooLong,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(err) => write!(f, "i/o error: {}", err),
+            FrameError::Encoding => write!(f, "invalid COBS encoding"),
+            FrameError::Checksum => write!(f, "CRC mismatch"),
+            FrameError::TooLong => write!(f, "frame too long"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<io::Error> for FrameError {
+    fn from(err: io::Error) -> Self {
+        FrameError::Io(err)
+    }
+}
+
+/// CRC-32 (IEEE 802.3, as used by zlib and PNG)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+pub fn cobs_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 254 + 2);
+    let mut code_idx = 0;
+    out.push(0);
+    for byte in data {
+        if *byte == 0 {
+            out[code_idx] = (out.len() - code_idx) as u8;
+            code_idx = out.len();
+            out.push(0);
+        } else {
+            out.push(*byte);
+            if out.len() - code_idx == 0xFF {
+                out[code_idx] = 0xFF;
+                code_idx = out.len();
+                out.push(0);
+            }
+        }
+    }
+    out[code_idx] = (out.len() - code_idx) as u8;
+    out
+}
+
+pub fn cobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut idx = 0;
+    while idx < data.len() {
+        let code = data[idx] as usize;
+        if code == 0 || idx + code > data.len() {
+            return None;
+        }
+        out.extend_from_slice(&data[idx + 1..idx + code]);
+        idx += code;
+        if code < 0xFF && idx < data.len() {
+            out.push(0);
+        }
+    }
+    Some(out)
+}
+
+/// Frames `payload`, including the trailing delimiter.
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(payload.len() + 4);
+    data.extend_from_slice(payload);
+    data.extend_from_slice(&crc32(payload).to_le_bytes());
+    let mut frame = cobs_encode(&data);
+    frame.push(DELIMITER);
+    frame
+}
+
+/// Recovers the payload of a frame, without its delimiter.
+pub fn decode_frame(frame: &[u8]) -> Result<Vec<u8>, FrameError> {
+    let mut data = cobs_decode(frame).ok_or(FrameError::Encoding)?;
+    if data.len() < 4 {
+        return Err(FrameError::Checksum);
+    }
+    let crc = data.split_off(data.len() - 4);
+    if crc32(&data).to_le_bytes() != crc[..] {
+        return Err(FrameError::Checksum);
+    }
+    Ok(data)
+}
+
+/// Splits a byte stream on frame delimiters.
+pub struct FrameReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+    max_len: usize,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_max_len(inner, MAX_FRAME_LEN)
+    }
+
+    /// Reader that rejects frames longer than `max_len` instead of `MAX_FRAME_LEN`.
+    pub fn with_max_len(inner: R, max_len: usize) -> Self {
+        Self {
+            inner,
+            buf: vec![0; 512],
+            pos: 0,
+            len: 0,
+            max_len,
+        }
+    }
+
+    /// Reads the next frame (without delimiter). `Ok(None)` at end of stream.
+    pub fn read_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let mut frame = vec![];
+        let mut overflowed = false;
+        loop {
+            if self.pos == self.len {
+                self.len = match self.inner.read(&mut self.buf) {
+                    Ok(0) => return Ok(None),
+                    Ok(n) => n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err.into()),
+                };
+                self.pos = 0;
+            }
+            let chunk = &self.buf[self.pos..self.len];
+            match chunk.iter().position(|b| *b == DELIMITER) {
+                Some(end) => {
+                    self.pos += end + 1;
+                    if overflowed || frame.len() + end > self.max_len {
+                        return Err(FrameError::TooLong);
+                    }
+                    frame.extend_from_slice(&chunk[..end]);
+                    // Back-to-back delimiters are just idle line, not empty frames
+                    if frame.is_empty() {
+                        continue;
+                    }
+                    return Ok(Some(frame));
+                }
+                None => {
+                    self.pos = self.len;
+                    if frame.len() + chunk.len() > self.max_len {
+                        // Keep reading up to the next delimiter, but stop buffering
+                        overflowed = true;
+                        frame.clear();
+                    } else if !overflowed {
+                        frame.extend_from_slice(chunk);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sending half of a framed point-to-point link. Everything written goes to the other end of the
+/// link, whatever the recipient, so subscribing is a no-op.
+pub struct FramedSender<W> {
+    inner: W,
+    address: String,
+}
+
+impl<W: Write> FramedSender<W> {
+    pub fn new(inner: W, address: String) -> Self {
+        Self { inner, address }
+    }
+}
+
+impl<W: Write> TransportSender for FramedSender<W> {
+    type Error = FrameError;
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn subscribe(&mut self, _address: &str) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn send(&mut self, msg: &Message) -> Result<(), Self::Error> {
+        let payload = crate::codec::encode(msg)
+            .map_err(|err| FrameError::Io(io::Error::new(io::ErrorKind::InvalidInput, err)))?;
+        self.inner.write_all(&encode_frame(&payload))?;
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+/// Receiving half of a framed point-to-point link.
+pub struct FramedReceiver<R> {
+    reader: FrameReader<R>,
+    max_message_len: usize,
+    connected: bool,
+}
+
+impl<R: Read> FramedReceiver<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            reader: FrameReader::new(inner),
+            max_message_len: crate::codec::MAX_MESSAGE_LEN,
+            connected: false,
+        }
+    }
+
+    /// Receiver that only accepts messages up to `max_len` bytes, e.g.
+    /// `codec::MAX_INSTRUCTION_LEN` on a node. Longer frames are discarded without buffering them.
+    pub fn with_max_len(inner: R, max_len: usize) -> Self {
+        Self {
+            reader: FrameReader::with_max_len(inner, frame_len(max_len)),
+            max_message_len: max_len,
+            connected: false,
+        }
+    }
+}
+
+impl<R: Read> TransportReceiver for FramedReceiver<R> {
+    type Error = FrameError;
+
+    fn receive(&mut self) -> Option<Result<Incoming, Self::Error>> {
+        // A link has no session handshake, it's up as soon as it's open
+        if !self.connected {
+            self.connected = true;
+            return Some(Ok(Incoming::Connected));
+        }
+        let frame = match self.reader.read_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return None,
+            Err(FrameError::Io(err)) => return Some(Err(FrameError::Io(err))),
+            Err(err) => return Some(Ok(Incoming::Malformed(err.to_string()))),
+        };
+        Some(Ok(match decode_frame(&frame) {
+            Ok(payload) => match crate::codec::decode_with_limit(&payload, self.max_message_len) {
+                Ok(msg) => Incoming::Message(msg),
+                Err(err) => Incoming::Malformed(format!("{:?}", err)),
+            },
+            Err(err) => Incoming::Malformed(err.to_string()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn cobs_round_trip() {
+        let cases: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0],
+            vec![0, 0],
+            vec![1, 2, 0, 3],
+            (1..=254).collect(),
+            (0..=255).cycle().take(1000).collect(),
+        ];
+        for data in cases {
+            let encoded = cobs_encode(&data);
+            assert!(!encoded.contains(&0), "zero in encoding of {:?}", data);
+            assert_eq!(cobs_decode(&encoded).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn corrupted_frame_is_rejected() {
+        let mut frame = encode_frame(b"hello");
+        frame.pop();
+        frame[2] ^= 0x01;
+        assert!(matches!(decode_frame(&frame), Err(FrameError::Checksum)));
+    }
+
+    #[test]
+    fn reader_resynchronizes_after_noise() {
+        let mut stream = b"noise".to_vec();
+        stream.push(0);
+        stream.extend(encode_frame(b"first"));
+        stream.extend(encode_frame(b"second"));
+        let mut reader = FrameReader::new(stream.as_slice());
+
+        let noise = reader.read_frame().unwrap().unwrap();
+        assert!(decode_frame(&noise).is_err());
+        assert_eq!(
+            decode_frame(&reader.read_frame().unwrap().unwrap()).unwrap(),
+            b"first"
+        );
+        assert_eq!(
+            decode_frame(&reader.read_frame().unwrap().unwrap()).unwrap(),
+            b"second"
+        );
+        assert!(reader.read_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn reader_rejects_oversized_frames() {
+        let mut stream = vec![1; MAX_FRAME_LEN + 1];
+        stream.push(0);
+        stream.extend(encode_frame(b"next"));
+        let mut reader = FrameReader::new(stream.as_slice());
+
+        assert!(matches!(reader.read_frame(), Err(FrameError::TooLong)));
+        assert_eq!(
+            decode_frame(&reader.read_frame().unwrap().unwrap()).unwrap(),
+            b"next"
+        );
+    }
+
+    #[test]
+    fn full_qvga_reading_round_trips() {
+        // Every byte at its longest in JSON
+        let msg = Message::new(
+            "flock-client-1".into(),
+            "flock-controller".into(),
+            crate::Payload::SensorReading(crate::SensorData::Camera {
+                frame_buffer: vec![0xFF; 320 * 240 * 2],
+                format: crate::FrameFormat::default(),
+            }),
+        );
+        let mut stream = vec![];
+        FramedSender::new(&mut stream, "flock-client-1".into())
+            .send(&msg)
+            .unwrap();
+        let mut receiver = FramedReceiver::new(stream.as_slice());
+
+        assert!(matches!(receiver.receive(), Some(Ok(Incoming::Connected))));
+        match receiver.receive() {
+            Some(Ok(Incoming::Message(received))) => assert_eq!(
+                crate::codec::encode(&received).unwrap(),
+                crate::codec::encode(&msg).unwrap()
+            ),
+            other => panic!("expected the reading, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn receiver_enforces_message_limit() {
+        let msg = Message::new(
+            "flock-controller".into(),
+            "flock-client-1".into(),
+            crate::Payload::Error("x".repeat(64)),
+        );
+        let mut stream = encode_frame(&crate::codec::encode(&msg).unwrap());
+        stream.extend(stream.clone());
+        let mut receiver = FramedReceiver::with_max_len(stream.as_slice(), 32);
+
+        assert!(matches!(receiver.receive(), Some(Ok(Incoming::Connected))));
+        assert!(matches!(
+            receiver.receive(),
+            Some(Ok(Incoming::Malformed(_)))
+        ));
+        assert!(matches!(
+            receiver.receive(),
+            Some(Ok(Incoming::Malformed(_)))
+        ));
+        assert!(receiver.receive().is_none());
+    }
+}
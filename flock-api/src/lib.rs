@@ -1,5 +1,14 @@
+pub mod auth;
+pub mod codec;
+pub mod frame;
+pub mod framing;
+mod mdns;
 mod message;
+#[cfg(feature = "schema")]
+pub mod schema;
+pub mod transport;
 
+pub use mdns::*;
 pub use message::*;
 
 
@@ -0,0 +1,11 @@
+//! Names used by camera nodes to advertise themselves over mDNS, and by controllers to find them.
+
+pub const MDNS_SERVICE_TYPE: &str = "_flock";
+pub const MDNS_SERVICE_PROTO: &str = "_tcp";
+
+/// TXT record holding the MQTT client ID, which is also the node's instruction topic
+pub const MDNS_TXT_CLIENT_ID: &str = "clientId";
+/// TXT record holding the camera sensor model (e.g. OV2640)
+pub const MDNS_TXT_SENSOR_MODEL: &str = "sensorModel";
+/// TXT record holding the firmware version
+pub const MDNS_TXT_FIRMWARE_VERSION: &str = "firmwareVersion";
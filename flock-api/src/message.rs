@@ -1,32 +1,215 @@
 // use crate::SensorStatus;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub enum Payload {
     Connected,
+    /// Published by the broker as a node's last will when its connection drops
     Disconnected,
     Instruction(Instruction),
     SensorReading(SensorData),
+    /// Reading that was also written to the node's SD card. Nodes with a card reply to
+    /// `ReadSensor` with this, and use it to upload captures taken while offline.
+    StoredReading(StoredReading),
+    /// One shot of a `Burst`, published once the whole burst has been taken
+    BurstReading(BurstShot),
+    StoredCaptures(Vec<StoredCapture>),
+    HttpServer(HttpServerStatus),
     SensorConfig(SensorConfig),
+    SensorInfo(SensorInfo),
+    PowerMode(PowerMode),
+    FrameEncoding(FrameEncoding),
+    Roi(RoiStatus),
+    /// `None` while the node isn't detecting motion
+    MotionDetection(Option<MotionDetection>),
+    /// Published unprompted when the node's own motion detection triggers
+    Motion(MotionAlert),
+    Actuators(Vec<Actuator>),
     Error(String),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub enum Instruction {
     ReadSensor,
+    /// Like `ReadSensor`, but with this encoding instead of the node's frame encoding
+    ReadSensorEncoded(FrameEncoding),
+    /// Takes several frames back to back, answered with one `BurstReading` per shot
+    Burst(Burst),
     ReadSensorConfig,
     WriteSensorConfig(SensorConfig),
+    /// Which sensor the node has, answered with `SensorInfo`
+    ReadSensorInfo,
+    ReadPowerMode,
+    /// Nodes in low-power mode are only reachable while awake, so this should
+    /// be published with the retain flag set to reach them on their next wake.
+    /// Nodes refuse a `sleep_interval_secs` of 0.
+    WritePowerMode(PowerMode),
+    ReadFrameEncoding,
+    /// Sets how readings are encoded unless an instruction asks otherwise, including the ones
+    /// low-power nodes publish on every wake.
+    WriteFrameEncoding(FrameEncoding),
+    ReadRoi,
+    /// Sends only part of each frame from now on, or whole frames again with `None`.
+    WriteRoi(Option<Roi>),
+    ReadMotionDetection,
+    /// Has the node watch for motion itself and only publish when it triggers, or stop with
+    /// `None`. Low-power nodes check once per wake, against the frame of their previous wake, and
+    /// publish their wake reading only when it shows motion.
+    WriteMotionDetection(Option<MotionDetection>),
+    ReadActuators,
+    /// Adds the actuator if no actuator with the same name exists, otherwise updates its state.
+    WriteActuator(Actuator),
+    ListCaptures,
+    FetchCapture(u32),
+    DeleteCapture(u32),
+    ReadHttpServer,
+    EnableHttpServer(HttpServerConfig),
+    DisableHttpServer,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub enum SensorData {
-    Camera { frame_buffer: Vec<u8> },
+    Camera {
+        frame_buffer: Vec<u8>,
+        /// Missing from older nodes, which always sent QVGA RGB565
+        #[serde(default)]
+        format: FrameFormat,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Pixel layout of a frame, as the camera driver was initialized with. `flock-imaging` documents
+/// the byte order of each.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum PixelFormat {
+    Rgb565,
+    Yuv422,
+    Yuv420,
+    Grayscale,
+    Jpeg,
+    Rgb888,
+    Raw,
+    Rgb444,
+    Rgb555,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct FrameFormat {
+    pub pixel_format: PixelFormat,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Default for FrameFormat {
+    /// The firmware's default camera config
+    fn default() -> Self {
+        Self {
+            pixel_format: PixelFormat::Rgb565,
+            width: 320,
+            height: 240,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Burst {
+    pub count: u8,
+    /// Time from the start of one shot to the start of the next. Shots take at least a frame
+    /// time, plus one more after settings change while the sensor settles.
+    pub interval_ms: u32,
+    /// Settings for each shot, in order. Shots past the end of the list are taken with the
+    /// node's settings. The node's settings are restored after the burst.
+    #[serde(default)]
+    pub shots: Vec<ShotSettings>,
+    /// The node's frame encoding if missing
+    #[serde(default)]
+    pub encoding: Option<FrameEncoding>,
+}
+
+/// Sensor settings to override for one shot. Setting a manual exposure (`aec_value`) or gain
+/// turns the corresponding automatic control off.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ShotSettings {
+    /// Automatic exposure target, from -2 to 2
+    #[serde(default)]
+    pub ae_level: Option<i8>,
+    /// Manual exposure, from 0 to 1200
+    #[serde(default)]
+    pub aec_value: Option<u16>,
+    /// Manual gain, from 0 to 30
+    #[serde(default)]
+    pub agc_gain: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct BurstShot {
+    /// Position in the burst, from 0
+    pub index: u8,
+    pub count: u8,
+    /// Milliseconds between the start of the burst and this shot
+    pub offset_ms: u32,
+    pub settings: ShotSettings,
+    pub reading: SensorData,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct StoredCapture {
+    pub id: u32,
+    /// Seconds since the UNIX epoch, as far as the node's clock knows
+    pub captured_at: u64,
+    pub size: u32,
+    /// Whether the capture has been published to the controller
+    pub uploaded: bool,
+    #[serde(default)]
+    pub format: FrameFormat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct StoredReading {
+    pub capture: StoredCapture,
+    pub reading: SensorData,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct HttpServerConfig {
+    pub port: u16,
+    /// Required as the `token` query parameter on every request. Nodes refuse an empty one, and
+    /// compare it with the parameter as sent, without URL decoding.
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct HttpServerStatus {
+    pub enabled: bool,
+    /// Base URL of the server, serving `/stream` (multipart MJPEG) and `/capture` (single JPEG)
+    pub url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct CameraSensorConfig {
     pub brightness: i8,
@@ -44,13 +227,143 @@ pub struct CameraSensorConfig {
     pub vertical_flip: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub enum SensorConfig {
     Camera(CameraSensorConfig),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The camera sensor as esp32-camera identifies it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct SensorInfo {
+    /// Manufacturer ID from the sensor's registers, 0x7FA2 for OmniVision
+    pub manufacturer_id: u16,
+    /// Product ID, e.g. 0x2642 for the OV2640
+    pub product_id: u16,
+    pub version: u8,
+    /// Model name, e.g. "OV2640". `None` for sensors esp32-camera doesn't know.
+    pub model: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum PowerMode {
+    /// Wi-Fi, MQTT and the camera stay up between instructions.
+    AlwaysOn,
+    /// The node wakes on a timer, captures and publishes a reading, picks up
+    /// any retained instructions and then deep-sleeps again.
+    LowPower { sleep_interval_secs: u32 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum FrameEncoding {
+    /// As the camera delivers them, in the `PixelFormat` it was initialized with
+    #[default]
+    Raw,
+    /// Converted to JPEG on the node, with `quality` from 1 to 100. A QVGA RGB565 frame shrinks
+    /// from 150 KB to around 10 KB. Frames the camera already delivers as JPEG are sent as is.
+    Jpeg { quality: u8 },
+}
+
+/// Region of interest, in pixels of the whole frame at the camera's frame size.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Roi {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Size to scale the region down to, the region's own size if missing
+    #[serde(default)]
+    pub output_width: Option<u32>,
+    #[serde(default)]
+    pub output_height: Option<u32>,
+}
+
+impl Roi {
+    pub fn output_size(&self) -> (u32, u32) {
+        (
+            self.output_width.unwrap_or(self.width),
+            self.output_height.unwrap_or(self.height),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RoiStatus {
+    /// `None` when whole frames are sent
+    pub roi: Option<Roi>,
+    /// Whether the sensor outputs just the region. Otherwise the node crops and scales whole
+    /// frames in software (see `frame::crop`), which needs the camera to deliver uncompressed
+    /// frames and doesn't save any capture time.
+    pub windowed: bool,
+    /// Size of the frames the node sends now. Sensors can round the output size.
+    pub width: u32,
+    pub height: u32,
+}
+
+/// On-node motion detection. Nodes compare a 32x24 grayscale thumbnail of each frame with a
+/// background that slowly follows the scene, see the flock-motion crate.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct MotionDetection {
+    /// Brightness difference (out of 255) for a thumbnail cell to count as changed
+    pub threshold: u8,
+    /// Changed cells, in thousandths of the thumbnail, that make up motion
+    pub min_area: u16,
+    /// How often always-on nodes check
+    pub interval_ms: u32,
+    /// Minimum time between two alerts
+    pub cooldown_ms: u32,
+    /// Whether alerts carry the frame that triggered them, encoded like readings
+    #[serde(default)]
+    pub include_frame: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct MotionAlert {
+    /// Changed cells, in thousandths of the thumbnail
+    pub area: u16,
+    #[serde(default)]
+    pub reading: Option<SensorData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum ActuatorState {
+    Off,
+    On,
+    /// PWM output, from 0 (off) to 255 (fully on)
+    Pwm { duty: u8 },
+    /// Off, except while a frame is captured. The output is switched on `lead_ms` before the
+    /// frame is grabbed so illuminators have time to reach full brightness.
+    CapturePulse { duty: u8, lead_ms: u32 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Actuator {
+    pub name: String,
+    pub pin: i32,
+    pub state: ActuatorState,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct Message {
     // pub message_id: String,
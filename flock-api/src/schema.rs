@@ -0,0 +1,131 @@
+//! Machine-readable description of the protocol, for clients written in other languages.
+//!
+//! Both documents are generated from the types in `message`, so they can't drift from what
+//! nodes actually send. `flock schema` prints them.
+
+use crate::Message;
+use schemars::gen::SchemaSettings;
+use serde_json::{json, Value};
+
+/// JSON Schema (draft 7) of `Message`, with every payload type in its definitions.
+pub fn message_schema() -> Value {
+    serde_json::to_value(schemars::schema_for!(Message)).unwrap()
+}
+
+/// AsyncAPI 2.6 document describing a node: the topics it listens and publishes on, and the
+/// messages on each. `controller_topic` is the firmware's `FLOCK_CONTROLLER_TOPIC`.
+pub fn asyncapi(controller_topic: &str) -> Value {
+    let mut gen = SchemaSettings::draft07()
+        .with(|s| {
+            s.definitions_path = "#/components/schemas/".into();
+            s.meta_schema = None;
+        })
+        .into_generator();
+    let root = gen.root_schema_for::<Message>();
+    let mut schemas = serde_json::Map::new();
+    for (name, schema) in root.definitions {
+        schemas.insert(name, serde_json::to_value(schema).unwrap());
+    }
+    schemas.insert("Message".into(), serde_json::to_value(root.schema).unwrap());
+
+    json!({
+        "asyncapi": "2.6.0",
+        "info": {
+            "title": "flock camera node",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Camera nodes take instructions on their own topic and publish \
+                everything they have to say on the controller topic. Messages carry no request \
+                ID: a reply is the next message from the node with the payload asked for, or \
+                an `error` payload.",
+        },
+        "defaultContentType": "application/json",
+        "channels": {
+            "{clientId}": {
+                "description": "Instructions for one node. Instructions for nodes in low-power \
+                    mode should be published retained, since the node only listens while awake; \
+                    the node clears the retained message once it has handled it.",
+                "parameters": {
+                    "clientId": {
+                        "description": "The node's client ID, `flock-client-<FLOCK_CLIENT_ID>`",
+                        "schema": { "type": "string" },
+                    },
+                },
+                "publish": {
+                    "operationId": "sendInstruction",
+                    "summary": "Controllers send instructions to the node",
+                    "message": { "$ref": "#/components/messages/Instruction" },
+                },
+            },
+            controller_topic: {
+                "description": "Everything nodes publish. Nodes also leave a `disconnected` \
+                    message as their MQTT last will here.",
+                "subscribe": {
+                    "operationId": "receiveNodeMessage",
+                    "summary": "Nodes announce themselves, reply to instructions and upload \
+                        readings",
+                    "message": { "$ref": "#/components/messages/NodeMessage" },
+                },
+            },
+        },
+        "components": {
+            "messages": {
+                "Instruction": {
+                    "name": "instruction",
+                    "summary": "A `Message` whose payload is an `instruction`",
+                    "payload": { "$ref": "#/components/schemas/Message" },
+                    "bindings": { "mqtt": { "qos": 0 } },
+                },
+                "NodeMessage": {
+                    "name": "nodeMessage",
+                    "summary": "A `Message` from a node, any payload but `instruction`",
+                    "payload": { "$ref": "#/components/schemas/Message" },
+                    "bindings": { "mqtt": { "qos": 0, "retain": false } },
+                },
+            },
+            "schemas": schemas,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_covers_every_payload() {
+        let schema = message_schema();
+        let definitions = schema["definitions"].as_object().unwrap();
+        for name in [
+            "Payload",
+            "Instruction",
+            "SensorConfig",
+            "CameraSensorConfig",
+            "PowerMode",
+        ] {
+            assert!(definitions.contains_key(name), "{} missing", name);
+        }
+        // Externally tagged, camelCase, like the wire format
+        let payload = serde_json::to_string(&definitions["Payload"]).unwrap();
+        for tag in [
+            "sensorReading",
+            "storedCaptures",
+            "httpServer",
+            "powerMode",
+            "error",
+        ] {
+            assert!(payload.contains(&format!("\"{}\"", tag)), "{} missing", tag);
+        }
+    }
+
+    #[test]
+    fn asyncapi_refs_resolve() {
+        let doc = asyncapi("flock-controller");
+        let text = serde_json::to_string(&doc).unwrap();
+        for reference in text.split("\"$ref\":\"").skip(1) {
+            let path = &reference[..reference.find('"').unwrap()];
+            let pointer = path.trim_start_matches('#');
+            assert!(doc.pointer(pointer).is_some(), "dangling {}", path);
+        }
+        assert!(doc["channels"]["flock-controller"]["subscribe"].is_object());
+    }
+}
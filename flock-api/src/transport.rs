@@ -0,0 +1,215 @@
+//! Transport-independent plumbing for `Message`s.
+//!
+//! A transport delivers a message to the endpoint(s) subscribed to its `recipient` address. With
+//! MQTT the address is the topic, but nothing else in the protocol depends on that, so the same
+//! flow can run over a serial link or the in-process `Hub` used in tests.
+
+use crate::Message;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{mpsc, Arc, Mutex};
+
+/// Address every node subscribes to besides its own, for instructions meant for all of them,
+/// e.g. to find out which nodes are up. Nodes still answer on the controller topic.
+pub const BROADCAST_ADDRESS: &str = "flock-broadcast";
+
+#[derive(Debug)]
+pub enum Incoming {
+    /// The transport (re)connected, e.g. the MQTT session was established
+    Connected,
+    Message(Message),
+    /// Data arrived that couldn't be decoded into a `Message`
+    Malformed(String),
+}
+
+/// Sending half of a transport.
+pub trait TransportSender {
+    type Error: fmt::Debug;
+
+    /// Address this endpoint receives messages on (e.g. its MQTT topic)
+    fn address(&self) -> &str;
+
+    /// Starts delivering messages sent to `address` to the receiving half.
+    fn subscribe(&mut self, address: &str) -> Result<(), Self::Error>;
+
+    /// Delivers `msg` to `msg.recipient`.
+    fn send(&mut self, msg: &Message) -> Result<(), Self::Error>;
+
+    /// Like `send`, but only succeeds once the other end confirmed receipt, for messages the
+    /// sender forgets about afterwards. Transports without receipts just send.
+    fn send_confirmed(&mut self, msg: &Message) -> Result<(), Self::Error> {
+        self.send(msg)
+    }
+
+    /// Called once an instruction has been handled. Transports that hold on to undelivered
+    /// instructions for sleeping endpoints (e.g. retained MQTT messages) drop them here.
+    fn acknowledge(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Receiving half of a transport.
+pub trait TransportReceiver {
+    type Error: fmt::Debug;
+
+    /// Blocks until something arrives. Returns `None` once the transport is closed.
+    fn receive(&mut self) -> Option<Result<Incoming, Self::Error>>;
+}
+
+#[derive(Debug)]
+pub enum ChannelError {
+    /// No endpoint is subscribed to the recipient address
+    Unreachable(String),
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelError::Unreachable(address) => write!(f, "no endpoint at {}", address),
+        }
+    }
+}
+
+impl std::error::Error for ChannelError {}
+
+type Routes = Arc<Mutex<HashMap<String, Vec<mpsc::Sender<Incoming>>>>>;
+
+/// In-process message router, standing in for a broker.
+#[derive(Clone, Default)]
+pub struct Hub {
+    routes: Routes,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connects an endpoint that receives messages sent to `address`.
+    pub fn connect(&self, address: &str) -> (ChannelSender, ChannelReceiver) {
+        let (tx, rx) = mpsc::channel();
+        tx.send(Incoming::Connected).unwrap();
+        let mut sender = ChannelSender {
+            address: address.to_string(),
+            routes: self.routes.clone(),
+            inbox: tx,
+        };
+        sender.subscribe(address).unwrap();
+        (sender, ChannelReceiver { inbox: rx })
+    }
+}
+
+pub struct ChannelSender {
+    address: String,
+    routes: Routes,
+    inbox: mpsc::Sender<Incoming>,
+}
+
+impl TransportSender for ChannelSender {
+    type Error = ChannelError;
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn subscribe(&mut self, address: &str) -> Result<(), Self::Error> {
+        self.routes
+            .lock()
+            .unwrap()
+            .entry(address.to_string())
+            .or_default()
+            .push(self.inbox.clone());
+        Ok(())
+    }
+
+    fn send(&mut self, msg: &Message) -> Result<(), Self::Error> {
+        // Round-trip through the codec so tests see exactly what a real transport would deliver
+        let data = crate::codec::encode(msg).unwrap();
+        let mut routes = self.routes.lock().unwrap();
+        let subscribers = routes.entry(msg.recipient.clone()).or_default();
+        subscribers.retain(|s| {
+            let incoming = match crate::codec::decode(&data) {
+                Ok(msg) => Incoming::Message(msg),
+                Err(err) => Incoming::Malformed(format!("{:?}", err)),
+            };
+            s.send(incoming).is_ok()
+        });
+        if subscribers.is_empty() {
+            return Err(ChannelError::Unreachable(msg.recipient.clone()));
+        }
+        Ok(())
+    }
+}
+
+pub struct ChannelReceiver {
+    inbox: mpsc::Receiver<Incoming>,
+}
+
+impl ChannelReceiver {
+    /// Like `receive`, but gives up after `timeout`.
+    pub fn receive_timeout(&mut self, timeout: std::time::Duration) -> Option<Incoming> {
+        self.inbox.recv_timeout(timeout).ok()
+    }
+}
+
+impl TransportReceiver for ChannelReceiver {
+    type Error = ChannelError;
+
+    fn receive(&mut self) -> Option<Result<Incoming, Self::Error>> {
+        self.inbox.recv().ok().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Instruction, Payload};
+
+    #[test]
+    fn hub_routes_by_recipient() {
+        let hub = Hub::new();
+        let (mut controller_tx, mut controller_rx) = hub.connect("flock-controller");
+        let (mut node_tx, mut node_rx) = hub.connect("flock-client-1");
+        assert!(matches!(node_rx.receive(), Some(Ok(Incoming::Connected))));
+        assert!(matches!(controller_rx.receive(), Some(Ok(Incoming::Connected))));
+
+        controller_tx
+            .send(&Message::new(
+                controller_tx.address().into(),
+                "flock-client-1".into(),
+                Payload::Instruction(Instruction::ReadSensorConfig),
+            ))
+            .unwrap();
+        match node_rx.receive() {
+            Some(Ok(Incoming::Message(msg))) => {
+                assert_eq!(msg.client_id, "flock-controller");
+                assert!(matches!(
+                    *msg.payload,
+                    Payload::Instruction(Instruction::ReadSensorConfig)
+                ));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        node_tx
+            .send(&Message::new_connected(
+                node_tx.address().into(),
+                "flock-controller".into(),
+            ))
+            .unwrap();
+        assert!(matches!(
+            controller_rx.receive(),
+            Some(Ok(Incoming::Message(msg))) if matches!(*msg.payload, Payload::Connected)
+        ));
+    }
+
+    #[test]
+    fn hub_rejects_unknown_recipient() {
+        let hub = Hub::new();
+        let (mut tx, _rx) = hub.connect("flock-controller");
+        let err = tx
+            .send(&Message::new_connected("flock-controller".into(), "nobody".into()))
+            .unwrap_err();
+        assert!(matches!(err, ChannelError::Unreachable(address) if address == "nobody"));
+    }
+}
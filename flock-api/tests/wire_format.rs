@@ -0,0 +1,398 @@
+//! Golden fixtures for the wire format.
+//!
+//! `tests/fixtures/current/` holds one message per payload and instruction variant, as the code
+//! encodes them today, so renaming a field or variant fails here instead of silently breaking
+//! deployed nodes. `tests/fixtures/<version>/` are frozen copies of `current/` as each release
+//! shipped it, and have to keep decoding.
+//!
+//! After an intentional change, regenerate `current/` with
+//! `FLOCK_BLESS=1 cargo test -p flock-api --test wire_format` and review the diff. Blessing never
+//! touches the release directories. When releasing, copy `current/` to a directory named after
+//! the version.
+
+use flock_api::framing;
+use flock_api::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+fn current_dir() -> PathBuf {
+    fixtures_dir().join("current")
+}
+
+fn blessing() -> bool {
+    std::env::var_os("FLOCK_BLESS").is_some()
+}
+
+fn payload_name(payload: &Payload) -> String {
+    // Exhaustive on purpose: a new variant needs a name here, and a sample below
+    let name = match payload {
+        Payload::Connected => "connected",
+        Payload::Disconnected => "disconnected",
+        Payload::Instruction(instruction) => {
+            return format!("instruction_{}", instruction_name(instruction))
+        }
+        Payload::SensorReading(_) => "sensor_reading",
+        Payload::StoredReading(_) => "stored_reading",
+        Payload::BurstReading(_) => "burst_reading",
+        Payload::StoredCaptures(_) => "stored_captures",
+        Payload::HttpServer(_) => "http_server",
+        Payload::SensorConfig(_) => "sensor_config",
+        Payload::SensorInfo(_) => "sensor_info",
+        Payload::PowerMode(_) => "power_mode",
+        Payload::FrameEncoding(_) => "frame_encoding",
+        Payload::Roi(_) => "roi",
+        Payload::MotionDetection(_) => "motion_detection",
+        Payload::Motion(_) => "motion",
+        Payload::Actuators(_) => "actuators",
+        Payload::Error(_) => "error",
+    };
+    format!("payload_{}", name)
+}
+
+fn instruction_name(instruction: &Instruction) -> &'static str {
+    match instruction {
+        Instruction::ReadSensor => "read_sensor",
+        Instruction::ReadSensorEncoded(_) => "read_sensor_encoded",
+        Instruction::Burst(_) => "burst",
+        Instruction::ReadSensorConfig => "read_sensor_config",
+        Instruction::WriteSensorConfig(_) => "write_sensor_config",
+        Instruction::ReadSensorInfo => "read_sensor_info",
+        Instruction::ReadPowerMode => "read_power_mode",
+        Instruction::WritePowerMode(_) => "write_power_mode",
+        Instruction::ReadFrameEncoding => "read_frame_encoding",
+        Instruction::WriteFrameEncoding(_) => "write_frame_encoding",
+        Instruction::ReadRoi => "read_roi",
+        Instruction::WriteRoi(_) => "write_roi",
+        Instruction::ReadMotionDetection => "read_motion_detection",
+        Instruction::WriteMotionDetection(_) => "write_motion_detection",
+        Instruction::ReadActuators => "read_actuators",
+        Instruction::WriteActuator(_) => "write_actuator",
+        Instruction::ListCaptures => "list_captures",
+        Instruction::FetchCapture(_) => "fetch_capture",
+        Instruction::DeleteCapture(_) => "delete_capture",
+        Instruction::ReadHttpServer => "read_http_server",
+        Instruction::EnableHttpServer(_) => "enable_http_server",
+        Instruction::DisableHttpServer => "disable_http_server",
+    }
+}
+
+fn camera_config() -> SensorConfig {
+    SensorConfig::Camera(CameraSensorConfig {
+        brightness: 2,
+        contrast: -1,
+        saturation: 0,
+        sharpness: 1,
+        de_noise: 0,
+        special_effect: 2,
+        wb_mode: 1,
+        awb: true,
+        awb_gain: true,
+        gain_ceiling: 3,
+        lens_correction: true,
+        horizontal_mirror: false,
+        vertical_flip: true,
+    })
+}
+
+fn capture(id: u32, uploaded: bool) -> StoredCapture {
+    StoredCapture {
+        id,
+        captured_at: 1_660_000_000 + u64::from(id),
+        size: 4,
+        uploaded,
+        format: format(),
+    }
+}
+
+fn format() -> FrameFormat {
+    FrameFormat {
+        pixel_format: PixelFormat::Rgb565,
+        width: 2,
+        height: 1,
+    }
+}
+
+fn frame() -> SensorData {
+    SensorData::Camera {
+        frame_buffer: vec![0xF8, 0x00, 0x07, 0xE0],
+        format: format(),
+    }
+}
+
+fn bracket() -> Vec<ShotSettings> {
+    vec![
+        ShotSettings {
+            ae_level: Some(-2),
+            ..Default::default()
+        },
+        ShotSettings {
+            aec_value: Some(300),
+            agc_gain: Some(0),
+            ..Default::default()
+        },
+        ShotSettings {
+            ae_level: Some(2),
+            ..Default::default()
+        },
+    ]
+}
+
+fn motion_detection() -> MotionDetection {
+    MotionDetection {
+        threshold: 12,
+        min_area: 5,
+        interval_ms: 500,
+        cooldown_ms: 10_000,
+        include_frame: true,
+    }
+}
+
+fn gauge() -> Roi {
+    Roi {
+        x: 100,
+        y: 60,
+        width: 100,
+        height: 100,
+        output_width: Some(50),
+        output_height: Some(50),
+    }
+}
+
+fn flash(state: ActuatorState) -> Actuator {
+    Actuator {
+        name: "flash".into(),
+        pin: 4,
+        state,
+    }
+}
+
+/// One sample of every variant, with every field set
+fn samples() -> Vec<Message> {
+    let instructions = vec![
+        Instruction::ReadSensor,
+        Instruction::ReadSensorEncoded(FrameEncoding::Jpeg { quality: 80 }),
+        Instruction::Burst(Burst {
+            count: 3,
+            interval_ms: 100,
+            shots: bracket(),
+            encoding: Some(FrameEncoding::Raw),
+        }),
+        Instruction::ReadSensorConfig,
+        Instruction::WriteSensorConfig(camera_config()),
+        Instruction::ReadSensorInfo,
+        Instruction::ReadPowerMode,
+        Instruction::WritePowerMode(PowerMode::LowPower {
+            sleep_interval_secs: 300,
+        }),
+        Instruction::ReadFrameEncoding,
+        Instruction::WriteFrameEncoding(FrameEncoding::Raw),
+        Instruction::ReadRoi,
+        Instruction::WriteRoi(Some(gauge())),
+        Instruction::ReadMotionDetection,
+        Instruction::WriteMotionDetection(Some(motion_detection())),
+        Instruction::ReadActuators,
+        Instruction::WriteActuator(flash(ActuatorState::CapturePulse {
+            duty: 128,
+            lead_ms: 50,
+        })),
+        Instruction::ListCaptures,
+        Instruction::FetchCapture(7),
+        Instruction::DeleteCapture(7),
+        Instruction::ReadHttpServer,
+        Instruction::EnableHttpServer(HttpServerConfig {
+            port: 8080,
+            token: "s3cret".into(),
+        }),
+        Instruction::DisableHttpServer,
+    ];
+    let from_controller = instructions.into_iter().map(|instruction| {
+        Message::new(
+            "flock-controller".into(),
+            "flock-client-1".into(),
+            Payload::Instruction(instruction),
+        )
+    });
+
+    let payloads = vec![
+        Payload::Connected,
+        Payload::Disconnected,
+        Payload::SensorReading(frame()),
+        Payload::StoredReading(StoredReading {
+            capture: capture(7, false),
+            reading: frame(),
+        }),
+        Payload::BurstReading(BurstShot {
+            index: 2,
+            count: 3,
+            offset_ms: 210,
+            settings: bracket().pop().unwrap(),
+            reading: frame(),
+        }),
+        Payload::StoredCaptures(vec![capture(6, true), capture(7, false)]),
+        Payload::HttpServer(HttpServerStatus {
+            enabled: true,
+            url: Some("http://192.168.1.20:8080".into()),
+        }),
+        Payload::SensorConfig(camera_config()),
+        Payload::SensorInfo(SensorInfo {
+            manufacturer_id: 0x7FA2,
+            product_id: 0x2642,
+            version: 0x42,
+            model: Some("OV2640".into()),
+        }),
+        Payload::PowerMode(PowerMode::AlwaysOn),
+        Payload::FrameEncoding(FrameEncoding::Jpeg { quality: 80 }),
+        Payload::Roi(RoiStatus {
+            roi: Some(gauge()),
+            windowed: true,
+            width: 48,
+            height: 48,
+        }),
+        Payload::MotionDetection(Some(motion_detection())),
+        Payload::Motion(MotionAlert {
+            area: 42,
+            reading: Some(frame()),
+        }),
+        Payload::Actuators(vec![
+            flash(ActuatorState::Off),
+            flash(ActuatorState::On),
+            flash(ActuatorState::Pwm { duty: 64 }),
+        ]),
+        Payload::Error("No SD card mounted".into()),
+    ];
+    let from_node = payloads
+        .into_iter()
+        .map(|payload| Message::new("flock-client-1".into(), "flock-controller".into(), payload));
+
+    from_controller.chain(from_node).collect()
+}
+
+fn check(path: &Path, actual: &[u8], same: impl Fn(&[u8], &[u8]) -> bool) -> Option<String> {
+    if blessing() {
+        assert!(
+            path.starts_with(current_dir()),
+            "release fixtures are frozen"
+        );
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, actual).unwrap();
+        return None;
+    }
+    match fs::read(path) {
+        Ok(expected) if same(&expected, actual) => None,
+        Ok(_) => Some(format!("{} changed", path.display())),
+        Err(_) => Some(format!("{} is missing", path.display())),
+    }
+}
+
+fn same_json(a: &[u8], b: &[u8]) -> bool {
+    serde_json::from_slice::<serde_json::Value>(a).unwrap()
+        == serde_json::from_slice::<serde_json::Value>(b).unwrap()
+}
+
+#[test]
+fn encoding_matches_fixtures() {
+    let mut failures = vec![];
+    for msg in samples() {
+        let path = current_dir().join(format!("{}.json", payload_name(&msg.payload)));
+        let mut actual = serde_json::to_vec_pretty(&msg).unwrap();
+        actual.push(b'\n');
+        failures.extend(check(&path, &actual, same_json));
+    }
+
+    // Serial links carry the same JSON inside COBS frames, so pin one frame byte for byte
+    let msg = Message::new(
+        "flock-controller".into(),
+        "flock-client-1".into(),
+        Payload::Instruction(Instruction::ReadSensorConfig),
+    );
+    let frame = framing::encode_frame(&codec::encode(&msg).unwrap());
+    let path = current_dir().join("frame_instruction_read_sensor_config.bin");
+    failures.extend(check(&path, &frame, |a, b| a == b));
+
+    assert!(
+        failures.is_empty(),
+        "wire format changed, see the top of this file:\n{}",
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn fixtures_of_every_release_decode() {
+    let mut checked = 0;
+    for release in fs::read_dir(fixtures_dir()).unwrap() {
+        for fixture in fs::read_dir(release.unwrap().path()).unwrap() {
+            let path = fixture.unwrap().path();
+            let data = fs::read(&path).unwrap();
+            let decoded = match path.extension().and_then(|e| e.to_str()) {
+                Some("json") => codec::decode(&data),
+                Some("bin") => {
+                    let payload = framing::decode_frame(&data[..data.len() - 1])
+                        .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+                    codec::decode(&payload)
+                }
+                _ => continue,
+            };
+            let msg = decoded.unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+            // Tell apart fixtures that decode, but as something else
+            let stem = path.file_stem().unwrap().to_str().unwrap();
+            assert!(
+                stem.ends_with(&payload_name(&msg.payload)),
+                "{} decodes as {}",
+                path.display(),
+                payload_name(&msg.payload)
+            );
+            checked += 1;
+        }
+    }
+    assert!(checked >= samples().len());
+}
+
+#[test]
+fn frames_without_a_format_default_to_qvga_rgb565() {
+    // 0.1.0 predates frame formats
+    let release = fixtures_dir().join("0.1.0");
+    let decode = |name: &str| {
+        let data = fs::read(release.join(format!("{}.json", name))).unwrap();
+        *codec::decode(&data).unwrap().payload
+    };
+
+    let Payload::SensorReading(SensorData::Camera { format, .. }) =
+        decode("payload_sensor_reading")
+    else {
+        panic!("not a reading");
+    };
+    assert_eq!(format, FrameFormat::default());
+    let Payload::StoredReading(stored) = decode("payload_stored_reading") else {
+        panic!("not a stored reading");
+    };
+    let SensorData::Camera { format, .. } = stored.reading;
+    assert_eq!(format, FrameFormat::default());
+    assert_eq!(stored.capture.format, FrameFormat::default());
+    let Payload::StoredCaptures(captures) = decode("payload_stored_captures") else {
+        panic!("not a capture list");
+    };
+    assert!(captures
+        .iter()
+        .all(|capture| capture.format == FrameFormat::default()));
+}
+
+#[test]
+fn fixtures_match_schema() {
+    let schema = jsonschema::JSONSchema::compile(&schema::message_schema()).unwrap();
+    for fixture in fs::read_dir(current_dir()).unwrap() {
+        let path = fixture.unwrap().path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let value: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let errors: Vec<String> = match schema.validate(&value) {
+            Ok(()) => continue,
+            Err(errors) => errors.map(|e| e.to_string()).collect(),
+        };
+        panic!("{}: {}", path.display(), errors.join(", "));
+    }
+}
@@ -0,0 +1,288 @@
+//! Embedded MQTT broker, so a lab setup doesn't need a separately administered one.
+//!
+//! Access control is per connection: either anyone can connect (the default on loopback), or
+//! clients log in with one of the configured users, never both on the same broker.
+//!
+//! Deliberately out of scope, use a full broker such as Mosquitto if you need them:
+//! - Per-topic ACLs. Any client that can connect can publish and subscribe to any topic,
+//!   including other nodes' instruction topics.
+//! - Persistence. Retained messages (instructions waiting for sleeping nodes) are kept in memory
+//!   only, so they are lost when the broker restarts and have to be sent again.
+
+use anyhow::{bail, Context};
+use log::*;
+use rumqttd::{Broker, Config, ConnectionSettings, RouterConfig, ServerSettings, TlsConfig};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerConfig {
+    /// Plain TCP listener
+    pub listen: SocketAddr,
+    /// Optional TLS listener, alongside the plain one
+    pub tls: Option<TlsSettings>,
+    /// User name to password. Clients have to log in as one of these unless anonymous access is
+    /// allowed.
+    pub users: HashMap<String, String>,
+    /// Let clients connect without logging in. Defaults to allowed when there are no users and
+    /// the broker only listens on loopback, and refused otherwise. Can't be turned on together
+    /// with users.
+    pub allow_anonymous: Option<bool>,
+    /// Largest message accepted, in bytes. Uncompressed frames are sent as JSON, so this is big.
+    pub max_payload_size: usize,
+    pub max_connections: usize,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    pub listen: SocketAddr,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA to verify client certificates against. Without it clients aren't asked for one.
+    pub ca: Option<PathBuf>,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        Self {
+            listen: ([127, 0, 0, 1], 1883).into(),
+            tls: None,
+            users: HashMap::new(),
+            allow_anonymous: None,
+            max_payload_size: 16 * 1024 * 1024,
+            max_connections: 1024,
+        }
+    }
+}
+
+impl BrokerConfig {
+    /// Reads a TOML config file. Missing settings keep their defaults.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Error reading {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Error parsing {}", path.display()))
+    }
+
+    /// Default config on a free loopback port, for tests.
+    pub fn local() -> anyhow::Result<Self> {
+        let listen = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        Ok(Self {
+            listen,
+            ..Default::default()
+        })
+    }
+
+    pub fn anonymous_allowed(&self) -> bool {
+        self.allow_anonymous.unwrap_or_else(|| {
+            self.users.is_empty() && self.tls.is_none() && self.listen.ip().is_loopback()
+        })
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.users.is_empty() && !self.anonymous_allowed() {
+            bail!("No users configured and anonymous access is off, nobody could connect");
+        }
+        // rumqttd refuses connections without a login as soon as any authentication is set up
+        if !self.users.is_empty() && self.allow_anonymous == Some(true) {
+            bail!("Anonymous access can't be allowed when users are configured");
+        }
+        if let Some(tls) = &self.tls {
+            for path in [Some(&tls.cert), Some(&tls.key), tls.ca.as_ref()]
+                .into_iter()
+                .flatten()
+            {
+                if !path.exists() {
+                    bail!("TLS file {} doesn't exist", path.display());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn connection_settings(&self) -> ConnectionSettings {
+        let external_auth: Option<rumqttd::AuthHandler> = if self.users.is_empty() {
+            None
+        } else {
+            let users = self.users.clone();
+            Some(Arc::new(move |_client_id, user: String, pass: String| {
+                users.get(&user).is_some_and(|expected| {
+                    flock_api::auth::constant_time_eq(expected.as_bytes(), pass.as_bytes())
+                })
+            }))
+        };
+        ConnectionSettings {
+            connection_timeout_ms: 60_000,
+            max_payload_size: self.max_payload_size,
+            max_inflight_count: 100,
+            auth: None,
+            external_auth,
+            dynamic_filters: true,
+        }
+    }
+
+    fn to_rumqttd(&self) -> Config {
+        let server = |name: &str, listen, tls| ServerSettings {
+            name: name.into(),
+            listen,
+            tls,
+            next_connection_delay_ms: 1,
+            connections: self.connection_settings(),
+        };
+        let mut servers = HashMap::from([("tcp".to_string(), server("tcp", self.listen, None))]);
+        if let Some(tls) = &self.tls {
+            let path = |p: &PathBuf| p.to_string_lossy().into_owned();
+            let tls_config = TlsConfig::Rustls {
+                capath: tls.ca.as_ref().map(path),
+                certpath: path(&tls.cert),
+                keypath: path(&tls.key),
+            };
+            servers.insert("tls".into(), server("tls", tls.listen, Some(tls_config)));
+        }
+        Config {
+            router: RouterConfig {
+                max_connections: self.max_connections,
+                max_outgoing_packet_count: 200,
+                max_segment_size: 100 * 1024 * 1024,
+                max_segment_count: 10,
+                ..Default::default()
+            },
+            v4: Some(servers),
+            ..Default::default()
+        }
+    }
+}
+
+/// Starts the broker in the background and returns once it accepts connections. It runs until
+/// the process exits.
+pub fn start(config: &BrokerConfig) -> anyhow::Result<()> {
+    config.validate()?;
+    let mut broker = Broker::new(config.to_rumqttd());
+    thread::Builder::new()
+        .name("flock-broker".into())
+        .spawn(move || {
+            if let Err(err) = broker.start() {
+                error!("Broker stopped: {}", err);
+            }
+        })?;
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while TcpStream::connect(config.listen).is_err() {
+        if Instant::now() > deadline {
+            bail!("Broker isn't listening on {}", config.listen);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::{Client, ConnectReturnCode, Event, MqttOptions, Packet, QoS};
+
+    fn client(config: &BrokerConfig, login: Option<(&str, &str)>) -> (Client, rumqttc::Connection) {
+        let mut options = MqttOptions::new(
+            "test-client",
+            config.listen.ip().to_string(),
+            config.listen.port(),
+        );
+        if let Some((user, pass)) = login {
+            options.set_credentials(user, pass);
+        }
+        Client::new(options, 10)
+    }
+
+    #[test]
+    fn routes_messages() {
+        let config = BrokerConfig::local().unwrap();
+        start(&config).unwrap();
+
+        let (client, mut connection) = client(&config, None);
+        client
+            .subscribe("flock-controller", QoS::AtMostOnce)
+            .unwrap();
+        for event in connection.iter() {
+            match event.unwrap() {
+                Event::Incoming(Packet::SubAck(_)) => {
+                    client
+                        .publish("flock-controller", QoS::AtMostOnce, false, "hello")
+                        .unwrap();
+                }
+                Event::Incoming(Packet::Publish(p)) => {
+                    assert_eq!(&p.payload[..], b"hello");
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn requires_login_when_users_are_configured() {
+        let mut config = BrokerConfig::local().unwrap();
+        config.users.insert("camera".into(), "secret".into());
+        assert!(!config.anonymous_allowed());
+        start(&config).unwrap();
+
+        let connack = |login| {
+            let (_client, mut connection) = client(&config, login);
+            for event in connection.iter() {
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(ack))) => return Some(ack.code),
+                    Ok(_) => {}
+                    // Refused connections surface as errors
+                    Err(_) => return None,
+                }
+            }
+            unreachable!()
+        };
+        assert!(matches!(
+            connack(Some(("camera", "secret"))),
+            Some(ConnectReturnCode::Success)
+        ));
+        assert!(connack(Some(("camera", "wrong"))).is_none());
+        assert!(connack(None).is_none());
+    }
+
+    #[test]
+    fn refuses_open_access_off_loopback() {
+        let config = BrokerConfig {
+            listen: ([0, 0, 0, 0], 1883).into(),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config: BrokerConfig = toml::from_str(
+            r#"
+            listen = "0.0.0.0:1883"
+            [users]
+            camera = "secret"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn refuses_anonymous_access_alongside_users() {
+        let config: BrokerConfig = toml::from_str(
+            r#"
+            allow_anonymous = true
+            [users]
+            camera = "secret"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+        assert!(start(&config).is_err());
+    }
+}
@@ -0,0 +1,41 @@
+use clap::Parser;
+use flock_broker::BrokerConfig;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Runs an MQTT broker for flock nodes and controllers.
+#[derive(Parser, Debug)]
+#[command(name = "flock-broker", version)]
+struct Args {
+    /// TOML config file, see `BrokerConfig` for the settings
+    #[arg(long, short)]
+    config: Option<PathBuf>,
+    /// Overrides the listen address from the config
+    #[arg(long)]
+    listen: Option<SocketAddr>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    // RUST_LOG overrides the level
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let mut config = match &args.config {
+        Some(path) => BrokerConfig::load(path)?,
+        None => BrokerConfig::default(),
+    };
+    if let Some(listen) = args.listen {
+        config.listen = listen;
+    }
+
+    flock_broker::start(&config)?;
+    println!("Listening on {}", config.listen);
+    if let Some(tls) = &config.tls {
+        println!("Listening for TLS on {}", tls.listen);
+    }
+    if config.anonymous_allowed() {
+        println!("Anonymous access is allowed");
+    }
+    loop {
+        std::thread::park();
+    }
+}
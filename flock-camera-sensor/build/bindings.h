@@ -1 +1,2 @@
-#include<esp_camera.h>
\ No newline at end of file
+#include<esp_camera.h>
+#include<img_converters.h>
@@ -0,0 +1,173 @@
+use crate::camera::CameraConfig;
+use anyhow::bail;
+use esp_idf_sys::{esp, EspError};
+use flock_api::{Actuator, ActuatorState};
+use log::*;
+use std::thread;
+use std::time::Duration;
+
+const LEDC_MODE: esp_idf_sys::ledc_mode_t = esp_idf_sys::ledc_mode_t_LEDC_LOW_SPEED_MODE;
+const LEDC_FREQ_HZ: u32 = 5000;
+const LEDC_CHANNELS: u32 = 8;
+const LEDC_TIMERS: u32 = 4;
+
+// GPIO6-11 are wired to the SPI flash and GPIO34-39 are input only
+fn is_output_pin(pin: i32) -> bool {
+    (0..34).contains(&pin) && !(6..=11).contains(&pin)
+}
+
+struct Channel {
+    actuator: Actuator,
+    ledc_channel: u32,
+}
+
+/// Drives GPIO outputs such as the AI-Thinker flash LED (GPIO4) or external IR illuminators.
+///
+/// Every actuator gets its own LEDC channel so on/off and PWM go through the same path. The
+/// camera's XCLK is also generated by LEDC, so its timer and channel are never handed out.
+pub struct Actuators {
+    reserved_pins: Vec<i32>,
+    ledc_timer: u32,
+    free_channels: Vec<u32>,
+    channels: Vec<Channel>,
+}
+
+impl Actuators {
+    pub fn new(camera_config: &CameraConfig) -> Result<Self, EspError> {
+        let ledc_timer = (0..LEDC_TIMERS)
+            .find(|t| *t != camera_config.ledc_timer)
+            .unwrap();
+        let timer_config = esp_idf_sys::ledc_timer_config_t {
+            speed_mode: LEDC_MODE,
+            __bindgen_anon_1: esp_idf_sys::ledc_timer_config_t__bindgen_ty_1 {
+                duty_resolution: esp_idf_sys::ledc_timer_bit_t_LEDC_TIMER_8_BIT,
+            },
+            timer_num: ledc_timer,
+            freq_hz: LEDC_FREQ_HZ,
+            clk_cfg: esp_idf_sys::ledc_clk_cfg_t_LEDC_AUTO_CLK,
+        };
+        esp!(unsafe { esp_idf_sys::ledc_timer_config(&timer_config) })?;
+
+        Ok(Self {
+            reserved_pins: camera_config.pins(),
+            ledc_timer,
+            free_channels: (0..LEDC_CHANNELS)
+                .rev()
+                .filter(|c| *c != camera_config.ledc_channel)
+                .collect(),
+            channels: vec![],
+        })
+    }
+
+    /// Keeps other peripherals (e.g. the SD card) from handing their pins out to actuators.
+    pub fn reserve_pins(&mut self, pins: &[i32]) {
+        self.reserved_pins.extend_from_slice(pins);
+    }
+
+    pub fn list(&self) -> Vec<Actuator> {
+        self.channels.iter().map(|c| c.actuator.clone()).collect()
+    }
+
+    pub fn write(&mut self, actuator: Actuator) -> anyhow::Result<()> {
+        if let Some(idx) = self
+            .channels
+            .iter()
+            .position(|c| c.actuator.name == actuator.name)
+        {
+            if self.channels[idx].actuator.pin != actuator.pin {
+                bail!(
+                    "Actuator {} is already assigned to GPIO{}",
+                    actuator.name,
+                    self.channels[idx].actuator.pin
+                );
+            }
+            set_duty(self.channels[idx].ledc_channel, idle_duty(&actuator.state))?;
+            self.channels[idx].actuator = actuator;
+            return Ok(());
+        }
+
+        if !is_output_pin(actuator.pin) {
+            bail!("GPIO{} can't be used as an output", actuator.pin);
+        }
+        if self.reserved_pins.contains(&actuator.pin) {
+            bail!("GPIO{} is reserved by the camera or SD card", actuator.pin);
+        }
+        if let Some(other) = self.channels.iter().find(|c| c.actuator.pin == actuator.pin) {
+            bail!(
+                "GPIO{} is already used by actuator {}",
+                actuator.pin,
+                other.actuator.name
+            );
+        }
+        let ledc_channel = match self.free_channels.pop() {
+            Some(c) => c,
+            None => bail!("No free LEDC channel for actuator {}", actuator.name),
+        };
+
+        info!("Adding actuator {} on GPIO{}", actuator.name, actuator.pin);
+        let channel_config = esp_idf_sys::ledc_channel_config_t {
+            gpio_num: actuator.pin,
+            speed_mode: LEDC_MODE,
+            channel: ledc_channel,
+            intr_type: esp_idf_sys::ledc_intr_type_t_LEDC_INTR_DISABLE,
+            timer_sel: self.ledc_timer,
+            duty: idle_duty(&actuator.state),
+            hpoint: 0,
+            ..Default::default()
+        };
+        if let Err(err) = esp!(unsafe { esp_idf_sys::ledc_channel_config(&channel_config) }) {
+            self.free_channels.push(ledc_channel);
+            return Err(err.into());
+        }
+        self.channels.push(Channel {
+            actuator,
+            ledc_channel,
+        });
+        Ok(())
+    }
+
+    /// Runs `capture` with every `CapturePulse` actuator switched on.
+    pub fn pulse<T>(&self, capture: impl FnOnce() -> T) -> T {
+        let pulsed: Vec<(&Channel, u8, u32)> = self
+            .channels
+            .iter()
+            .filter_map(|c| match c.actuator.state {
+                ActuatorState::CapturePulse { duty, lead_ms } => Some((c, duty, lead_ms)),
+                _ => None,
+            })
+            .collect();
+        if pulsed.is_empty() {
+            return capture();
+        }
+
+        for (c, duty, _) in pulsed.iter() {
+            if let Err(err) = set_duty(c.ledc_channel, (*duty).into()) {
+                error!("Error pulsing actuator {}: {:?}", c.actuator.name, err);
+            }
+        }
+        let lead_ms = pulsed.iter().map(|(_, _, lead_ms)| *lead_ms).max().unwrap();
+        thread::sleep(Duration::from_millis(lead_ms.into()));
+
+        let result = capture();
+
+        for (c, _, _) in pulsed.iter() {
+            if let Err(err) = set_duty(c.ledc_channel, 0) {
+                error!("Error pulsing actuator {}: {:?}", c.actuator.name, err);
+            }
+        }
+        result
+    }
+}
+
+fn idle_duty(state: &ActuatorState) -> u32 {
+    match state {
+        ActuatorState::Off | ActuatorState::CapturePulse { .. } => 0,
+        ActuatorState::On => 255,
+        ActuatorState::Pwm { duty } => (*duty).into(),
+    }
+}
+
+fn set_duty(channel: u32, duty: u32) -> Result<(), EspError> {
+    esp!(unsafe { esp_idf_sys::ledc_set_duty(LEDC_MODE, channel, duty) })?;
+    esp!(unsafe { esp_idf_sys::ledc_update_duty(LEDC_MODE, channel) })
+}
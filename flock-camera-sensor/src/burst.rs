@@ -0,0 +1,127 @@
+use crate::camera::{Camera, SensorStatus};
+use anyhow::bail;
+use esp_idf_sys::EspError;
+use flock_api::{Burst, BurstShot, FrameEncoding, FrameFormat, Payload, SensorData, ShotSettings};
+use log::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_SHOTS: u8 = 16;
+/// PSRAM the frames of one burst may take up. Shots are only published once the burst is over,
+/// so publishing doesn't stretch the intervals, which means they're all held at once.
+const MAX_BURST_BYTES: usize = 2 * 1024 * 1024;
+/// The driver hands over the frame captured before a settings change, drop this many so each
+/// shot is exposed with its own settings.
+const SETTLE_FRAMES: usize = 1;
+
+/// Takes the shots of a burst with `capture`, and returns one `BurstReading` per shot, or a
+/// single `Error` if any shot fails.
+pub fn take(
+    cam: &Camera,
+    burst: &Burst,
+    mut capture: impl FnMut(FrameEncoding) -> anyhow::Result<(Vec<u8>, FrameFormat)>,
+) -> Vec<Payload> {
+    info!("taking burst: {:?}", burst);
+    let saved = cam.sensor().status();
+    let shots = take_shots(cam, burst, &saved, &mut capture);
+    // Whatever happened, the node goes back to its own settings
+    if let Err(err) = restore(cam, &saved) {
+        error!("Error restoring sensor settings after burst: {:?}", err);
+    }
+    match shots {
+        Ok(shots) => shots.into_iter().map(Payload::BurstReading).collect(),
+        Err(err) => vec![Payload::Error(format!("{:?}", err))],
+    }
+}
+
+fn take_shots(
+    cam: &Camera,
+    burst: &Burst,
+    saved: &SensorStatus,
+    capture: &mut impl FnMut(FrameEncoding) -> anyhow::Result<(Vec<u8>, FrameFormat)>,
+) -> anyhow::Result<Vec<BurstShot>> {
+    if burst.count == 0 || burst.count > MAX_SHOTS {
+        bail!("Bursts take 1 to {} shots", MAX_SHOTS);
+    }
+    let encoding = burst.encoding.unwrap_or_else(crate::encoding::frame_encoding);
+    let interval = Duration::from_millis(burst.interval_ms.into());
+    let mut shots = Vec::with_capacity(burst.count.into());
+    let mut used = 0;
+    // The sensor starts out with the node's settings, which is what no overrides mean
+    let mut current = ShotSettings::default();
+    let start = Instant::now();
+
+    for index in 0..burst.count {
+        let settings = burst.shots.get(usize::from(index)).cloned().unwrap_or_default();
+        if index > 0 {
+            let due = interval * u32::from(index);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        let offset_ms = start.elapsed().as_millis() as u32;
+        if settings != current {
+            apply(cam, &settings, saved)?;
+            for _ in 0..SETTLE_FRAMES {
+                drop(cam.fb_get());
+            }
+            current = settings.clone();
+        }
+
+        let (frame_buffer, format) = capture(encoding)?;
+        used += frame_buffer.len();
+        if used > MAX_BURST_BYTES {
+            bail!(
+                "Burst doesn't fit in {} bytes after {} shots, take fewer or encode as JPEG",
+                MAX_BURST_BYTES,
+                index
+            );
+        }
+        shots.push(BurstShot {
+            index,
+            count: burst.count,
+            offset_ms,
+            settings,
+            reading: SensorData::Camera {
+                frame_buffer,
+                format,
+            },
+        });
+    }
+    Ok(shots)
+}
+
+/// Applies a shot's overrides on top of the node's settings in `saved`.
+fn apply(cam: &Camera, settings: &ShotSettings, saved: &SensorStatus) -> Result<(), EspError> {
+    let sensor = cam.sensor();
+    match (settings.aec_value, settings.ae_level) {
+        (Some(value), _) => {
+            sensor.set_exposure_ctrl(false)?;
+            sensor.set_aec_value(value.into())?;
+        }
+        (None, Some(level)) => {
+            sensor.set_exposure_ctrl(true)?;
+            sensor.set_ae_level(level.into())?;
+        }
+        (None, None) => {
+            sensor.set_exposure_ctrl(saved.aec)?;
+            sensor.set_ae_level(saved.ae_level.into())?;
+            sensor.set_aec_value(saved.aec_value.into())?;
+        }
+    }
+    match settings.agc_gain {
+        Some(gain) => {
+            sensor.set_gain_ctrl(false)?;
+            sensor.set_agc_gain(gain.into())?;
+        }
+        None => {
+            sensor.set_gain_ctrl(saved.agc)?;
+            sensor.set_agc_gain(saved.agc_gain.into())?;
+        }
+    }
+    Ok(())
+}
+
+fn restore(cam: &Camera, saved: &SensorStatus) -> Result<(), EspError> {
+    apply(cam, &ShotSettings::default(), saved)
+}
@@ -53,6 +53,22 @@ impl From<esp_idf_sys::camera::pixformat_t> for PixelFormat {
     }
 }
 
+impl From<PixelFormat> for flock_api::PixelFormat {
+    fn from(pix_format: PixelFormat) -> Self {
+        match pix_format {
+            PixelFormat::RGB565 => Self::Rgb565,
+            PixelFormat::YUV422 => Self::Yuv422,
+            PixelFormat::YUV420 => Self::Yuv420,
+            PixelFormat::GRAYSCALE => Self::Grayscale,
+            PixelFormat::JPEG => Self::Jpeg,
+            PixelFormat::RGB888 => Self::Rgb888,
+            PixelFormat::RAW => Self::Raw,
+            PixelFormat::RGB444 => Self::Rgb444,
+            PixelFormat::RGB555 => Self::Rgb555,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum FrameSize {
     FrameSize96X96,   // 96x96
@@ -82,6 +98,37 @@ pub enum FrameSize {
     FrameSizeINVALID,
 }
 
+impl FrameSize {
+    /// Width and height in pixels
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            FrameSize::FrameSize96X96 => (96, 96),
+            FrameSize::FrameSizeQQVGA => (160, 120),
+            FrameSize::FrameSizeQCIF => (176, 144),
+            FrameSize::FrameSizeHQVGA => (240, 176),
+            FrameSize::FrameSize240X240 => (240, 240),
+            FrameSize::FrameSizeQVGA => (320, 240),
+            FrameSize::FrameSizeCIF => (400, 296),
+            FrameSize::FrameSizeHVGA => (480, 320),
+            FrameSize::FrameSizeVGA => (640, 480),
+            FrameSize::FrameSizeSVGA => (800, 600),
+            FrameSize::FrameSizeXGA => (1024, 768),
+            FrameSize::FrameSizeHD => (1280, 720),
+            FrameSize::FrameSizeSXGA => (1280, 1024),
+            FrameSize::FrameSizeUXGA => (1600, 1200),
+            FrameSize::FrameSizeFHD => (1920, 1080),
+            FrameSize::FrameSizePHD => (720, 1280),
+            FrameSize::FrameSizeP3MP => (864, 1536),
+            FrameSize::FrameSizeQXGA => (2048, 1536),
+            FrameSize::FrameSizeQHD => (2560, 1440),
+            FrameSize::FrameSizeWQXGA => (2560, 1600),
+            FrameSize::FrameSizePFHD => (1080, 1920),
+            FrameSize::FrameSizeQSXGA => (2560, 1920),
+            FrameSize::FrameSizeINVALID => (0, 0),
+        }
+    }
+}
+
 impl From<FrameSize> for esp_idf_sys::camera::framesize_t {
     fn from(frame_size: FrameSize) -> Self {
         match frame_size {
@@ -235,6 +282,33 @@ impl Default for CameraConfig {
     }
 }
 
+impl CameraConfig {
+    /// GPIOs claimed by the camera interface. Unused pins (-1) are left out.
+    pub fn pins(&self) -> Vec<i32> {
+        [
+            self.pin_pwdn,
+            self.pin_reset,
+            self.pin_xclk,
+            self.pin_sda,
+            self.pin_scl,
+            self.pin_d7,
+            self.pin_d6,
+            self.pin_d5,
+            self.pin_d4,
+            self.pin_d3,
+            self.pin_d2,
+            self.pin_d1,
+            self.pin_d0,
+            self.pin_vsync,
+            self.pin_href,
+            self.pin_pclk,
+        ]
+        .into_iter()
+        .filter(|p| *p >= 0)
+        .collect()
+    }
+}
+
 impl From<CameraConfig> for esp_idf_sys::camera::camera_config_t {
     fn from(config: CameraConfig) -> Self {
         Self {
@@ -292,13 +366,7 @@ impl Camera {
     }
 
     pub fn fb_get<'fb>(&self) -> Option<FrameBuffer<'fb>> {
-        unsafe {
-            let fb = esp_idf_sys::camera::esp_camera_fb_get();
-            if fb.is_null() {
-                return None;
-            }
-            Some(fb.into())
-        }
+        fb_get_shared()
     }
 
     pub fn sensor(&self) -> &SensorHandle {
@@ -306,6 +374,19 @@ impl Camera {
     }
 }
 
+/// Grabs a frame without going through the `Camera` handle, for tasks that don't own it (e.g. HTTP
+/// handlers). The driver hands out frame buffers from a queue, so this is safe across tasks as long
+/// as the camera has been initialized.
+pub fn fb_get_shared<'fb>() -> Option<FrameBuffer<'fb>> {
+    unsafe {
+        let fb = esp_idf_sys::camera::esp_camera_fb_get();
+        if fb.is_null() {
+            return None;
+        }
+        Some(fb.into())
+    }
+}
+
 impl Drop for Camera {
     fn drop(&mut self) {
         self.de_init().expect("error de-initializing camera driver");
@@ -339,6 +420,103 @@ impl<'fb> FrameBuffer<'fb> {
     pub fn format(&self) -> PixelFormat {
         unsafe { (*self.fb).format.into() }
     }
+
+    /// Describes the frame for the controller, which can't know how the camera was initialized.
+    pub fn frame_format(&self) -> flock_api::FrameFormat {
+        flock_api::FrameFormat {
+            pixel_format: self.format().into(),
+            width: self.width(),
+            height: self.height(),
+        }
+    }
+
+    /// Copies the frame out in `encoding`, with a description of the result. `None` if JPEG
+    /// conversion fails.
+    pub fn encode(
+        &self,
+        encoding: flock_api::FrameEncoding,
+    ) -> Option<(Vec<u8>, flock_api::FrameFormat)> {
+        let format = self.frame_format();
+        match encoding {
+            flock_api::FrameEncoding::Raw => Some((Vec::from(self.data()), format)),
+            flock_api::FrameEncoding::Jpeg { quality } => {
+                let jpeg = self.to_jpeg(quality)?;
+                let format = flock_api::FrameFormat {
+                    pixel_format: flock_api::PixelFormat::Jpeg,
+                    ..format
+                };
+                Some((jpeg, format))
+            }
+        }
+    }
+
+    /// Like `encode`, but cuts `roi` out of the frame first. For sensors that can't window.
+    pub fn encode_region(
+        &self,
+        roi: &flock_api::Roi,
+        encoding: flock_api::FrameEncoding,
+    ) -> anyhow::Result<(Vec<u8>, flock_api::FrameFormat)> {
+        let (data, format) = flock_api::frame::crop(self.data(), &self.frame_format(), roi)?;
+        match encoding {
+            flock_api::FrameEncoding::Raw => Ok((data, format)),
+            flock_api::FrameEncoding::Jpeg { quality } => {
+                let jpeg = fmt_to_jpeg(&data, format.width, format.height, self.format(), quality)
+                    .ok_or_else(|| anyhow::anyhow!("Error encoding frame as JPEG"))?;
+                let format = flock_api::FrameFormat {
+                    pixel_format: flock_api::PixelFormat::Jpeg,
+                    ..format
+                };
+                Ok((jpeg, format))
+            }
+        }
+    }
+
+    /// Encodes the frame as JPEG (quality 0-100) using esp32-camera's converters. Frames that are
+    /// already JPEG are copied as is.
+    pub fn to_jpeg(&self, quality: u8) -> Option<Vec<u8>> {
+        if let PixelFormat::JPEG = self.format() {
+            return Some(Vec::from(self.data()));
+        }
+        let mut out: *mut u8 = std::ptr::null_mut();
+        let mut out_len: esp_idf_sys::size_t = 0;
+        unsafe {
+            if !esp_idf_sys::camera::frame2jpg(self.fb, quality, &mut out, &mut out_len) {
+                return None;
+            }
+            let jpeg = Vec::from(std::slice::from_raw_parts(out, out_len.try_into().unwrap()));
+            esp_idf_sys::free(out as *mut std::ffi::c_void);
+            Some(jpeg)
+        }
+    }
+}
+
+/// Encodes an uncompressed frame that isn't in a frame buffer as JPEG, like `FrameBuffer::to_jpeg`.
+pub fn fmt_to_jpeg(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    quality: u8,
+) -> Option<Vec<u8>> {
+    let mut out: *mut u8 = std::ptr::null_mut();
+    let mut out_len: esp_idf_sys::size_t = 0;
+    unsafe {
+        if !esp_idf_sys::camera::fmt2jpg(
+            data.as_ptr() as *mut u8,
+            data.len().try_into().unwrap(),
+            width.try_into().unwrap(),
+            height.try_into().unwrap(),
+            format.into(),
+            quality,
+            &mut out,
+            &mut out_len,
+        ) {
+            return None;
+        }
+        let jpeg = Vec::from(std::slice::from_raw_parts(out, out_len.try_into().unwrap()));
+        esp_idf_sys::free(out as *mut std::ffi::c_void);
+        Some(jpeg)
+    }
 }
 
 impl<'fb> From<*mut esp_idf_sys::camera::camera_fb_t> for FrameBuffer<'fb> {
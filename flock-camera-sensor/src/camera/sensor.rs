@@ -4,6 +4,7 @@ use super::{FrameBuffer, FrameSize, PixelFormat};
 use esp_idf_sys::{esp, EspError};
 use log::*;
 use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
 
 pub struct SensorId {
     pub midh: u8,
@@ -67,6 +68,17 @@ impl SensorHandle {
         }
     }
 
+    /// Sensor model name as known to esp32-camera (e.g. OV2640)
+    pub fn model(&self) -> Option<String> {
+        unsafe {
+            let info = esp_idf_sys::camera::esp_camera_sensor_get_info(&mut (*self.s).id);
+            if info.is_null() {
+                return None;
+            }
+            Some(CStr::from_ptr((*info).name).to_string_lossy().into_owned())
+        }
+    }
+
     pub fn slv_addr(&self) -> u8 {
         unsafe { (*self.s).slv_addr }
     }
@@ -0,0 +1,24 @@
+use flock_api::FrameEncoding;
+use log::*;
+
+// Kept in RTC slow memory like the power mode, so low-power nodes keep encoding their wake
+// readings across deep sleep. 0 means raw, which is also what a cold boot starts with.
+#[link_section = ".rtc.data"]
+static mut JPEG_QUALITY: u8 = 0;
+
+pub fn frame_encoding() -> FrameEncoding {
+    match unsafe { JPEG_QUALITY } {
+        0 => FrameEncoding::Raw,
+        quality => FrameEncoding::Jpeg { quality },
+    }
+}
+
+pub fn set_frame_encoding(encoding: &FrameEncoding) {
+    info!("setting frame encoding: {:?}", encoding);
+    let quality = match encoding {
+        FrameEncoding::Raw => 0,
+        // frame2jpg takes 1-100, and 0 is taken
+        FrameEncoding::Jpeg { quality } => (*quality).clamp(1, 100),
+    };
+    unsafe { JPEG_QUALITY = quality };
+}
@@ -0,0 +1,203 @@
+use crate::camera::fb_get_shared;
+use anyhow::bail;
+use esp_idf_sys::{esp, EspError};
+use flock_api::{HttpServerConfig, HttpServerStatus};
+use log::*;
+use std::ffi::{c_void, CStr, CString};
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const JPEG_QUALITY: u8 = 80;
+const BOUNDARY: &str = "flockframe";
+
+struct Context {
+    token: String,
+    running: AtomicBool,
+}
+
+/// Optional HTTP server for live viewing, next to the MQTT control path.
+///
+/// `/stream` serves multipart MJPEG and `/capture` a single JPEG. Non-JPEG frames are encoded on
+/// the fly. The server runs a single task, so `/capture` waits while a stream is open.
+pub struct HttpServer {
+    handle: esp_idf_sys::httpd_handle_t,
+    port: u16,
+    // Referenced by the URI handlers through `user_ctx`, so it has to outlive the server
+    context: Box<Context>,
+    _uris: Vec<CString>,
+}
+
+impl HttpServer {
+    pub fn start(config: &HttpServerConfig) -> anyhow::Result<Self> {
+        if config.token.is_empty() {
+            bail!("The HTTP server needs a token, anyone could watch otherwise");
+        }
+        info!("Starting HTTP server on port {}", config.port);
+        let httpd_config = esp_idf_sys::httpd_config_t {
+            task_priority: 5,
+            stack_size: 8192,
+            core_id: i32::MAX, // tskNO_AFFINITY
+            server_port: config.port,
+            ctrl_port: 32768,
+            max_open_sockets: 3,
+            max_uri_handlers: 2,
+            max_resp_headers: 8,
+            backlog_conn: 5,
+            lru_purge_enable: true,
+            recv_wait_timeout: 5,
+            send_wait_timeout: 5,
+            ..Default::default()
+        };
+        let mut handle: esp_idf_sys::httpd_handle_t = std::ptr::null_mut();
+        esp!(unsafe { esp_idf_sys::httpd_start(&mut handle, &httpd_config) })?;
+
+        let mut server = Self {
+            handle,
+            port: config.port,
+            context: Box::new(Context {
+                token: config.token.clone(),
+                running: AtomicBool::new(true),
+            }),
+            _uris: vec![],
+        };
+        server.register("/stream", stream_handler)?;
+        server.register("/capture", capture_handler)?;
+        Ok(server)
+    }
+
+    fn register(
+        &mut self,
+        uri: &str,
+        handler: unsafe extern "C" fn(*mut esp_idf_sys::httpd_req_t) -> esp_idf_sys::esp_err_t,
+    ) -> Result<(), EspError> {
+        let uri = CString::new(uri).unwrap();
+        let httpd_uri = esp_idf_sys::httpd_uri_t {
+            uri: uri.as_ptr(),
+            method: esp_idf_sys::http_method_HTTP_GET,
+            handler: Some(handler),
+            user_ctx: self.context.as_ref() as *const Context as *mut c_void,
+            ..Default::default()
+        };
+        esp!(unsafe { esp_idf_sys::httpd_register_uri_handler(self.handle, &httpd_uri) })?;
+        self._uris.push(uri);
+        Ok(())
+    }
+
+    pub fn status(&self) -> HttpServerStatus {
+        HttpServerStatus {
+            enabled: true,
+            url: station_ip().map(|ip| format!("http://{}:{}", ip, self.port)),
+        }
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        info!("Stopping HTTP server");
+        // Ends any open stream before the handlers lose their context
+        self.context.running.store(false, Ordering::SeqCst);
+        if let Err(err) = esp!(unsafe { esp_idf_sys::httpd_stop(self.handle) }) {
+            error!("Error stopping HTTP server: {:?}", err);
+        }
+    }
+}
+
+pub fn disabled_status() -> HttpServerStatus {
+    HttpServerStatus {
+        enabled: false,
+        url: None,
+    }
+}
+
+fn station_ip() -> Option<String> {
+    let key = CString::new("WIFI_STA_DEF").unwrap();
+    let mut ip_info = esp_idf_sys::esp_netif_ip_info_t::default();
+    unsafe {
+        let netif = esp_idf_sys::esp_netif_get_handle_from_ifkey(key.as_ptr());
+        if netif.is_null() || esp_idf_sys::esp_netif_get_ip_info(netif, &mut ip_info) != 0 {
+            return None;
+        }
+    }
+    // esp_ip4_addr_t is stored in network byte order
+    let addr = ip_info.ip.addr.to_le_bytes();
+    Some(format!("{}.{}.{}.{}", addr[0], addr[1], addr[2], addr[3]))
+}
+
+unsafe fn context<'a>(req: *mut esp_idf_sys::httpd_req_t) -> &'a Context {
+    &*((*req).user_ctx as *const Context)
+}
+
+unsafe fn authorized(req: *mut esp_idf_sys::httpd_req_t) -> bool {
+    // Sized to fit, truncated tokens could match a prefix of the real one
+    let len = esp_idf_sys::httpd_req_get_url_query_len(req) as usize + 1;
+    let mut query = vec![0 as c_char; len];
+    let mut token = vec![0 as c_char; len];
+    let key = CString::new("token").unwrap();
+    if esp_idf_sys::httpd_req_get_url_query_str(req, query.as_mut_ptr(), query.len() as _) != 0
+        || esp_idf_sys::httpd_query_key_value(
+            query.as_ptr(),
+            key.as_ptr(),
+            token.as_mut_ptr(),
+            token.len() as _,
+        ) != 0
+    {
+        return false;
+    }
+    let token = CStr::from_ptr(token.as_ptr()).to_bytes();
+    flock_api::auth::constant_time_eq(token, context(req).token.as_bytes())
+}
+
+unsafe fn reject(req: *mut esp_idf_sys::httpd_req_t) -> esp_idf_sys::esp_err_t {
+    let status = CString::new("401 Unauthorized").unwrap();
+    esp_idf_sys::httpd_resp_set_status(req, status.as_ptr());
+    esp_idf_sys::httpd_resp_send(req, std::ptr::null(), 0)
+}
+
+unsafe fn send_chunk(req: *mut esp_idf_sys::httpd_req_t, data: &[u8]) -> esp_idf_sys::esp_err_t {
+    esp_idf_sys::httpd_resp_send_chunk(req, data.as_ptr() as *const c_char, data.len() as _)
+}
+
+unsafe extern "C" fn capture_handler(req: *mut esp_idf_sys::httpd_req_t) -> esp_idf_sys::esp_err_t {
+    if !authorized(req) {
+        return reject(req);
+    }
+    let jpeg = match fb_get_shared().and_then(|fb| fb.to_jpeg(JPEG_QUALITY)) {
+        Some(jpeg) => jpeg,
+        None => return esp_idf_sys::httpd_resp_send_500(req),
+    };
+    let content_type = CString::new("image/jpeg").unwrap();
+    esp_idf_sys::httpd_resp_set_type(req, content_type.as_ptr());
+    esp_idf_sys::httpd_resp_send(req, jpeg.as_ptr() as *const c_char, jpeg.len() as _)
+}
+
+unsafe extern "C" fn stream_handler(req: *mut esp_idf_sys::httpd_req_t) -> esp_idf_sys::esp_err_t {
+    if !authorized(req) {
+        return reject(req);
+    }
+    let content_type =
+        CString::new(format!("multipart/x-mixed-replace;boundary={}", BOUNDARY)).unwrap();
+    esp_idf_sys::httpd_resp_set_type(req, content_type.as_ptr());
+
+    info!("MJPEG stream opened");
+    let ctx = context(req);
+    while ctx.running.load(Ordering::SeqCst) {
+        let jpeg = match fb_get_shared().and_then(|fb| fb.to_jpeg(JPEG_QUALITY)) {
+            Some(jpeg) => jpeg,
+            None => {
+                error!("Error capturing frame for MJPEG stream");
+                break;
+            }
+        };
+        let header = format!(
+            "\r\n--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            BOUNDARY,
+            jpeg.len()
+        );
+        // Fails once the client goes away
+        if send_chunk(req, header.as_bytes()) != 0 || send_chunk(req, &jpeg) != 0 {
+            break;
+        }
+    }
+    info!("MJPEG stream closed");
+    send_chunk(req, &[])
+}
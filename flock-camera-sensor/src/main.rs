@@ -1,37 +1,85 @@
+mod actuator;
+mod burst;
 mod camera;
-
+mod encoding;
+mod http;
+mod mdns;
+mod motion;
+#[cfg(not(feature = "serial"))]
+mod mqtt;
+mod power;
+mod roi;
+mod storage;
+#[cfg(feature = "serial")]
+mod uart;
+
+use actuator::Actuators;
 use anyhow::{bail, Context};
 use camera::*;
+use http::HttpServer;
+use mdns::Mdns;
+#[cfg(not(feature = "serial"))]
+use mqtt::{MqttReceiver, MqttSender, Pubacks};
 
+#[cfg(not(feature = "serial"))]
 use embedded_svc::ipv4;
-use embedded_svc::mqtt::client::{utils, Message};
-use embedded_svc::mqtt::client::{Client, Connection, Event, MessageImpl, Publish, QoS};
+#[cfg(not(feature = "serial"))]
+use embedded_svc::mqtt::client::QoS;
+#[cfg(not(feature = "serial"))]
 use embedded_svc::ping::Ping;
+#[cfg(not(feature = "serial"))]
 use embedded_svc::wifi::*;
-use esp_idf_hal::mutex::Condvar;
 use esp_idf_hal::prelude::*;
-use esp_idf_svc::mqtt::client::{EspMqttClient, MqttClientConfiguration};
+#[cfg(not(feature = "serial"))]
+use esp_idf_svc::mqtt::client::{EspMqttClient, LwtConfiguration, MqttClientConfiguration};
+#[cfg(not(feature = "serial"))]
 use esp_idf_svc::netif::EspNetifStack;
+#[cfg(not(feature = "serial"))]
 use esp_idf_svc::nvs::EspDefaultNvs;
+#[cfg(not(feature = "serial"))]
 use esp_idf_svc::ping::EspPing;
+#[cfg(not(feature = "serial"))]
+use esp_idf_svc::sntp::EspSntp;
+#[cfg(not(feature = "serial"))]
 use esp_idf_svc::sysloop::EspSysLoopStack;
+#[cfg(not(feature = "serial"))]
 use esp_idf_svc::wifi::EspWifi;
 use esp_idf_sys as _;
 // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 use esp_idf_sys::EspError;
 use log::*;
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{mpsc, Arc};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::Duration;
-use flock_api::CameraSensorConfig;
-
+#[cfg(feature = "serial")]
+use flock_api::framing::{FramedReceiver, FramedSender};
+use flock_api::transport::{Incoming, TransportReceiver, TransportSender};
+use flock_api::{CameraSensorConfig, PowerMode};
+use storage::Storage;
+
+// Tethered builds need neither Wi-Fi credentials nor a broker
+#[cfg(not(feature = "serial"))]
 const SSID: &str = env!("FLOCK_WIFI_SSID");
+#[cfg(not(feature = "serial"))]
 const PASS: &str = env!("FLOCK_WIFI_PASS");
+#[cfg(not(feature = "serial"))]
 const MQTT_BROKER_ADDR: &str = env!("FLOCK_MQTT_BROKER_ADDR");
 const CLIENT_ID: &str = env!("FLOCK_CLIENT_ID");
 const CONTROLLER_TOPIC: &str = env!("FLOCK_CONTROLLER_TOPIC");
 
+// UART0 is the one wired to the USB-serial bridge
+#[cfg(feature = "serial")]
+const SERIAL_UART: esp_idf_sys::uart_port_t = 0;
+#[cfg(feature = "serial")]
+const SERIAL_BAUD_RATE: i32 = 921_600;
+
+// Flash LED on the AI-Thinker ESP32-CAM
+const FLASH_LED: &str = "flash";
+const FLASH_LED_PIN: i32 = 4;
+// How often the motion detector thread looks for a change of settings while it has nothing to do
+const MOTION_IDLE_POLL: Duration = Duration::from_secs(1);
+
 #[allow(unused)]
 fn mqtt_client_id() -> String {
     format!("flock-client-{}", CLIENT_ID)
@@ -157,6 +205,7 @@ fn set_sensor_config(cam: &Camera, cfg: &CameraSensorConfig) -> Result<(), EspEr
     Ok(())
 }
 
+#[cfg(not(feature = "serial"))]
 fn ping(ip_settings: &ipv4::ClientSettings) -> anyhow::Result<()> {
     info!("About to do some pings for {:?}", ip_settings);
 
@@ -173,6 +222,7 @@ fn ping(ip_settings: &ipv4::ClientSettings) -> anyhow::Result<()> {
     Ok(())
 }
 
+#[cfg(not(feature = "serial"))]
 fn wifi(
     netif_stack: Arc<EspNetifStack>,
     sys_loop_stack: Arc<EspSysLoopStack>,
@@ -229,14 +279,111 @@ fn wifi(
     Ok(wifi)
 }
 
-fn handle_flock_message(cam: &Camera, msg: flock_api::Message) -> Option<flock_api::Payload> {
+type SharedStorage = Option<Arc<Mutex<Storage>>>;
+
+// Everything the receiver thread needs to act on instructions. Fields drop in order, and the
+// HTTP server's handlers use the camera, so it goes first.
+struct Node {
+    http: Option<HttpServer>,
+    cam: Camera,
+    actuators: Actuators,
+    storage: SharedStorage,
+    mdns: Option<Mdns>,
+}
+
+impl Node {
+    fn advertise_http_port(&self, port: u16) {
+        if let Some(Err(err)) = self.mdns.as_ref().map(|m| m.set_port(port)) {
+            error!("Error updating mDNS service port: {:?}", err);
+        }
+    }
+}
+
+/// Takes a frame, cropped to the region of interest and encoded.
+fn capture(
+    node: &Node,
+    encoding: flock_api::FrameEncoding,
+) -> anyhow::Result<(Vec<u8>, flock_api::FrameFormat)> {
+    let cam = &node.cam;
+    let fb = node
+        .actuators
+        .pulse(|| cam.fb_get())
+        .ok_or_else(|| anyhow::anyhow!("Error getting frame buffer"))?;
+    // Encoding takes a while, no need to keep illuminators on for it. The buffer goes back to
+    // the driver when it's dropped here.
+    roi::encode(&fb, encoding)
+}
+
+fn read_sensor(node: &Node, encoding: flock_api::FrameEncoding) -> Option<flock_api::Payload> {
+    let (frame_buffer, format) = match capture(node, encoding) {
+        Ok(frame) => frame,
+        Err(err) => return Some(flock_api::Payload::Error(format!("{:?}", err))),
+    };
+    if let Some(storage) = &node.storage {
+        match storage.lock().unwrap().save(&frame_buffer, format) {
+            Ok(capture) => {
+                return Some(flock_api::Payload::StoredReading(stored_reading(
+                    capture,
+                    frame_buffer,
+                )));
+            }
+            Err(err) => error!("Error storing capture: {:?}", err),
+        }
+    }
+    Some(flock_api::Payload::SensorReading(flock_api::SensorData::Camera {
+        frame_buffer,
+        format,
+    }))
+}
+
+fn stored_reading(
+    capture: flock_api::StoredCapture,
+    frame_buffer: Vec<u8>,
+) -> flock_api::StoredReading {
+    flock_api::StoredReading {
+        reading: flock_api::SensorData::Camera {
+            frame_buffer,
+            format: capture.format,
+        },
+        capture,
+    }
+}
+
+fn handle_storage_instruction(
+    storage: &SharedStorage,
+    instruction: flock_api::Instruction,
+) -> anyhow::Result<flock_api::Payload> {
+    let storage = match storage {
+        Some(storage) => storage,
+        None => bail!("No SD card mounted"),
+    };
+    let mut storage = storage.lock().unwrap();
+    match instruction {
+        flock_api::Instruction::FetchCapture(id) => {
+            Ok(flock_api::Payload::StoredReading(stored_reading(
+                storage.get(id)?,
+                storage.read(id)?,
+            )))
+        }
+        flock_api::Instruction::DeleteCapture(id) => {
+            storage.delete(id)?;
+            Ok(flock_api::Payload::StoredCaptures(storage.list()))
+        }
+        _ => Ok(flock_api::Payload::StoredCaptures(storage.list())),
+    }
+}
+
+fn handle_flock_message(node: &mut Node, msg: flock_api::Message) -> Vec<flock_api::Payload> {
+    let cam = &node.cam;
     if let flock_api::Payload::Instruction(instruction) = *msg.payload {
-        return match instruction {
-            flock_api::Instruction::ReadSensor => {
-                cam.fb_get()
-                    .map(|fb| flock_api::Payload::SensorReading(flock_api::SensorData::Camera {
-                        frame_buffer: Vec::from(fb.data())
-                    }))
+        let payload = match instruction {
+            flock_api::Instruction::ReadSensor => read_sensor(node, encoding::frame_encoding()),
+            flock_api::Instruction::ReadSensorEncoded(frame_encoding) => {
+                read_sensor(node, frame_encoding)
+            }
+            // The only instruction answered with more than one message
+            flock_api::Instruction::Burst(config) => {
+                return burst::take(cam, &config, |encoding| capture(node, encoding));
             }
             flock_api::Instruction::ReadSensorConfig => {
                 let status = cam.sensor().status();
@@ -247,95 +394,336 @@ fn handle_flock_message(cam: &Camera, msg: flock_api::Message) -> Option<flock_a
                 if let Err(err) = set_sensor_config(cam, &cam_cfg) {
                     Some(flock_api::Payload::Error(format!("{:?}", err)))
                 } else {
+                    // The window was set up for the old config
+                    roi::restore(cam);
                     let status = cam.sensor().status();
                     Some(flock_api::Payload::SensorConfig(flock_api::SensorConfig::Camera(status.into())))
                 }
             }
+            flock_api::Instruction::ReadSensorInfo => {
+                let id = cam.sensor().id();
+                Some(flock_api::Payload::SensorInfo(flock_api::SensorInfo {
+                    manufacturer_id: (u16::from(id.midh) << 8) | u16::from(id.midl),
+                    product_id: id.pid,
+                    version: id.ver,
+                    model: cam.sensor().model(),
+                }))
+            }
+            flock_api::Instruction::ReadPowerMode => {
+                Some(flock_api::Payload::PowerMode(power::power_mode()))
+            }
+            flock_api::Instruction::WritePowerMode(mode) => match power::set_power_mode(&mode) {
+                Ok(()) => Some(flock_api::Payload::PowerMode(power::power_mode())),
+                Err(err) => Some(flock_api::Payload::Error(format!("{:?}", err))),
+            },
+            flock_api::Instruction::ReadFrameEncoding => {
+                Some(flock_api::Payload::FrameEncoding(encoding::frame_encoding()))
+            }
+            flock_api::Instruction::WriteFrameEncoding(frame_encoding) => {
+                encoding::set_frame_encoding(&frame_encoding);
+                Some(flock_api::Payload::FrameEncoding(encoding::frame_encoding()))
+            }
+            flock_api::Instruction::ReadRoi => Some(flock_api::Payload::Roi(roi::status(cam))),
+            flock_api::Instruction::WriteRoi(region) => match roi::set_roi(cam, region) {
+                Ok(status) => Some(flock_api::Payload::Roi(status)),
+                Err(err) => Some(flock_api::Payload::Error(format!("{:?}", err))),
+            },
+            flock_api::Instruction::ReadMotionDetection => {
+                Some(flock_api::Payload::MotionDetection(motion::settings()))
+            }
+            flock_api::Instruction::WriteMotionDetection(settings) => {
+                motion::set_settings(settings);
+                Some(flock_api::Payload::MotionDetection(motion::settings()))
+            }
+            flock_api::Instruction::ReadActuators => {
+                Some(flock_api::Payload::Actuators(node.actuators.list()))
+            }
+            flock_api::Instruction::WriteActuator(actuator) => {
+                if let Err(err) = node.actuators.write(actuator) {
+                    Some(flock_api::Payload::Error(format!("{:?}", err)))
+                } else {
+                    Some(flock_api::Payload::Actuators(node.actuators.list()))
+                }
+            }
+            flock_api::Instruction::ListCaptures
+            | flock_api::Instruction::FetchCapture(_)
+            | flock_api::Instruction::DeleteCapture(_) => {
+                match handle_storage_instruction(&node.storage, instruction) {
+                    Ok(payload) => Some(payload),
+                    Err(err) => Some(flock_api::Payload::Error(format!("{:?}", err))),
+                }
+            }
+            flock_api::Instruction::ReadHttpServer => Some(flock_api::Payload::HttpServer(
+                node.http.as_ref().map_or_else(http::disabled_status, |s| s.status()),
+            )),
+            flock_api::Instruction::EnableHttpServer(cfg) => {
+                // Restart with the new config if already running
+                node.http = None;
+                match HttpServer::start(&cfg) {
+                    Ok(server) => {
+                        let status = server.status();
+                        node.http = Some(server);
+                        node.advertise_http_port(cfg.port);
+                        Some(flock_api::Payload::HttpServer(status))
+                    }
+                    Err(err) => {
+                        node.advertise_http_port(0);
+                        Some(flock_api::Payload::Error(format!("{:?}", err)))
+                    }
+                }
+            }
+            flock_api::Instruction::DisableHttpServer => {
+                node.http = None;
+                node.advertise_http_port(0);
+                Some(flock_api::Payload::HttpServer(http::disabled_status()))
+            }
         };
+        return payload.into_iter().collect();
     }
-    return None;
+    return vec![];
 }
 
-fn handle_mqtt_message(evt: Event<MessageImpl>, cam: &Camera) -> Option<flock_api::Message> {
-    let payload = match evt {
-        Event::Connected(_) => Some(flock_api::Payload::Connected),
-        Event::Received(m) => {
-            match serde_json::from_slice::<flock_api::Message>(m.data()) {
-                Ok(msg) => handle_flock_message(cam, msg),
-                Err(err) => {
-                    let message = format!("{:?}", err);
-                    Some(flock_api::Payload::Error(message))
-                }
-            }
-        }
-        _ => None,
+fn handle_incoming(incoming: Incoming, node: &mut Node) -> Vec<flock_api::Message> {
+    let payloads = match incoming {
+        Incoming::Connected => vec![flock_api::Payload::Connected],
+        Incoming::Message(msg) => handle_flock_message(node, msg),
+        Incoming::Malformed(message) => vec![flock_api::Payload::Error(message)],
     };
-    payload.map(|p| flock_api::Message::new(
-        mqtt_client_id(),
-        CONTROLLER_TOPIC.into(),
-        p,
-    ))
+    payloads
+        .into_iter()
+        .map(|p| flock_api::Message::new(mqtt_client_id(), CONTROLLER_TOPIC.into(), p))
+        .collect()
+}
+
+/// What a low-power node publishes on wake without waiting for an instruction: a reading, or with
+/// motion detection on, an alert only when the wake frame shows motion. With an SD card the
+/// reading is also stored, under the same condition, and published from the backlog.
+fn wake_payloads(node: &Node) -> Vec<flock_api::Payload> {
+    let mut payloads = vec![];
+    if motion::settings().is_some() {
+        let grab = || node.actuators.pulse(|| node.cam.fb_get());
+        match motion::check(grab) {
+            Ok(Some(alert)) => payloads.push(flock_api::Payload::Motion(alert)),
+            Ok(None) => return payloads,
+            Err(err) => return vec![flock_api::Payload::Error(format!("{:?}", err))],
+        }
+        // The alert carries the frame if asked to
+        if node.storage.is_none() {
+            return payloads;
+        }
+    }
+    match read_sensor(node, encoding::frame_encoding()) {
+        // Published from the backlog once connected
+        Some(flock_api::Payload::StoredReading(_)) | None => {}
+        Some(p) => payloads.push(p),
+    }
+    payloads
 }
 
+enum Outbound {
+    Message(flock_api::Message),
+    // Let the transport drop a handled instruction it kept around for us
+    Acknowledge,
+    // Publish the captures that are stored on the SD card but haven't been uploaded yet
+    DrainBacklog,
+}
 
-fn spawn_mqtt_receiver(
-    mut connection: utils::Connection<Condvar, MessageImpl, EspError>,
-    tx: Sender<flock_api::Message>,
+fn spawn_receiver<R: TransportReceiver + Send + 'static>(
+    mut receiver: R,
+    tx: Sender<Outbound>,
+    storage: SharedStorage,
 ) -> thread::JoinHandle<()> {
-    info!("Spawning MQTT watcher thread");
+    info!("Spawning receiver thread");
     thread::spawn(move || {
+        let config = CameraConfig::default();
+        info!("Initializing actuators");
+        let mut actuators = Actuators::new(&config)
+            .with_context(|| "Error initializing actuators")
+            .unwrap();
+        actuators
+            .write(flock_api::Actuator {
+                name: FLASH_LED.into(),
+                pin: FLASH_LED_PIN,
+                state: flock_api::ActuatorState::Off,
+            })
+            .with_context(|| "Error initializing flash LED")
+            .unwrap();
+        if storage.is_some() {
+            actuators.reserve_pins(&storage::PINS);
+        }
         info!("Initializing camera");
-        let cam = Camera::init(CameraConfig::default())
+        let cam = Camera::init(config)
             .with_context(|| "Error initializing camera")
             .unwrap();
         info!("Configuring camera sensor");
         sensor_config(&cam)
             .with_context(|| "Error configuring camera sensor")
             .unwrap();
+        roi::restore(&cam);
+        let sensor_model = cam.sensor().model().unwrap_or_else(|| "unknown".into());
+        let mdns = Mdns::start(&mqtt_client_id(), &sensor_model)
+            .map_err(|err| error!("Error starting mDNS: {:?}", err))
+            .ok();
+        let mut node = Node {
+            http: None,
+            cam,
+            actuators,
+            storage,
+            mdns,
+        };
 
-        while let Some(msg) = connection.next() {
-            match msg {
-                Ok(evt) => {
-                    info!("MQTT Message received: {:?}", evt);
-                    if let Some(msg) = handle_mqtt_message(evt, &cam) {
-                        tx.send(msg).unwrap();
+        // Capture on wake even if the broker turns out to be unreachable, stored readings are
+        // published from the backlog once connected
+        let mut wake = if power::is_low_power() {
+            wake_payloads(&node)
+        } else {
+            vec![]
+        };
+
+        while let Some(incoming) = receiver.receive() {
+            match incoming {
+                Ok(incoming) => {
+                    let connected = matches!(incoming, Incoming::Connected);
+                    // Undecodable retained messages would be replayed on every wake too
+                    let received = matches!(incoming, Incoming::Message(_) | Incoming::Malformed(_));
+                    for msg in handle_incoming(incoming, &mut node) {
+                        tx.send(Outbound::Message(msg)).unwrap();
+                    }
+                    if connected && node.storage.is_some() {
+                        tx.send(Outbound::DrainBacklog).unwrap();
+                    }
+                    if connected {
+                        for p in wake.drain(..) {
+                            let msg = flock_api::Message::new(mqtt_client_id(), CONTROLLER_TOPIC.into(), p);
+                            tx.send(Outbound::Message(msg)).unwrap();
+                        }
+                    }
+                    // Instructions are retained until handled, whatever the power mode
+                    if received {
+                        tx.send(Outbound::Acknowledge).unwrap();
                     }
                 }
                 Err(err) => {
-                    error!("MQTT Error : {:?}", err);
+                    error!("Transport Error : {:?}", err);
                 }
             }
         }
-        panic!("Connection watcher closed unexpectedly")
+        panic!("Transport closed unexpectedly")
     })
 }
 
-#[allow(unused)]
-fn spawn_mqtt_publisher(
-    mut client: EspMqttClient<utils::ConnState<MessageImpl, EspError>>,
-    rx: Receiver<flock_api::Message>,
+/// Checks for motion every `MotionDetection::interval_ms` while always on, and publishes alerts.
+fn spawn_motion_detector(tx: Sender<Outbound>) -> thread::JoinHandle<()> {
+    info!("Spawning motion detector thread");
+    thread::spawn(move || loop {
+        let settings = match motion::settings() {
+            Some(settings) if !power::is_low_power() => settings,
+            // Low-power nodes check on wake instead
+            _ => {
+                thread::sleep(MOTION_IDLE_POLL);
+                continue;
+            }
+        };
+        match motion::check(camera::fb_get_shared) {
+            Ok(Some(alert)) => {
+                let msg = flock_api::Message::new(
+                    mqtt_client_id(),
+                    CONTROLLER_TOPIC.into(),
+                    flock_api::Payload::Motion(alert),
+                );
+                tx.send(Outbound::Message(msg)).unwrap();
+            }
+            Ok(None) => {}
+            Err(err) => error!("Error checking for motion: {:?}", err),
+        }
+        thread::sleep(Duration::from_millis(settings.interval_ms.into()));
+    })
+}
+
+fn spawn_publisher<S: TransportSender + Send + 'static>(
+    mut sender: S,
+    rx: Receiver<Outbound>,
+    storage: SharedStorage,
 ) -> thread::JoinHandle<()> {
-    let client_id = mqtt_client_id();
-    info!("Subscribing to topic {}", &client_id);
-    client.subscribe(&client_id, QoS::AtMostOnce).unwrap();
+    let address = sender.address().to_string();
+    sender.subscribe(&address).unwrap();
+    sender
+        .subscribe(flock_api::transport::BROADCAST_ADDRESS)
+        .unwrap();
 
     thread::spawn(move || {
         info!("Waiting for messages to publish");
-        for msg in rx {
-            info!("Sending message: ({:?})", &msg);
-            let payload = serde_json::to_vec(&msg).unwrap();
-            if let Err(err) = client.publish(
-                msg.recipient.as_str(),
-                QoS::AtMostOnce,
-                false,
-                payload.as_slice(),
-            ) {
-                error!("Error publishing MQTT message: (err={})", err);
+        for outbound in rx {
+            match outbound {
+                Outbound::Message(msg) => {
+                    publish(&mut sender, &storage, &msg);
+                }
+                Outbound::Acknowledge => {
+                    if let Err(err) = sender.acknowledge() {
+                        error!("Error acknowledging instruction: {:?}", err);
+                    }
+                }
+                Outbound::DrainBacklog => drain_backlog(&mut sender, &storage),
             }
         }
     })
 }
 
+fn publish<S: TransportSender>(
+    sender: &mut S,
+    storage: &SharedStorage,
+    msg: &flock_api::Message,
+) -> bool {
+    info!("Sending message: ({:?})", msg);
+    // A stored capture only counts as uploaded once the broker confirmed it, otherwise it stays
+    // in the backlog and goes out again on the next connection
+    let stored = match (storage, msg.payload.as_ref()) {
+        (Some(storage), flock_api::Payload::StoredReading(stored)) => Some((storage, stored.capture.id)),
+        _ => None,
+    };
+    let sent = if stored.is_some() {
+        sender.send_confirmed(msg)
+    } else {
+        sender.send(msg)
+    };
+    if let Err(err) = sent {
+        error!("Error sending message: (err={:?})", err);
+        return false;
+    }
+    if let Some((storage, id)) = stored {
+        if let Err(err) = storage.lock().unwrap().mark_uploaded(id) {
+            error!("Error updating capture index: {:?}", err);
+        }
+    }
+    true
+}
+
+fn drain_backlog<S: TransportSender>(sender: &mut S, storage: &SharedStorage) {
+    let backlog = match storage {
+        Some(storage) => storage.lock().unwrap().backlog(),
+        None => return,
+    };
+    info!("Uploading {} stored captures", backlog.len());
+    // One capture in memory at a time, the backlog can be much larger than the heap
+    for capture in backlog {
+        let frame_buffer = match storage.as_ref().unwrap().lock().unwrap().read(capture.id) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("Error reading stored capture {}: {:?}", capture.id, err);
+                continue;
+            }
+        };
+        let msg = flock_api::Message::new(
+            mqtt_client_id(),
+            CONTROLLER_TOPIC.into(),
+            flock_api::Payload::StoredReading(stored_reading(capture, frame_buffer)),
+        );
+        if !publish(sender, storage, &msg) {
+            break;
+        }
+    }
+}
+
 fn main() -> anyhow::Result<()> {
     // Temporary. Will disappear once ESP-IDF 4.4 is released, but for now it is necessary to call this function once,
     // or else some patches to the runtime implemented by esp-idf-sys might not link properly.
@@ -344,34 +732,95 @@ fn main() -> anyhow::Result<()> {
     // Bind the log crate to the ESP Logging facilities
     esp_idf_svc::log::EspLogger::initialize_default();
 
-    let netif_stack = Arc::new(EspNetifStack::new()?);
-    let sysloop_stack = Arc::new(EspSysLoopStack::new()?);
-    let default_nvs = Arc::new(EspDefaultNvs::new()?);
+    if power::woke_from_timer() {
+        info!("Woke from deep sleep ({:?})", power::power_mode());
+    }
+
     let _peripherals = Peripherals::take().unwrap();
-    let _wifi = wifi(
-        netif_stack.clone(),
-        sysloop_stack.clone(),
-        default_nvs.clone(),
-    )?;
-
-    let client_id = mqtt_client_id();
-    let mqtt_config = MqttClientConfiguration {
-        client_id: Some(client_id.as_str()),
-        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
-        ..Default::default()
+
+    let storage = match Storage::mount() {
+        Ok(storage) => Some(Arc::new(Mutex::new(storage))),
+        Err(err) => {
+            warn!("Continuing without local storage: {:?}", err);
+            None
+        }
+    };
+
+    let (tx, rx) = mpsc::channel::<Outbound>();
+
+    let mut handles = vec![spawn_motion_detector(tx.clone())];
+
+    // Tethered nodes talk to the host over USB serial and don't need Wi-Fi at all
+    #[cfg(feature = "serial")]
+    let _link = {
+        info!("Using the serial link on UART{}, logging is disabled", SERIAL_UART);
+        uart::silence_logs();
+        let uart = uart::Uart::install(SERIAL_UART, SERIAL_BAUD_RATE)?;
+
+        handles.push(spawn_receiver(
+            FramedReceiver::with_max_len(uart.try_clone(), flock_api::codec::MAX_INSTRUCTION_LEN),
+            tx,
+            storage.clone(),
+        ));
+        handles.push(spawn_publisher(FramedSender::new(uart, mqtt_client_id()), rx, storage));
     };
 
-    let (client, connection) = EspMqttClient::new_with_conn(MQTT_BROKER_ADDR, &mqtt_config)?;
+    #[cfg(not(feature = "serial"))]
+    let _link = {
+        let netif_stack = Arc::new(EspNetifStack::new()?);
+        let sysloop_stack = Arc::new(EspSysLoopStack::new()?);
+        let default_nvs = Arc::new(EspDefaultNvs::new()?);
+        let wifi = wifi(
+            netif_stack.clone(),
+            sysloop_stack.clone(),
+            default_nvs.clone(),
+        )?;
+        let sntp = EspSntp::new_default()?;
+
+        let client_id = mqtt_client_id();
+        // The broker tells the controller when we drop off without a clean disconnect
+        let will = flock_api::codec::encode(&flock_api::Message::new(
+            client_id.clone(),
+            CONTROLLER_TOPIC.into(),
+            flock_api::Payload::Disconnected,
+        ))?;
+        let mqtt_config = MqttClientConfiguration {
+            client_id: Some(client_id.as_str()),
+            lwt: Some(LwtConfiguration {
+                topic: CONTROLLER_TOPIC,
+                payload: &will,
+                qos: QoS::AtMostOnce,
+                retain: false,
+            }),
+            crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+            ..Default::default()
+        };
+
+        let (client, connection) = EspMqttClient::new_with_conn(MQTT_BROKER_ADDR, &mqtt_config)?;
 
-    let (tx, rx) = mpsc::channel::<flock_api::Message>();
+        let pubacks = Pubacks::default();
 
-    let mut handles = vec![];
+        info!("Spawning MQTT receiver thread");
+        handles.push(spawn_receiver(
+            MqttReceiver::new(connection, pubacks.clone()),
+            tx,
+            storage.clone(),
+        ));
 
-    info!("Spawning MQTT receiver thread");
-    handles.push(spawn_mqtt_receiver(connection, tx));
+        info!("Spawning MQTT publisher thread");
+        handles.push(spawn_publisher(MqttSender::new(client, client_id, pubacks), rx, storage));
 
-    info!("Spawning MQTT publisher thread");
-    handles.push(spawn_mqtt_publisher(client, rx));
+        // Both have to stay up for as long as the node runs
+        (wifi, sntp)
+    };
+
+    // Either the power mode sends us to sleep, or we wait here until a worker thread dies
+    while !handles.iter().any(|h| h.is_finished()) {
+        thread::sleep(power::WAKE_WINDOW);
+        if let PowerMode::LowPower { sleep_interval_secs } = power::power_mode() {
+            power::deep_sleep(Duration::from_secs(sleep_interval_secs.into()));
+        }
+    }
 
     for h in handles.into_iter() {
         h.join().unwrap()
@@ -0,0 +1,69 @@
+use esp_idf_sys::{esp, EspError};
+use log::*;
+use std::ffi::CString;
+
+/// Advertises the node as a `_flock._tcp` service so controllers can find it without reading
+/// serial logs. The TXT records carry the client ID, sensor model and firmware version.
+pub struct Mdns {
+    service_type: CString,
+    proto: CString,
+}
+
+impl Mdns {
+    pub fn start(client_id: &str, sensor_model: &str) -> Result<Self, EspError> {
+        info!("Advertising {} over mDNS", client_id);
+        let hostname = CString::new(client_id).unwrap();
+        let service_type = CString::new(flock_api::MDNS_SERVICE_TYPE).unwrap();
+        let proto = CString::new(flock_api::MDNS_SERVICE_PROTO).unwrap();
+        let txt = [
+            (flock_api::MDNS_TXT_CLIENT_ID, client_id),
+            (flock_api::MDNS_TXT_SENSOR_MODEL, sensor_model),
+            (flock_api::MDNS_TXT_FIRMWARE_VERSION, env!("CARGO_PKG_VERSION")),
+        ]
+        .map(|(k, v)| (CString::new(k).unwrap(), CString::new(v).unwrap()));
+        let mut txt_items = txt
+            .iter()
+            .map(|(k, v)| esp_idf_sys::mdns_txt_item_t {
+                key: k.as_ptr(),
+                value: v.as_ptr(),
+            })
+            .collect::<Vec<_>>();
+
+        esp!(unsafe { esp_idf_sys::mdns_init() })?;
+        let mdns = Self {
+            service_type,
+            proto,
+        };
+        esp!(unsafe { esp_idf_sys::mdns_hostname_set(hostname.as_ptr()) })?;
+        esp!(unsafe { esp_idf_sys::mdns_instance_name_set(hostname.as_ptr()) })?;
+        // Nothing listens until the HTTP server is enabled, see `set_port`
+        esp!(unsafe {
+            esp_idf_sys::mdns_service_add(
+                hostname.as_ptr(),
+                mdns.service_type.as_ptr(),
+                mdns.proto.as_ptr(),
+                0,
+                txt_items.as_mut_ptr(),
+                txt_items.len() as _,
+            )
+        })?;
+        Ok(mdns)
+    }
+
+    /// Points the service record at the node's HTTP server (0 when disabled).
+    pub fn set_port(&self, port: u16) -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_idf_sys::mdns_service_port_set(
+                self.service_type.as_ptr(),
+                self.proto.as_ptr(),
+                port,
+            )
+        })
+    }
+}
+
+impl Drop for Mdns {
+    fn drop(&mut self) {
+        unsafe { esp_idf_sys::mdns_free() }
+    }
+}
@@ -0,0 +1,129 @@
+use crate::camera::{FrameBuffer, PixelFormat};
+use anyhow::bail;
+use flock_api::{MotionAlert, MotionDetection, SensorData};
+use flock_motion::{Detector, Grid, Layout, Thresholds, GRID_HEIGHT, GRID_WIDTH};
+use log::*;
+use std::sync::{Mutex, MutexGuard};
+
+// Kept in RTC slow memory like the power mode, so low-power nodes compare each wake's frame with
+// the background built up over earlier wakes. The receiver thread changes them while the
+// detector thread checks, so both are only touched with `LOCK` held. The lock itself lives in
+// regular memory, it can't outlive the boot that created it.
+#[link_section = ".rtc.data"]
+static mut SETTINGS: Option<MotionDetection> = None;
+#[link_section = ".rtc.data"]
+static mut DETECTOR: Detector = Detector::new();
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn lock() -> MutexGuard<'static, ()> {
+    // Nothing in here panics with the lock held, but a poisoned lock still guards valid data
+    LOCK.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+pub fn settings() -> Option<MotionDetection> {
+    let _guard = lock();
+    unsafe { SETTINGS }
+}
+
+pub fn set_settings(settings: Option<MotionDetection>) {
+    info!("setting motion detection: {:?}", settings);
+    let _guard = lock();
+    unsafe {
+        SETTINGS = settings;
+        // Thresholds don't affect the background, but a node that was off for a while has a
+        // stale one
+        DETECTOR.reset();
+    }
+}
+
+/// Grabs a frame with `grab` and compares it with the background. `None` if motion detection is
+/// off or didn't trigger.
+pub fn check<'fb>(
+    grab: impl FnOnce() -> Option<FrameBuffer<'fb>>,
+) -> anyhow::Result<Option<MotionAlert>> {
+    let settings = match settings() {
+        Some(settings) => settings,
+        None => return Ok(None),
+    };
+    let fb = match grab() {
+        Some(fb) => fb,
+        None => bail!("Error getting frame buffer"),
+    };
+    let grid = grid(&fb)?;
+    // The wall clock jumps when SNTP syncs, the cooldown needs one that doesn't
+    let now_ms = crate::power::uptime_ms();
+    let thresholds = Thresholds {
+        threshold: settings.threshold,
+        min_area: settings.min_area,
+        cooldown_ms: settings.cooldown_ms,
+    };
+    let fed = {
+        let _guard = lock();
+        unsafe { DETECTOR.feed(&grid, now_ms, &thresholds) }
+    };
+    let trigger = match fed {
+        Some(trigger) => trigger,
+        None => return Ok(None),
+    };
+    info!("motion detected: {:?}", trigger);
+
+    let reading = if settings.include_frame {
+        let (frame_buffer, format) = crate::roi::encode(&fb, crate::encoding::frame_encoding())?;
+        Some(SensorData::Camera {
+            frame_buffer,
+            format,
+        })
+    } else {
+        None
+    };
+    Ok(Some(MotionAlert {
+        area: trigger.area,
+        reading,
+    }))
+}
+
+fn grid(fb: &FrameBuffer) -> anyhow::Result<Grid> {
+    let (width, height) = (fb.width() as usize, fb.height() as usize);
+    let grid = match fb.format() {
+        PixelFormat::GRAYSCALE => Grid::from_frame(fb.data(), Layout::Grayscale, width, height),
+        PixelFormat::YUV422 => Grid::from_frame(fb.data(), Layout::Yuv422, width, height),
+        PixelFormat::RGB565 => Grid::from_frame(fb.data(), Layout::Rgb565, width, height),
+        PixelFormat::JPEG => return jpeg_grid(fb.data(), width, height),
+        other => bail!("Can't detect motion in {:?} frames", other),
+    };
+    grid.ok_or_else(|| anyhow::anyhow!("Frame too small to detect motion in"))
+}
+
+/// Decodes a JPEG frame at the smallest scale that still covers the grid. The decoder scales
+/// while decoding, which is much cheaper than decoding whole frames.
+fn jpeg_grid(data: &[u8], width: usize, height: usize) -> anyhow::Result<Grid> {
+    let scales = [
+        (8, esp_idf_sys::camera::jpg_scale_t_JPG_SCALE_8X),
+        (4, esp_idf_sys::camera::jpg_scale_t_JPG_SCALE_4X),
+        (2, esp_idf_sys::camera::jpg_scale_t_JPG_SCALE_2X),
+        (1, esp_idf_sys::camera::jpg_scale_t_JPG_SCALE_NONE),
+    ];
+    let (factor, scale) = match scales
+        .iter()
+        .find(|(f, _)| width / f >= GRID_WIDTH && height / f >= GRID_HEIGHT)
+    {
+        Some(scale) => *scale,
+        None => bail!("Frame too small to detect motion in"),
+    };
+    let (width, height) = (width / factor, height / factor);
+    // Big endian, like the camera's own RGB565 frames
+    let mut rgb565 = vec![0u8; width * height * 2];
+    let decoded = unsafe {
+        esp_idf_sys::camera::jpg2rgb565(
+            data.as_ptr(),
+            data.len().try_into().unwrap(),
+            rgb565.as_mut_ptr(),
+            scale,
+        )
+    };
+    if !decoded {
+        bail!("Error decoding JPEG frame");
+    }
+    Grid::from_frame(&rgb565, Layout::Rgb565, width, height)
+        .ok_or_else(|| anyhow::anyhow!("Frame too small to detect motion in"))
+}
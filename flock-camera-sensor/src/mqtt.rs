@@ -0,0 +1,164 @@
+use embedded_svc::mqtt::client::{utils, Message};
+use embedded_svc::mqtt::client::{Client, Connection, Event, MessageId, MessageImpl, Publish, QoS};
+use esp_idf_hal::mutex::Condvar;
+use esp_idf_svc::mqtt::client::EspMqttClient;
+use esp_idf_sys::EspError;
+use flock_api::transport::{Incoming, TransportReceiver, TransportSender};
+use log::*;
+use std::collections::VecDeque;
+use std::sync::{self, Arc, Mutex};
+use std::time::Duration;
+
+/// How long `send_confirmed` waits for the broker's PUBACK. The receiver thread only sees it in
+/// between instructions, so this covers a burst being taken.
+const PUBACK_TIMEOUT: Duration = Duration::from_secs(30);
+// PUBACKs nobody waits for (e.g. for retained-message clears) are forgotten after this many
+const MAX_PUBACKS: usize = 16;
+
+/// Message ids the broker acknowledged. They arrive on the connection, so the receiver records
+/// them for the sender.
+#[derive(Clone, Default)]
+pub struct Pubacks(Arc<(Mutex<VecDeque<MessageId>>, sync::Condvar)>);
+
+impl Pubacks {
+    fn record(&self, id: MessageId) {
+        let (ids, cvar) = &*self.0;
+        let mut ids = ids.lock().unwrap();
+        if ids.len() == MAX_PUBACKS {
+            ids.pop_front();
+        }
+        ids.push_back(id);
+        cvar.notify_all();
+    }
+
+    fn wait(&self, id: MessageId, timeout: Duration) -> bool {
+        let (ids, cvar) = &*self.0;
+        let (mut ids, _) = cvar
+            .wait_timeout_while(ids.lock().unwrap(), timeout, |ids| !ids.contains(&id))
+            .unwrap();
+        match ids.iter().position(|i| *i == id) {
+            Some(pos) => {
+                ids.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Publishes messages on the topic named by their recipient.
+pub struct MqttSender {
+    client: EspMqttClient<utils::ConnState<MessageImpl, EspError>>,
+    topic: String,
+    pubacks: Pubacks,
+}
+
+impl MqttSender {
+    pub fn new(
+        client: EspMqttClient<utils::ConnState<MessageImpl, EspError>>,
+        topic: String,
+        pubacks: Pubacks,
+    ) -> Self {
+        Self {
+            client,
+            topic,
+            pubacks,
+        }
+    }
+}
+
+impl TransportSender for MqttSender {
+    type Error = EspError;
+
+    fn address(&self) -> &str {
+        &self.topic
+    }
+
+    fn subscribe(&mut self, address: &str) -> Result<(), Self::Error> {
+        info!("Subscribing to topic {}", address);
+        self.client.subscribe(address, QoS::AtMostOnce)?;
+        Ok(())
+    }
+
+    fn send(&mut self, msg: &flock_api::Message) -> Result<(), Self::Error> {
+        let payload = flock_api::codec::encode(msg).unwrap();
+        self.client.publish(
+            msg.recipient.as_str(),
+            QoS::AtMostOnce,
+            false,
+            payload.as_slice(),
+        )?;
+        Ok(())
+    }
+
+    /// Publishes with QoS 1 and waits for the broker's PUBACK.
+    fn send_confirmed(&mut self, msg: &flock_api::Message) -> Result<(), Self::Error> {
+        let payload = flock_api::codec::encode(msg).unwrap();
+        let id = self.client.publish(
+            msg.recipient.as_str(),
+            QoS::AtLeastOnce,
+            false,
+            payload.as_slice(),
+        )?;
+        if !self.pubacks.wait(id, PUBACK_TIMEOUT) {
+            warn!("No PUBACK for message {} on topic {}", id, msg.recipient);
+            return Err(EspError::from(esp_idf_sys::ESP_ERR_TIMEOUT as i32).unwrap());
+        }
+        Ok(())
+    }
+
+    /// Instructions are retained by the broker, so they have to be cleared once handled or they
+    /// would be replayed on every wake or reconnect.
+    fn acknowledge(&mut self) -> Result<(), Self::Error> {
+        info!("Clearing retained message on topic {}", &self.topic);
+        self.client
+            .publish(self.topic.as_str(), QoS::AtLeastOnce, true, &[])?;
+        Ok(())
+    }
+}
+
+pub struct MqttReceiver {
+    connection: utils::Connection<Condvar, MessageImpl, EspError>,
+    pubacks: Pubacks,
+}
+
+impl MqttReceiver {
+    pub fn new(connection: utils::Connection<Condvar, MessageImpl, EspError>, pubacks: Pubacks) -> Self {
+        Self {
+            connection,
+            pubacks,
+        }
+    }
+}
+
+impl TransportReceiver for MqttReceiver {
+    type Error = EspError;
+
+    fn receive(&mut self) -> Option<Result<Incoming, Self::Error>> {
+        loop {
+            let evt = match self.connection.next()? {
+                Ok(evt) => evt,
+                Err(err) => return Some(Err(err)),
+            };
+            info!("MQTT Message received: {:?}", evt);
+            return Some(Ok(match evt {
+                Event::Connected(_) => Incoming::Connected,
+                Event::Published(id) => {
+                    self.pubacks.record(id);
+                    continue;
+                }
+                // An empty payload is the broker echoing back our own retained-message clear
+                Event::Received(m) if m.data().is_empty() => continue,
+                // All a node receives are instructions, anything bigger is hostile
+                Event::Received(m) => match flock_api::codec::decode_with_limit(
+                    m.data(),
+                    flock_api::codec::MAX_INSTRUCTION_LEN,
+                ) {
+                    Ok(msg) => Incoming::Message(msg),
+                    Err(err) => Incoming::Malformed(format!("{:?}", err)),
+                },
+                _ => continue,
+            }));
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use flock_api::PowerMode;
+use log::*;
+use std::time::Duration;
+
+/// How long a node stays awake after connecting before going back to sleep. This gives the
+/// broker time to deliver retained instructions and the publisher time to flush the reading.
+pub const WAKE_WINDOW: Duration = Duration::from_secs(5);
+
+// Kept in RTC slow memory so the interval survives deep sleep. A cold boot resets it to zero,
+// which means the node always comes up in `PowerMode::AlwaysOn` after a power cycle.
+#[link_section = ".rtc.data"]
+static mut SLEEP_INTERVAL_SECS: u32 = 0;
+
+// Milliseconds counted before the current wake, including the time spent asleep. Only written
+// right before going to sleep.
+#[link_section = ".rtc.data"]
+static mut EARLIER_UPTIME_MS: u64 = 0;
+
+pub fn power_mode() -> PowerMode {
+    match unsafe { SLEEP_INTERVAL_SECS } {
+        0 => PowerMode::AlwaysOn,
+        sleep_interval_secs => PowerMode::LowPower { sleep_interval_secs },
+    }
+}
+
+pub fn set_power_mode(mode: &PowerMode) -> anyhow::Result<()> {
+    info!("setting power mode: {:?}", mode);
+    let secs = match mode {
+        PowerMode::AlwaysOn => 0,
+        // 0 is how always-on is stored
+        PowerMode::LowPower {
+            sleep_interval_secs: 0,
+        } => anyhow::bail!("Low-power mode needs a sleep interval of at least 1 second"),
+        PowerMode::LowPower { sleep_interval_secs } => *sleep_interval_secs,
+    };
+    unsafe { SLEEP_INTERVAL_SECS = secs };
+    Ok(())
+}
+
+pub fn is_low_power() -> bool {
+    matches!(power_mode(), PowerMode::LowPower { .. })
+}
+
+pub fn woke_from_timer() -> bool {
+    let cause = unsafe { esp_idf_sys::esp_sleep_get_wakeup_cause() };
+    cause == esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER
+}
+
+/// Milliseconds since the node was powered on, deep sleep included. Unlike the wall clock, this
+/// never jumps back. Starts over after a power cycle, along with everything else in RTC memory.
+pub fn uptime_ms() -> u64 {
+    // Microseconds since this wake, the timer restarts with the chip
+    let awake_us = unsafe { esp_idf_sys::esp_timer_get_time() } as u64;
+    unsafe { EARLIER_UPTIME_MS + awake_us / 1000 }
+}
+
+/// Shuts down the radio and enters deep sleep. The chip resets on wake, so this never returns.
+pub fn deep_sleep(interval: Duration) -> ! {
+    info!("Entering deep sleep for {:?}", interval);
+    unsafe {
+        EARLIER_UPTIME_MS = uptime_ms() + interval.as_millis() as u64;
+        esp_idf_sys::esp_wifi_stop();
+        esp_idf_sys::esp_sleep_enable_timer_wakeup(interval.as_micros() as u64);
+        esp_idf_sys::esp_deep_sleep_start()
+    }
+}
@@ -0,0 +1,146 @@
+use crate::camera::{Camera, FrameBuffer};
+use anyhow::bail;
+use flock_api::{FrameEncoding, FrameFormat, Roi, RoiStatus};
+use log::*;
+
+// Kept in RTC slow memory like the power mode, so a low-power node keeps its region across deep
+// sleep. The sensor itself is reset on wake, `restore` windows it again.
+#[link_section = ".rtc.data"]
+static mut STATUS: Option<RoiStatus> = None;
+
+/// The region, if frames have to be cropped in software.
+pub fn software_roi() -> Option<Roi> {
+    match unsafe { &STATUS } {
+        Some(RoiStatus {
+            roi, windowed: false, ..
+        }) => *roi,
+        _ => None,
+    }
+}
+
+/// Copies a frame out in `encoding`, cropped to the region if the sensor isn't windowed.
+pub fn encode(fb: &FrameBuffer, encoding: FrameEncoding) -> anyhow::Result<(Vec<u8>, FrameFormat)> {
+    match software_roi() {
+        Some(roi) => fb.encode_region(&roi, encoding),
+        None => fb
+            .encode(encoding)
+            .ok_or_else(|| anyhow::anyhow!("Error encoding frame as JPEG")),
+    }
+}
+
+pub fn status(cam: &Camera) -> RoiStatus {
+    if let Some(status) = unsafe { &STATUS } {
+        return status.clone();
+    }
+    let (width, height) = cam.sensor().status().frame_size.dimensions();
+    RoiStatus {
+        roi: None,
+        windowed: false,
+        width,
+        height,
+    }
+}
+
+pub fn set_roi(cam: &Camera, roi: Option<Roi>) -> anyhow::Result<RoiStatus> {
+    info!("setting region of interest: {:?}", roi);
+    let sensor = cam.sensor();
+    let frame_size = sensor.status().frame_size;
+    let (width, height) = frame_size.dimensions();
+    if let Some(roi) = &roi {
+        flock_api::frame::check_roi(roi, width, height)?;
+    }
+
+    // Back to the whole frame first, so a failed change leaves whole frames
+    if let Some(RoiStatus { windowed: true, .. }) = unsafe { &STATUS } {
+        sensor.set_frame_size(frame_size)?;
+    }
+    unsafe { STATUS = None };
+    let roi = match roi {
+        Some(roi) => roi,
+        None => return Ok(status(cam)),
+    };
+
+    let status = match window(cam, &roi, width, height) {
+        Ok((width, height)) => RoiStatus {
+            roi: Some(roi),
+            windowed: true,
+            width,
+            height,
+        },
+        Err(err) => {
+            // Better to refuse now than to fail every capture from here on
+            let pixel_format = flock_api::PixelFormat::from(sensor.pix_format());
+            if !flock_api::frame::can_crop(pixel_format) {
+                bail!(
+                    "Sensor can't window ({:?}) and {:?} frames can't be cropped",
+                    err,
+                    pixel_format
+                );
+            }
+            info!("Cropping in software, sensor can't window: {:?}", err);
+            let (width, height) = roi.output_size();
+            RoiStatus {
+                roi: Some(roi),
+                windowed: false,
+                width,
+                height,
+            }
+        }
+    };
+    unsafe { STATUS = Some(status.clone()) };
+    Ok(status)
+}
+
+/// Applies the region again, to a camera freshly initialized after deep sleep or reconfigured.
+/// If that fails, frames are whole again.
+pub fn restore(cam: &Camera) {
+    let roi = unsafe { STATUS.as_ref().and_then(|s| s.roi) };
+    if roi.is_some() {
+        if let Err(err) = set_roi(cam, roi) {
+            error!("Error restoring region of interest: {:?}", err);
+        }
+    }
+}
+
+/// Has the sensor output just `roi` and returns the output size. Only OV2640 is supported, since
+/// what `set_res_raw` takes differs per sensor. esp32-camera runs the OV2640 in CIF mode
+/// (400x296) for frame sizes up to CIF, in SVGA mode (800x600) up to SVGA and in UXGA mode
+/// (1600x1200) above, and scales the mode's window down to the frame size. For the OV2640,
+/// `set_res_raw` takes the mode, then the window in the mode's pixels and the output size.
+fn window(cam: &Camera, roi: &Roi, width: u32, height: u32) -> anyhow::Result<(u32, u32)> {
+    let sensor = cam.sensor();
+    if sensor.model().as_deref() != Some("OV2640") {
+        bail!("Windowing isn't supported on this sensor");
+    }
+    // ov2640_sensor_mode_t
+    let (mode, mode_width, mode_height) = if width <= 400 {
+        (2, 400, 296)
+    } else if width <= 800 {
+        (1, 800, 600)
+    } else {
+        (0, 1600, 1200)
+    };
+    let x = |v: u32| (v * mode_width / width) as i32;
+    let y = |v: u32| (v * mode_height / height) as i32;
+    // The DSP outputs multiples of 4 pixels
+    let (out_width, out_height) = roi.output_size();
+    let (out_width, out_height) = (out_width / 4 * 4, out_height / 4 * 4);
+    if out_width == 0 || out_height == 0 {
+        bail!("Output too small for the sensor");
+    }
+    sensor.set_res_raw(
+        mode,
+        0,
+        0,
+        0,
+        x(roi.x),
+        y(roi.y),
+        x(roi.width),
+        y(roi.height),
+        out_width as i32,
+        out_height as i32,
+        false,
+        false,
+    )?;
+    Ok((out_width, out_height))
+}
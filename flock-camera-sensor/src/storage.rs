@@ -0,0 +1,169 @@
+use anyhow::{bail, Context};
+use esp_idf_sys::esp;
+use flock_api::{FrameFormat, StoredCapture};
+use log::*;
+use std::ffi::CString;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MOUNT_POINT: &str = "/sdcard";
+const CAPTURE_DIR: &str = "/sdcard/flock";
+const INDEX_PATH: &str = "/sdcard/flock/index.json";
+// Ids are never reused, even once every capture has been deleted, so the controller can't mix
+// up a new capture with one it saw earlier
+const NEXT_ID_PATH: &str = "/sdcard/flock/next_id";
+
+/// Once this many captures are stored, the oldest uploaded ones are removed to make room.
+/// Captures that haven't been uploaded yet are never removed automatically.
+const MAX_CAPTURES: usize = 500;
+
+/// The SD card runs in 1-bit mode (CLK, CMD, D0), which leaves GPIO4 (D1 on the AI-Thinker
+/// board, shared with the flash LED) free for an actuator.
+pub const PINS: [i32; 3] = [14, 15, 2];
+
+/// Captures written to the SD card, with an index of what has been uploaded.
+pub struct Storage {
+    index: Vec<StoredCapture>,
+    next_id: u32,
+}
+
+impl Storage {
+    pub fn mount() -> anyhow::Result<Self> {
+        let host = esp_idf_sys::sdmmc_host_t {
+            flags: esp_idf_sys::SDMMC_HOST_FLAG_1BIT
+                | esp_idf_sys::SDMMC_HOST_FLAG_4BIT
+                | esp_idf_sys::SDMMC_HOST_FLAG_8BIT
+                | esp_idf_sys::SDMMC_HOST_FLAG_DDR,
+            slot: esp_idf_sys::SDMMC_HOST_SLOT_1 as i32,
+            max_freq_khz: esp_idf_sys::SDMMC_FREQ_DEFAULT as i32,
+            io_voltage: 3.3,
+            init: Some(esp_idf_sys::sdmmc_host_init),
+            set_bus_width: Some(esp_idf_sys::sdmmc_host_set_bus_width),
+            get_bus_width: Some(esp_idf_sys::sdmmc_host_get_slot_width),
+            set_bus_ddr_mode: Some(esp_idf_sys::sdmmc_host_set_bus_ddr_mode),
+            set_card_clk: Some(esp_idf_sys::sdmmc_host_set_card_clk),
+            do_transaction: Some(esp_idf_sys::sdmmc_host_do_transaction),
+            __bindgen_anon_1: esp_idf_sys::sdmmc_host_t__bindgen_ty_1 {
+                deinit: Some(esp_idf_sys::sdmmc_host_deinit),
+            },
+            io_int_enable: Some(esp_idf_sys::sdmmc_host_io_int_enable),
+            io_int_wait: Some(esp_idf_sys::sdmmc_host_io_int_wait),
+            command_timeout_ms: 0,
+        };
+        let slot = esp_idf_sys::sdmmc_slot_config_t {
+            __bindgen_anon_1: esp_idf_sys::sdmmc_slot_config_t__bindgen_ty_1 { gpio_cd: -1 },
+            __bindgen_anon_2: esp_idf_sys::sdmmc_slot_config_t__bindgen_ty_2 { gpio_wp: -1 },
+            width: 1,
+            flags: esp_idf_sys::SDMMC_SLOT_FLAG_INTERNAL_PULLUP,
+        };
+        let mount_config = esp_idf_sys::esp_vfs_fat_sdmmc_mount_config_t {
+            format_if_mount_failed: false,
+            max_files: 4,
+            allocation_unit_size: 16 * 1024,
+        };
+        let mount_point = CString::new(MOUNT_POINT).unwrap();
+        let mut card: *mut esp_idf_sys::sdmmc_card_t = std::ptr::null_mut();
+        esp!(unsafe {
+            esp_idf_sys::esp_vfs_fat_sdmmc_mount(
+                mount_point.as_ptr(),
+                &host,
+                &slot as *const _ as *const std::ffi::c_void,
+                &mount_config,
+                &mut card,
+            )
+        })
+        .with_context(|| "Error mounting SD card")?;
+
+        fs::create_dir_all(CAPTURE_DIR)?;
+        let index: Vec<StoredCapture> = match fs::read(INDEX_PATH) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|err| {
+                error!("Discarding unreadable capture index: {:?}", err);
+                vec![]
+            }),
+            Err(_) => vec![],
+        };
+        // The index wins if the counter is missing or behind, e.g. on cards written before it
+        let next_id = fs::read_to_string(NEXT_ID_PATH)
+            .ok()
+            .and_then(|id| id.trim().parse().ok())
+            .unwrap_or(1)
+            .max(index.last().map_or(1, |c| c.id + 1));
+        info!("SD card mounted, {} captures stored", index.len());
+        Ok(Self { index, next_id })
+    }
+
+    pub fn save(&mut self, data: &[u8], format: FrameFormat) -> anyhow::Result<StoredCapture> {
+        self.evict()?;
+        let capture = StoredCapture {
+            id: self.next_id,
+            captured_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            size: data.len().try_into()?,
+            uploaded: false,
+            format,
+        };
+        self.next_id += 1;
+        fs::write(NEXT_ID_PATH, self.next_id.to_string())?;
+        fs::write(capture_path(capture.id), data)?;
+        self.index.push(capture.clone());
+        self.write_index()?;
+        Ok(capture)
+    }
+
+    pub fn list(&self) -> Vec<StoredCapture> {
+        self.index.clone()
+    }
+
+    /// Captures that haven't been uploaded yet, oldest first.
+    pub fn backlog(&self) -> Vec<StoredCapture> {
+        self.index.iter().filter(|c| !c.uploaded).cloned().collect()
+    }
+
+    pub fn get(&self, id: u32) -> anyhow::Result<StoredCapture> {
+        match self.index.iter().find(|c| c.id == id) {
+            Some(c) => Ok(c.clone()),
+            None => bail!("No stored capture with id {}", id),
+        }
+    }
+
+    pub fn read(&self, id: u32) -> anyhow::Result<Vec<u8>> {
+        self.get(id)?;
+        Ok(fs::read(capture_path(id))?)
+    }
+
+    pub fn delete(&mut self, id: u32) -> anyhow::Result<()> {
+        self.get(id)?;
+        fs::remove_file(capture_path(id))?;
+        self.index.retain(|c| c.id != id);
+        self.write_index()
+    }
+
+    pub fn mark_uploaded(&mut self, id: u32) -> anyhow::Result<()> {
+        if let Some(c) = self.index.iter_mut().find(|c| c.id == id) {
+            c.uploaded = true;
+        }
+        self.write_index()
+    }
+
+    fn evict(&mut self) -> anyhow::Result<()> {
+        while self.index.len() >= MAX_CAPTURES {
+            let oldest = match self.index.iter().find(|c| c.uploaded) {
+                Some(c) => c.id,
+                None => bail!("SD card is full of captures that haven't been uploaded"),
+            };
+            self.delete(oldest)?;
+        }
+        Ok(())
+    }
+
+    fn write_index(&self) -> anyhow::Result<()> {
+        fs::write(INDEX_PATH, serde_json::to_vec(&self.index)?)?;
+        Ok(())
+    }
+}
+
+fn capture_path(id: u32) -> PathBuf {
+    PathBuf::from(CAPTURE_DIR).join(format!("{:08}.bin", id))
+}
@@ -0,0 +1,90 @@
+use esp_idf_sys::{esp, EspError};
+use std::ffi::CString;
+use std::io::{self, Read, Write};
+
+const RX_BUF_SIZE: i32 = 4096;
+
+/// Byte stream over one of the ESP32's UARTs, for the framed serial transport.
+///
+/// Reads and writes go through the IDF driver, which is safe to use from two tasks at once, so
+/// the port can be split into a reading and a writing handle with `try_clone`.
+pub struct Uart {
+    port: esp_idf_sys::uart_port_t,
+}
+
+impl Uart {
+    pub fn install(port: esp_idf_sys::uart_port_t, baud_rate: i32) -> Result<Self, EspError> {
+        let config = esp_idf_sys::uart_config_t {
+            baud_rate,
+            data_bits: esp_idf_sys::uart_word_length_t_UART_DATA_8_BITS,
+            parity: esp_idf_sys::uart_parity_t_UART_PARITY_DISABLE,
+            stop_bits: esp_idf_sys::uart_stop_bits_t_UART_STOP_BITS_1,
+            flow_ctrl: esp_idf_sys::uart_hw_flowcontrol_t_UART_HW_FLOWCTRL_DISABLE,
+            ..Default::default()
+        };
+        esp!(unsafe { esp_idf_sys::uart_param_config(port, &config) })?;
+        esp!(unsafe {
+            esp_idf_sys::uart_driver_install(port, RX_BUF_SIZE, 0, 0, std::ptr::null_mut(), 0)
+        })?;
+        Ok(Self { port })
+    }
+
+    pub fn try_clone(&self) -> Self {
+        Self { port: self.port }
+    }
+}
+
+/// Log output on the same UART would corrupt frames, so this turns it off entirely.
+pub fn silence_logs() {
+    let all = CString::new("*").unwrap();
+    unsafe { esp_idf_sys::esp_log_level_set(all.as_ptr(), esp_idf_sys::esp_log_level_t_ESP_LOG_NONE) };
+    log::set_max_level(log::LevelFilter::Off);
+}
+
+impl Read for Uart {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // Block for the first byte, then take whatever else is already buffered. A plain
+        // uart_read_bytes would wait until the whole buffer is full.
+        let n = unsafe {
+            esp_idf_sys::uart_read_bytes(
+                self.port,
+                buf.as_mut_ptr() as *mut _,
+                1,
+                esp_idf_sys::portMAX_DELAY,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "uart_read_bytes failed"));
+        }
+        let mut buffered: esp_idf_sys::size_t = 0;
+        unsafe { esp_idf_sys::uart_get_buffered_data_len(self.port, &mut buffered) };
+        let more = (buffered as usize).min(buf.len() - 1);
+        if more == 0 {
+            return Ok(n as usize);
+        }
+        let m = unsafe {
+            esp_idf_sys::uart_read_bytes(self.port, buf[1..].as_mut_ptr() as *mut _, more as u32, 0)
+        };
+        Ok(n as usize + m.max(0) as usize)
+    }
+}
+
+impl Write for Uart {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = unsafe {
+            esp_idf_sys::uart_write_bytes(self.port, buf.as_ptr() as *const _, buf.len() as _)
+        };
+        if n < 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "uart_write_bytes failed"));
+        }
+        Ok(n as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        esp!(unsafe { esp_idf_sys::uart_wait_tx_done(self.port, esp_idf_sys::portMAX_DELAY) })
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+}
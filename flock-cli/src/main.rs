@@ -0,0 +1,1193 @@
+use anyhow::{anyhow, bail, Context};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use flock_api::{
+    Burst, FrameEncoding, Instruction, Message, Payload, PixelFormat, SensorConfig, SensorData,
+    ShotSettings,
+};
+use flock_client::clip::{Clip, ClipFormat, ClipOptions};
+use flock_client::motion::{Motion, MotionConfig, MotionEvent, Region};
+use flock_client::{Client, ClientOptions};
+use flock_imaging::image;
+use flock_imaging::metadata::{self, CaptureMetadata};
+use flock_imaging::timelapse::{self, TimelapseFormat, TimelapseOptions, TimelapseWriter};
+use flock_store::{Query, Retention, Store};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+/// Scriptable access to flock camera nodes.
+#[derive(Parser, Debug)]
+#[command(name = "flock", version)]
+struct Cli {
+    /// MQTT broker host
+    #[arg(long, env = "FLOCK_MQTT_HOST", default_value = "localhost")]
+    host: String,
+    #[arg(long, env = "FLOCK_MQTT_PORT", default_value_t = 1883)]
+    port: u16,
+    /// Topic the nodes publish on, the firmware's FLOCK_CONTROLLER_TOPIC
+    #[arg(long, env = "FLOCK_CONTROLLER_TOPIC", default_value = "flock-controller")]
+    controller_topic: String,
+    /// Client ID to connect to the broker with
+    #[arg(long, env = "FLOCK_CLI_CLIENT_ID", default_value = "flock-cli")]
+    client_id: String,
+    /// Seconds to wait for a node to answer
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+    #[arg(long, value_enum, default_value_t = Format::Human)]
+    format: Format,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum Format {
+    Human,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Ask every node to answer and list the ones that do while listening
+    List {
+        /// Seconds to listen for. Low-power nodes only answer once they wake.
+        #[arg(long, default_value_t = 5)]
+        listen: u64,
+    },
+    /// Read the sensor and write the frame to a file, as an image if the extension is one (e.g.
+    /// .png or .jpg) and raw otherwise
+    Capture {
+        device: String,
+        file: PathBuf,
+        /// Have the node encode this frame as JPEG with the given quality (1-100)
+        #[arg(long, value_name = "QUALITY", value_parser = clap::value_parser!(u8).range(1..=100))]
+        jpeg: Option<u8>,
+    },
+    /// Take frames back to back and write them to a directory as PNGs, optionally stepping the
+    /// AE level between shots and merging them into one tone-mapped image
+    Burst {
+        device: String,
+        dir: PathBuf,
+        /// Number of shots, one per AE level by default
+        #[arg(long)]
+        count: Option<u8>,
+        /// Milliseconds from one shot to the next
+        #[arg(long, default_value_t = 0)]
+        interval: u32,
+        /// AE level (-2 to 2) of each shot, e.g. -2,0,2
+        #[arg(long, value_delimiter = ',', allow_hyphen_values = true)]
+        ae_levels: Vec<i8>,
+        /// Have the node encode the shots as JPEG with the given quality (1-100)
+        #[arg(long, value_name = "QUALITY", value_parser = clap::value_parser!(u8).range(1..=100))]
+        jpeg: Option<u8>,
+        /// Also merge the shots with exposure fusion and save the result here
+        #[arg(long, value_name = "FILE")]
+        hdr: Option<PathBuf>,
+    },
+    /// Read the sensor over and over and print when motion starts and ends, until interrupted
+    Motion {
+        device: String,
+        /// Milliseconds between readings
+        #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+        /// From 0 to 1, how small a change in brightness counts
+        #[arg(long, default_value_t = MotionConfig::default().sensitivity)]
+        sensitivity: f32,
+        /// Fraction of the watched area that has to change
+        #[arg(long, default_value_t = MotionConfig::default().min_area)]
+        min_area: f32,
+        /// Part of the frame to ignore as x,y,width,height in fractions of the frame, e.g.
+        /// 0,0,0.5,0.25 for the top left. Can be given more than once.
+        #[arg(long, value_parser = parse_region)]
+        mask: Vec<Region>,
+        /// Seconds without motion before it ends
+        #[arg(long, default_value_t = MotionConfig::default().cooldown.as_secs())]
+        cooldown: u64,
+    },
+    /// Record clips from a few seconds before to a few seconds after each event into a
+    /// directory, until interrupted. Events are motion the node detects itself, motion detected
+    /// here with --detect-motion, and every line read from stdin, which is used as the reason.
+    Clips {
+        device: String,
+        dir: PathBuf,
+        /// Seconds before the event to include
+        #[arg(long, default_value_t = 5)]
+        before: u64,
+        /// Seconds after the event to include
+        #[arg(long, default_value_t = 5)]
+        after: u64,
+        /// Milliseconds between readings, 0 to only use frames the node sends anyway
+        #[arg(long, default_value_t = 500)]
+        interval: u64,
+        /// Write numbered JPEGs instead of an AVI
+        #[arg(long)]
+        sequence: bool,
+        /// Also detect motion in the frames here, with the defaults of `flock motion`
+        #[arg(long)]
+        detect_motion: bool,
+    },
+    /// Build a time-lapse from the captures on a node's SD card, or in a library, as an MJPEG
+    /// AVI or, if the file ends in .gif, an animated GIF
+    Timelapse {
+        device: String,
+        #[command(flatten)]
+        args: TimelapseArgs,
+        /// Take the captures from this library instead of the node. Works offline.
+        #[arg(long, value_name = "DIR")]
+        library: Option<PathBuf>,
+    },
+    /// Keep frames in a library, a directory of images with a searchable index. Only
+    /// `record` needs the broker.
+    Library {
+        #[arg(env = "FLOCK_LIBRARY")]
+        dir: PathBuf,
+        #[command(subcommand)]
+        action: LibraryCommand,
+    },
+    /// Print the sensor config
+    GetConfig { device: String },
+    /// Write the sensor config from a JSON or TOML file (by extension)
+    SetConfig { device: String, file: PathBuf },
+    /// Print every message the nodes publish until interrupted
+    Watch {
+        /// Only show messages from this node
+        #[arg(long)]
+        device: Option<String>,
+    },
+    /// Print which node took a saved JPEG or PNG, when and with which settings. Works offline.
+    Metadata { file: PathBuf },
+    /// Print the JSON Schema of messages, or an AsyncAPI document of the protocol. Works offline.
+    Schema {
+        #[arg(long)]
+        asyncapi: bool,
+    },
+    /// Send an instruction given as JSON, e.g. '"readPowerMode"'
+    Send {
+        device: String,
+        instruction: String,
+        /// Wait for the node's next message and print it
+        #[arg(long)]
+        wait: bool,
+    },
+}
+
+#[derive(Args, Debug)]
+struct TimelapseArgs {
+    file: PathBuf,
+    /// Only captures taken from then on, in Unix seconds, e.g. $(date -d yesterday +%s)
+    #[arg(long)]
+    from: Option<u64>,
+    /// Only captures taken until then, in Unix seconds
+    #[arg(long)]
+    to: Option<u64>,
+    /// Captures shown per second
+    #[arg(long, default_value_t = 10.0)]
+    fps: f32,
+    /// Even out jumps in brightness between captures
+    #[arg(long)]
+    deflicker: bool,
+    /// Stamp each frame with its capture time, in UTC
+    #[arg(long)]
+    timestamps: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum LibraryCommand {
+    /// Store every frame the nodes publish, until interrupted
+    Record {
+        /// Only store frames from this node
+        #[arg(long)]
+        device: Option<String>,
+        /// Tag every frame stored, can be given more than once
+        #[arg(long)]
+        tag: Vec<String>,
+        /// Also read the node's sensor every this many milliseconds
+        #[arg(long, requires = "device", value_parser = clap::value_parser!(u64).range(1..))]
+        interval: Option<u64>,
+    },
+    /// List captures, oldest first
+    List {
+        #[arg(long)]
+        device: Option<String>,
+        /// Only captures taken from then on, in Unix seconds
+        #[arg(long)]
+        from: Option<u64>,
+        /// Only captures taken until then, in Unix seconds
+        #[arg(long)]
+        to: Option<u64>,
+        #[arg(long)]
+        tag: Option<String>,
+        /// Newest first
+        #[arg(long)]
+        newest: bool,
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Tag a capture, or untag it
+    Tag {
+        id: i64,
+        tag: String,
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Remove the oldest captures until the limits given hold
+    Prune {
+        /// Remove captures older than this many days
+        #[arg(long)]
+        max_age_days: Option<u64>,
+        /// Captures to keep per node
+        #[arg(long)]
+        max_count: Option<usize>,
+        /// Megabytes all captures together may take up
+        #[arg(long)]
+        max_mb: Option<u64>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    // What the client library reports in the background (lost connections, undecodable
+    // messages) goes to stderr, so it doesn't mix with the output. RUST_LOG overrides the level.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    if let Command::Schema { asyncapi } = cli.command {
+        let doc = if asyncapi {
+            flock_api::schema::asyncapi(&cli.controller_topic)
+        } else {
+            flock_api::schema::message_schema()
+        };
+        return print_json(&doc);
+    }
+    // Libraries are on disk, only recording into one needs the broker
+    match &cli.command {
+        Command::Library { dir, action } if !matches!(action, LibraryCommand::Record { .. }) => {
+            return manage_library(dir, action, cli.format);
+        }
+        Command::Timelapse {
+            device,
+            args,
+            library: Some(dir),
+        } => return timelapse_from_library(dir, device, args, cli.format),
+        Command::Metadata { file } => return print_metadata(file, cli.format),
+        _ => {}
+    }
+
+    let mut options = ClientOptions::new(&cli.host, cli.port, &cli.client_id, &cli.controller_topic);
+    options.request_timeout = Duration::from_secs(cli.timeout);
+    let client = Client::connect(options)
+        .await
+        .with_context(|| format!("Error connecting to {}:{}", cli.host, cli.port))?;
+
+    match cli.command {
+        Command::List { listen } => {
+            let devices = client.discover(Duration::from_secs(listen)).await?;
+            match cli.format {
+                Format::Json => print_json(&devices)?,
+                Format::Human => devices.iter().for_each(|d| println!("{}", d)),
+            }
+        }
+        Command::Capture { device, file, jpeg } => {
+            let captured_at = unix_millis(SystemTime::now());
+            let reading = match jpeg {
+                Some(quality) => {
+                    client
+                        .read_sensor_encoded(&device, FrameEncoding::Jpeg { quality })
+                        .await?
+                }
+                None => client.read_sensor(&device).await?,
+            };
+            let SensorData::Camera {
+                frame_buffer,
+                format,
+            } = &reading;
+            if image::ImageFormat::from_path(&file).is_ok() {
+                let metadata = capture_metadata(&client, &device, captured_at).await;
+                flock_imaging::decode_reading(&reading)
+                    .and_then(|img| metadata::save(&img, &file, &metadata))
+                    .with_context(|| format!("Error writing {}", file.display()))?;
+            } else {
+                fs::write(&file, frame_buffer)
+                    .with_context(|| format!("Error writing {}", file.display()))?;
+            }
+            match cli.format {
+                Format::Json => print_json(&serde_json::json!({
+                    "file": file,
+                    "size": frame_buffer.len(),
+                    "format": format,
+                }))?,
+                Format::Human => println!(
+                    "Wrote {}x{} {:?} frame ({} bytes) to {}",
+                    format.width,
+                    format.height,
+                    format.pixel_format,
+                    frame_buffer.len(),
+                    file.display()
+                ),
+            }
+        }
+        Command::Burst {
+            device,
+            dir,
+            count,
+            interval,
+            ae_levels,
+            jpeg,
+            hdr,
+        } => {
+            let burst = Burst {
+                count: shot_count(count, &ae_levels)?,
+                interval_ms: interval,
+                shots: ae_levels
+                    .iter()
+                    .map(|&level| ShotSettings {
+                        ae_level: Some(level),
+                        ..Default::default()
+                    })
+                    .collect(),
+                encoding: jpeg.map(|quality| FrameEncoding::Jpeg { quality }),
+            };
+            let started = unix_millis(SystemTime::now());
+            let shots = client.burst(&device, burst).await?;
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("Error creating {}", dir.display()))?;
+            let metadata = capture_metadata(&client, &device, started).await;
+            let mut images = vec![];
+            let mut files = vec![];
+            for shot in &shots {
+                let img = flock_imaging::decode_reading(&shot.reading)?;
+                let file = dir.join(format!("shot-{}.png", shot.index));
+                let metadata = CaptureMetadata {
+                    captured_at: started + u64::from(shot.offset_ms),
+                    ..metadata.clone()
+                };
+                metadata::save(&img, &file, &metadata)
+                    .with_context(|| format!("Error writing {}", file.display()))?;
+                images.push(img);
+                files.push(file);
+            }
+            let sharpest = flock_imaging::hdr::sharpest(&images);
+            if let Some(file) = &hdr {
+                // Saturation is 0 everywhere in grayscale frames, leave it out of the weights
+                let grayscale = shots.iter().any(|shot| {
+                    let SensorData::Camera { format, .. } = &shot.reading;
+                    format.pixel_format == PixelFormat::Grayscale
+                });
+                let weights = flock_imaging::hdr::FusionWeights {
+                    saturation: if grayscale { 0.0 } else { 1.0 },
+                    ..Default::default()
+                };
+                flock_imaging::hdr::fuse_with(&images, &weights)
+                    .and_then(|img| metadata::save(&img, file, &metadata))
+                    .with_context(|| format!("Error writing {}", file.display()))?;
+            }
+            match cli.format {
+                Format::Json => print_json(&serde_json::json!({
+                    "files": files,
+                    "offsetsMs": shots.iter().map(|s| s.offset_ms).collect::<Vec<_>>(),
+                    "sharpest": sharpest,
+                    "hdr": hdr,
+                }))?,
+                Format::Human => {
+                    for (shot, file) in shots.iter().zip(&files) {
+                        println!(
+                            "Shot {} at {} ms: {}",
+                            shot.index,
+                            shot.offset_ms,
+                            file.display()
+                        );
+                    }
+                    if let Some(i) = sharpest {
+                        println!("Sharpest: shot {}", i);
+                    }
+                    if let Some(file) = &hdr {
+                        println!("Merged into {}", file.display());
+                    }
+                }
+            }
+        }
+        Command::Motion {
+            device,
+            interval,
+            sensitivity,
+            min_area,
+            mask,
+            cooldown,
+        } => {
+            let watch = client.watch_motion(
+                &device,
+                MotionConfig {
+                    sensitivity,
+                    min_area,
+                    masks: mask,
+                    cooldown: Duration::from_secs(cooldown),
+                },
+            );
+            let mut events = watch.subscribe();
+            let mut ticks = tokio::time::interval(Duration::from_millis(interval));
+            loop {
+                tokio::select! {
+                    // The watch sees the reading whether or not we wait for it
+                    _ = ticks.tick() => client.send(&device, Instruction::ReadSensor).await?,
+                    event = events.recv() => match event {
+                        Ok(event) => print_motion(&event.motion, cli.format)?,
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(err) => return Err(err.into()),
+                    },
+                    _ = tokio::signal::ctrl_c() => break,
+                }
+            }
+        }
+        Command::Clips {
+            device,
+            dir,
+            before,
+            after,
+            interval,
+            sequence,
+            detect_motion,
+        } => {
+            let recorder = client.record_clips(ClipOptions {
+                before: Duration::from_secs(before),
+                after: Duration::from_secs(after),
+                format: if sequence {
+                    ClipFormat::JpegSequence
+                } else {
+                    ClipFormat::Avi
+                },
+                ..ClipOptions::new(dir)
+            });
+            let mut clips = recorder.subscribe();
+            // The recorder picks up the answers for the clips' sidecars
+            if let Err(err) = client.read_config(&device).await {
+                eprintln!("Clips go without the sensor config: {}", err);
+            }
+            if let Err(err) = client.read_sensor_info(&device).await {
+                eprintln!("Clips go without naming the sensor: {}", err);
+            }
+            let watch = detect_motion.then(|| client.watch_motion(&device, Default::default()));
+            let mut motion = watch.as_ref().map(|watch| watch.subscribe());
+            let mut lines = BufReader::new(tokio::io::stdin()).lines();
+            let mut stdin_open = true;
+            // Only polled when the interval isn't 0
+            let mut ticks = tokio::time::interval(Duration::from_millis(interval.max(1)));
+            loop {
+                tokio::select! {
+                    _ = ticks.tick(), if interval > 0 => {
+                        client.send(&device, Instruction::ReadSensor).await?
+                    }
+                    line = lines.next_line(), if stdin_open => match line? {
+                        Some(line) => recorder.trigger(&device, line.trim()),
+                        None => stdin_open = false,
+                    },
+                    Some(event) = next_motion(&mut motion) => {
+                        if let Motion::Started { .. } = event.motion {
+                            recorder.trigger(&device, "motion");
+                        }
+                    }
+                    clip = clips.recv() => match clip {
+                        Ok(clip) => print_clip(&clip, cli.format)?,
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(err) => return Err(err.into()),
+                    },
+                    _ = tokio::signal::ctrl_c() => break,
+                }
+            }
+        }
+        Command::Timelapse {
+            device,
+            args,
+            library: None,
+        } => {
+            let range = (
+                args.from.map_or(Bound::Unbounded, Bound::Included),
+                args.to.map_or(Bound::Unbounded, Bound::Included),
+            );
+            let captures = timelapse::select(&client.list_captures(&device).await?, range);
+            if captures.is_empty() {
+                bail!("{} has no captures in that time range", device);
+            }
+            let mut writer = args.writer()?;
+            // One at a time, SD card captures are big and the frames don't need to be kept
+            for (i, capture) in captures.iter().enumerate() {
+                if cli.format == Format::Human {
+                    eprint!("\rFetching capture {}/{}", i + 1, captures.len());
+                }
+                let stored = client.fetch_capture(&device, capture.id).await?;
+                let img = flock_imaging::decode_reading(&stored.reading)
+                    .with_context(|| format!("Error decoding capture {}", capture.id))?;
+                writer.push(&img, capture.captured_at)?;
+            }
+            if cli.format == Format::Human {
+                eprintln!();
+            }
+            let times = captures.iter().map(|capture| capture.captured_at);
+            args.finish(writer, times, cli.format)?;
+        }
+        Command::Timelapse { .. } => unreachable!("handled before connecting"),
+        Command::Library {
+            dir,
+            action:
+                LibraryCommand::Record {
+                    device,
+                    tag,
+                    interval,
+                },
+        } => {
+            let mut store =
+                Store::open(&dir).with_context(|| format!("Error opening {}", dir.display()))?;
+            let tags: Vec<&str> = tag.iter().map(String::as_str).collect();
+            let mut events = client.subscribe();
+            let (mut configs, mut sensors) = (HashMap::new(), HashMap::new());
+            // The answers come in through `events` like any other message
+            if let Some(device) = &device {
+                if let Err(err) = client.read_config(device).await {
+                    eprintln!("Frames go without the sensor config for now: {}", err);
+                }
+                if let Err(err) = client.read_sensor_info(device).await {
+                    eprintln!("Frames go without naming the sensor for now: {}", err);
+                }
+            }
+            let mut ticks = tokio::time::interval(Duration::from_millis(interval.unwrap_or(1)));
+            loop {
+                let msg = tokio::select! {
+                    _ = ticks.tick(), if interval.is_some() => {
+                        // Clap makes sure there's a device with an interval
+                        let device = device.as_deref().expect("interval requires device");
+                        client.send(device, Instruction::ReadSensor).await?;
+                        continue;
+                    }
+                    msg = events.recv() => msg,
+                    _ = tokio::signal::ctrl_c() => break,
+                };
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        eprintln!("Missed {} messages", n);
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                if device.as_ref().is_some_and(|d| *d != msg.client_id) {
+                    continue;
+                }
+                match &*msg.payload {
+                    Payload::SensorConfig(config) => {
+                        configs.insert(msg.client_id.clone(), config.clone());
+                    }
+                    Payload::SensorInfo(sensor) => {
+                        sensors.insert(msg.client_id.clone(), sensor.clone());
+                    }
+                    _ => {}
+                }
+                let Some(reading) = flock_client::motion::frame(&msg.payload) else {
+                    continue;
+                };
+                // Stored readings were taken when the node says, not when they arrive
+                let captured_at = match &*msg.payload {
+                    Payload::StoredReading(stored) => stored.capture.captured_at * 1000,
+                    _ => unix_millis(SystemTime::now()),
+                };
+                let metadata = CaptureMetadata {
+                    client_id: msg.client_id.clone(),
+                    captured_at,
+                    sensor: sensors.get(&msg.client_id).cloned(),
+                    sensor_config: configs.get(&msg.client_id).cloned(),
+                };
+                match store.add(&metadata, reading, &tags) {
+                    Ok(capture) => match cli.format {
+                        Format::Json => println!("{}", capture_json(&store, &capture)),
+                        Format::Human => print_capture(&store, &capture),
+                    },
+                    Err(err) => eprintln!("Error storing frame from {}: {}", msg.client_id, err),
+                }
+            }
+        }
+        Command::Library { .. } => unreachable!("handled before connecting"),
+        Command::GetConfig { device } => {
+            let config = client.read_config(&device).await?;
+            print_config(&config, cli.format)?;
+        }
+        Command::SetConfig { device, file } => {
+            let config = read_config_file(&file)?;
+            let config = client.write_config(&device, config).await?;
+            print_config(&config, cli.format)?;
+        }
+        Command::Watch { device } => {
+            let mut events = client.subscribe();
+            loop {
+                let msg = tokio::select! {
+                    msg = events.recv() => msg,
+                    _ = tokio::signal::ctrl_c() => break,
+                };
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        eprintln!("Skipped {} messages", n);
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                if device.as_ref().is_none_or(|d| *d == msg.client_id) {
+                    print_message(&msg, cli.format)?;
+                }
+            }
+        }
+        Command::Metadata { .. } | Command::Schema { .. } => {
+            unreachable!("handled before connecting")
+        }
+        Command::Send {
+            device,
+            instruction,
+            wait,
+        } => {
+            let instruction: Instruction =
+                serde_json::from_str(&instruction).context("Error parsing instruction")?;
+            if wait {
+                let payload = client.request(&device, instruction, Ok).await?;
+                print_message(&Message::new(device, cli.client_id, payload), cli.format)?;
+            } else {
+                client.send(&device, instruction).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_config_file(path: &Path) -> anyhow::Result<SensorConfig> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Error reading {}", path.display()))?;
+    parse_config(&text, path.extension().and_then(|e| e.to_str()))
+        .with_context(|| format!("Error parsing {}", path.display()))
+}
+
+fn parse_config(text: &str, extension: Option<&str>) -> anyhow::Result<SensorConfig> {
+    Ok(match extension {
+        Some("json") => serde_json::from_str(text)?,
+        Some("toml") => toml::from_str(text)?,
+        _ => bail!("Config files must end in .json or .toml"),
+    })
+}
+
+fn parse_region(text: &str) -> Result<Region, String> {
+    let values = text
+        .split(',')
+        .map(|v| v.trim().parse::<f32>().map_err(|err| err.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    match values[..] {
+        [x, y, width, height] => Ok(Region {
+            x,
+            y,
+            width,
+            height,
+        }),
+        _ => Err("expected x,y,width,height".into()),
+    }
+}
+
+fn print_motion(motion: &Motion, format: Format) -> anyhow::Result<()> {
+    let millis = |t: &SystemTime| {
+        t.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+    };
+    match (motion, format) {
+        (Motion::Started { at, area, bounds }, Format::Json) => {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "event": "started",
+                    "at": millis(at),
+                    "area": area,
+                    "bounds": [bounds.x, bounds.y, bounds.width, bounds.height],
+                })
+            )
+        }
+        (Motion::Ended { at, started }, Format::Json) => println!(
+            "{}",
+            serde_json::json!({"event": "ended", "at": millis(at), "started": millis(started)})
+        ),
+        (Motion::Started { area, bounds, .. }, Format::Human) => println!(
+            "Motion started: {:.1}% of the frame, around {:.2},{:.2} {:.2}x{:.2}",
+            area * 100.0,
+            bounds.x,
+            bounds.y,
+            bounds.width,
+            bounds.height
+        ),
+        (Motion::Ended { at, started }, Format::Human) => println!(
+            "Motion ended after {:.1}s",
+            at.duration_since(*started)
+                .unwrap_or_default()
+                .as_secs_f32()
+        ),
+    }
+    Ok(())
+}
+
+impl TimelapseArgs {
+    fn writer(&self) -> anyhow::Result<TimelapseWriter<std::io::BufWriter<fs::File>>> {
+        if !self.fps.is_finite() || self.fps <= 0.0 {
+            bail!("--fps has to be more than 0");
+        }
+        let gif = self
+            .file
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("gif"));
+        let out = fs::File::create(&self.file)
+            .with_context(|| format!("Error creating {}", self.file.display()))?;
+        Ok(TimelapseWriter::new(
+            std::io::BufWriter::new(out),
+            TimelapseOptions {
+                format: if gif {
+                    TimelapseFormat::Gif
+                } else {
+                    TimelapseFormat::Avi
+                },
+                frame_interval: Duration::from_secs_f32(1.0 / self.fps),
+                deflicker: self.deflicker,
+                timestamps: self.timestamps,
+                ..Default::default()
+            },
+        ))
+    }
+
+    /// Finishes the file and reports on it, `times` are the captures' in Unix seconds.
+    fn finish(
+        &self,
+        writer: TimelapseWriter<std::io::BufWriter<fs::File>>,
+        mut times: impl Iterator<Item = u64>,
+        format: Format,
+    ) -> anyhow::Result<()> {
+        let frames = writer
+            .finish()
+            .with_context(|| format!("Error writing {}", self.file.display()))?;
+        let from = times.next();
+        let to = times.last().or(from);
+        match format {
+            Format::Json => print_json(&serde_json::json!({
+                "file": self.file,
+                "frames": frames,
+                "from": from,
+                "to": to,
+            })),
+            Format::Human => {
+                println!("Wrote {} frames to {}", frames, self.file.display());
+                Ok(())
+            }
+        }
+    }
+}
+
+fn timelapse_from_library(
+    dir: &Path,
+    device: &str,
+    args: &TimelapseArgs,
+    format: Format,
+) -> anyhow::Result<()> {
+    let store = Store::open(dir).with_context(|| format!("Error opening {}", dir.display()))?;
+    let captures = store.query(&Query {
+        device: Some(device.into()),
+        from: args.from.map(|secs| secs * 1000),
+        to: args.to.map(|secs| secs * 1000 + 999),
+        ..Default::default()
+    })?;
+    if captures.is_empty() {
+        bail!("{} has no captures in that time range", device);
+    }
+    let mut writer = args.writer()?;
+    for capture in &captures {
+        let path = store.path(capture);
+        let img = image::open(&path)
+            .with_context(|| format!("Error reading {}", path.display()))?
+            .to_rgb8();
+        writer.push(&img, capture.captured_at / 1000)?;
+    }
+    let times = captures.iter().map(|capture| capture.captured_at / 1000);
+    args.finish(writer, times, format)
+}
+
+fn manage_library(dir: &Path, action: &LibraryCommand, format: Format) -> anyhow::Result<()> {
+    let mut store = Store::open(dir).with_context(|| format!("Error opening {}", dir.display()))?;
+    match action {
+        LibraryCommand::Record { .. } => unreachable!("needs the broker"),
+        LibraryCommand::List {
+            device,
+            from,
+            to,
+            tag,
+            newest,
+            limit,
+        } => {
+            let captures = store.query(&Query {
+                device: device.clone(),
+                from: from.map(|secs| secs * 1000),
+                to: to.map(|secs| secs * 1000 + 999),
+                tag: tag.clone(),
+                newest_first: *newest,
+                limit: *limit,
+            })?;
+            match format {
+                Format::Json => print_json(
+                    &captures
+                        .iter()
+                        .map(|capture| capture_json(&store, capture))
+                        .collect::<Vec<_>>(),
+                )?,
+                Format::Human => captures
+                    .iter()
+                    .for_each(|capture| print_capture(&store, capture)),
+            }
+        }
+        LibraryCommand::Tag { id, tag, remove } => {
+            if store.get(*id)?.is_none() {
+                bail!("There's no capture {} in {}", id, dir.display());
+            }
+            if *remove {
+                store.untag(*id, tag)?;
+            } else {
+                store.tag(*id, tag)?;
+            }
+        }
+        LibraryCommand::Prune {
+            max_age_days,
+            max_count,
+            max_mb,
+        } => {
+            let removed = store.prune(&Retention {
+                max_age: max_age_days.map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+                max_count: *max_count,
+                max_bytes: max_mb.map(|mb| mb * 1024 * 1024),
+            })?;
+            let bytes: u64 = removed.iter().map(|capture| capture.size).sum();
+            match format {
+                Format::Json => print_json(&serde_json::json!({
+                    "removed": removed.iter().map(|capture| capture.id).collect::<Vec<_>>(),
+                    "bytes": bytes,
+                }))?,
+                Format::Human => println!(
+                    "Removed {} captures, {:.1} MB",
+                    removed.len(),
+                    bytes as f64 / (1024.0 * 1024.0)
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn capture_json(store: &Store, capture: &flock_store::Capture) -> serde_json::Value {
+    serde_json::json!({
+        "id": capture.id,
+        "device": capture.device,
+        "capturedAt": capture.captured_at,
+        "file": store.path(capture),
+        "pixelFormat": capture.pixel_format,
+        "width": capture.width,
+        "height": capture.height,
+        "size": capture.size,
+        "sensorConfig": capture.sensor_config,
+        "tags": capture.tags,
+    })
+}
+
+fn print_capture(store: &Store, capture: &flock_store::Capture) {
+    let tags = if capture.tags.is_empty() {
+        String::new()
+    } else {
+        format!(" [{}]", capture.tags.join(", "))
+    };
+    println!(
+        "{} {} {} {}x{} {}{}",
+        capture.id,
+        capture.device,
+        timelapse::utc(capture.captured_at / 1000),
+        capture.width,
+        capture.height,
+        store.path(capture).display(),
+        tags
+    );
+}
+
+fn print_metadata(file: &Path, format: Format) -> anyhow::Result<()> {
+    let Some(metadata) =
+        metadata::read_file(file).with_context(|| format!("Error reading {}", file.display()))?
+    else {
+        bail!("{} has no capture metadata", file.display());
+    };
+    match format {
+        Format::Json => return print_json(&metadata),
+        Format::Human => {
+            println!("Client ID: {}", metadata.client_id);
+            println!(
+                "Captured: {}.{:03} UTC",
+                timelapse::utc(metadata.captured_at / 1000),
+                metadata.captured_at % 1000
+            );
+            if let Some(sensor) = &metadata.sensor {
+                println!(
+                    "Sensor: {} (manufacturer 0x{:04X}, product 0x{:04X}, version 0x{:02X})",
+                    sensor.model.as_deref().unwrap_or("unknown"),
+                    sensor.manufacturer_id,
+                    sensor.product_id,
+                    sensor.version
+                );
+            }
+            if let Some(config) = &metadata.sensor_config {
+                println!("Sensor config: {}", serde_json::to_string(config)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Metadata for frames `device` took at `captured_at`, with what the node says about its sensor.
+async fn capture_metadata(client: &Client, device: &str, captured_at: u64) -> CaptureMetadata {
+    // Older nodes don't know `ReadSensorInfo`, the frame matters more than its metadata
+    CaptureMetadata {
+        client_id: device.into(),
+        captured_at,
+        sensor: client.read_sensor_info(device).await.ok(),
+        sensor_config: client.read_config(device).await.ok(),
+    }
+}
+
+fn unix_millis(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// The next motion event, or never without motion detection.
+async fn next_motion(
+    events: &mut Option<tokio::sync::broadcast::Receiver<MotionEvent>>,
+) -> Option<MotionEvent> {
+    match events {
+        Some(events) => events.recv().await.ok(),
+        None => std::future::pending().await,
+    }
+}
+
+fn print_clip(clip: &Clip, format: Format) -> anyhow::Result<()> {
+    match format {
+        Format::Json => println!("{}", serde_json::to_string(clip)?),
+        Format::Human => println!(
+            "Clip of {} frames around {} written to {}",
+            clip.frames.len(),
+            clip.reason,
+            clip.path.display()
+        ),
+    }
+    Ok(())
+}
+
+fn print_json<T: Serialize>(value: &T) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn print_config(config: &SensorConfig, format: Format) -> anyhow::Result<()> {
+    match format {
+        Format::Json => print_json(config),
+        // Same layout `set-config` reads back
+        Format::Human => {
+            print!("{}", toml::to_string(config)?);
+            Ok(())
+        }
+    }
+}
+
+fn print_message(msg: &Message, format: Format) -> anyhow::Result<()> {
+    match format {
+        Format::Json => println!("{}", serde_json::to_string(msg)?),
+        Format::Human => println!("{}: {}", msg.client_id, describe(&msg.payload)),
+    }
+    Ok(())
+}
+
+/// One-line summary of a payload, without dumping frame buffers to the terminal
+fn describe(payload: &Payload) -> String {
+    match payload {
+        Payload::SensorReading(SensorData::Camera { frame_buffer, .. }) => {
+            format!("sensor reading ({} bytes)", frame_buffer.len())
+        }
+        Payload::StoredReading(stored) => {
+            let SensorData::Camera { frame_buffer, .. } = &stored.reading;
+            format!(
+                "stored reading #{} ({} bytes)",
+                stored.capture.id,
+                frame_buffer.len()
+            )
+        }
+        Payload::BurstReading(shot) => {
+            let SensorData::Camera { frame_buffer, .. } = &shot.reading;
+            format!(
+                "burst shot {}/{} ({} bytes)",
+                shot.index + 1,
+                shot.count,
+                frame_buffer.len()
+            )
+        }
+        Payload::Motion(alert) => match &alert.reading {
+            Some(SensorData::Camera { frame_buffer, .. }) => format!(
+                "motion in {:.1}% of the frame ({} bytes)",
+                f32::from(alert.area) / 10.0,
+                frame_buffer.len()
+            ),
+            None => format!(
+                "motion in {:.1}% of the frame",
+                f32::from(alert.area) / 10.0
+            ),
+        },
+        Payload::Error(message) => format!("error: {}", message),
+        other => format!("{:?}", other),
+    }
+}
+
+/// One shot per AE level unless a count is given.
+fn shot_count(count: Option<u8>, ae_levels: &[i8]) -> anyhow::Result<u8> {
+    match count {
+        Some(count) => Ok(count),
+        None => u8::try_from(ae_levels.len().max(1))
+            .map_err(|_| anyhow!("At most {} AE levels can be given", u8::MAX)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn config_files_round_trip() {
+        let json = r#"{"camera": {
+            "brightness": 1, "contrast": 0, "saturation": -1, "sharpness": 0, "deNoise": 0,
+            "specialEffect": 0, "wbMode": 0, "awb": true, "awbGain": true, "gainCeiling": 0,
+            "lensCorrection": true, "horizontalMirror": false, "verticalFlip": true
+        }}"#;
+        let config = parse_config(json, Some("json")).unwrap();
+        let toml = toml::to_string(&config).unwrap();
+        let SensorConfig::Camera(config) = parse_config(&toml, Some("toml")).unwrap();
+        assert_eq!(config.brightness, 1);
+        assert_eq!(config.saturation, -1);
+        assert!(config.vertical_flip);
+
+        assert!(parse_config(json, Some("yaml")).is_err());
+    }
+
+    #[test]
+    fn parses_exposure_brackets() {
+        let cli = Cli::parse_from(["flock", "burst", "node", "out", "--ae-levels", "-2,0,2"]);
+        let Command::Burst {
+            ae_levels, count, ..
+        } = cli.command
+        else {
+            panic!("expected burst");
+        };
+        assert_eq!(ae_levels, [-2, 0, 2]);
+        assert_eq!(count, None);
+        assert_eq!(shot_count(count, &ae_levels).unwrap(), 3);
+        assert_eq!(shot_count(None, &[]).unwrap(), 1);
+        assert!(shot_count(None, &[0; 256]).is_err());
+    }
+
+    #[test]
+    fn parses_motion_masks() {
+        let cli = Cli::parse_from([
+            "flock",
+            "motion",
+            "node",
+            "--mask",
+            "0,0,0.5,0.25",
+            "--mask",
+            "0.5, 0.5, 0.5, 0.5",
+        ]);
+        let Command::Motion { mask, .. } = cli.command else {
+            panic!("expected motion");
+        };
+        assert_eq!(mask.len(), 2);
+        assert_eq!(mask[0].height, 0.25);
+        assert!(parse_region("0,0,1").is_err());
+    }
+
+    #[test]
+    fn motion_needs_an_interval() {
+        let cli = Cli::try_parse_from(["flock", "motion", "node", "--interval", "0"]);
+        assert!(cli.is_err());
+    }
+
+    #[test]
+    fn parses_timelapse_ranges() {
+        let cli = Cli::parse_from([
+            "flock",
+            "timelapse",
+            "node",
+            "day.gif",
+            "--from",
+            "1660000000",
+            "--deflicker",
+        ]);
+        let Command::Timelapse { args, library, .. } = cli.command else {
+            panic!("expected timelapse");
+        };
+        assert_eq!((args.from, args.to), (Some(1_660_000_000), None));
+        assert!(args.deflicker);
+        assert_eq!(args.fps, 10.0);
+        assert_eq!(library, None);
+    }
+
+    #[test]
+    fn recording_on_an_interval_needs_a_device() {
+        let cli = Cli::try_parse_from(["flock", "library", "lib", "record", "--interval", "500"]);
+        assert!(cli.is_err());
+        let cli = Cli::parse_from([
+            "flock",
+            "library",
+            "lib",
+            "record",
+            "--device",
+            "node",
+            "--interval",
+            "500",
+            "--tag",
+            "porch",
+        ]);
+        let Command::Library {
+            action: LibraryCommand::Record { tag, interval, .. },
+            ..
+        } = cli.command
+        else {
+            panic!("expected library record");
+        };
+        assert_eq!((tag, interval), (vec!["porch".to_string()], Some(500)));
+        let cli = Cli::try_parse_from([
+            "flock",
+            "library",
+            "lib",
+            "record",
+            "--device",
+            "node",
+            "--interval",
+            "0",
+        ]);
+        assert!(cli.is_err());
+    }
+
+    #[test]
+    fn parses_raw_instructions() {
+        let cli = Cli::parse_from(["flock", "send", "node", r#"{"fetchCapture": 3}"#, "--wait"]);
+        let Command::Send { instruction, wait, .. } = cli.command else {
+            panic!("expected send");
+        };
+        assert!(wait);
+        let instruction: Instruction = serde_json::from_str(&instruction).unwrap();
+        assert!(matches!(instruction, Instruction::FetchCapture(3)));
+    }
+}
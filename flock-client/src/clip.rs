@@ -0,0 +1,559 @@
+//! Clips of the frames around an event, to see what led up to it and what came after.
+//!
+//! A `ClipRecorder` keeps the last few seconds of frames each node publishes, whoever asked for
+//! them. When an event fires, for a node's own motion alert or through `ClipRecorder::trigger`,
+//! those frames start a clip that keeps taking frames until the time after the event is up, and
+//! is then written out with a JSON sidecar.
+
+use crate::Client;
+use flock_api::{Message, Payload, SensorConfig, SensorData, SensorInfo};
+use flock_imaging::avi::AviWriter;
+use flock_imaging::metadata::{self, CaptureMetadata};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+const EVENT_CAPACITY: usize = 16;
+/// Frames kept per node however long `before` is, so a node streaming fast can't take up all
+/// memory
+const MAX_BUFFERED_FRAMES: usize = 300;
+/// Frames in one clip, however long events keep extending it. A full clip is written out as it
+/// is, and an event after that starts a new one.
+const MAX_CLIP_FRAMES: usize = 3000;
+/// How often to look for clips that are done
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+/// Sidecar of a JPEG sequence, inside its directory
+const SEQUENCE_SIDECAR: &str = "clip.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipFormat {
+    /// `<device>-<ms>.avi`, with the sidecar next to it as `<device>-<ms>.json`
+    Avi,
+    /// A `<device>-<ms>` directory of `0000.jpg`, `0001.jpg`, ..., and `clip.json`
+    JpegSequence,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClipOptions {
+    pub dir: PathBuf,
+    /// Time before the event to include
+    pub before: Duration,
+    /// Time after the event to include. Another event during it makes the clip longer, up to
+    /// `MAX_CLIP_FRAMES` frames.
+    pub after: Duration,
+    pub format: ClipFormat,
+    /// For frames that don't arrive as JPEG already
+    pub jpeg_quality: u8,
+    /// Start clips for `Payload::Motion`, the motion nodes detect themselves
+    pub on_motion_alerts: bool,
+}
+
+impl ClipOptions {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            before: Duration::from_secs(5),
+            after: Duration::from_secs(5),
+            format: ClipFormat::Avi,
+            jpeg_quality: 90,
+            on_motion_alerts: true,
+        }
+    }
+}
+
+/// A clip that was written, as its sidecar describes it. Times are milliseconds since the Unix
+/// epoch, when the controller received the frame.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Clip {
+    pub device: String,
+    /// What fired the event, e.g. "motion"
+    pub reason: String,
+    pub triggered_at: u64,
+    /// The node's last sensor config before the event, if it was heard
+    pub sensor_config: Option<SensorConfig>,
+    /// The node's sensor, if it was heard
+    #[serde(default)]
+    pub sensor: Option<SensorInfo>,
+    pub frames: Vec<ClipFrame>,
+    /// The AVI file or the JPEG sequence's directory
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClipFrame {
+    pub received_at: u64,
+    pub width: u32,
+    pub height: u32,
+    /// File name within a JPEG sequence's directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+/// Records clips until dropped. A clip that is still taking frames then isn't written.
+pub struct ClipRecorder {
+    triggers: mpsc::UnboundedSender<(String, String)>,
+    clips: broadcast::Sender<Clip>,
+    task: JoinHandle<()>,
+}
+
+impl ClipRecorder {
+    /// Fires an event for `device` now, e.g. for motion the controller detected or an external
+    /// trigger.
+    pub fn trigger(&self, device: &str, reason: &str) {
+        // The task only ends when the recorder is dropped
+        let _ = self.triggers.send((device.into(), reason.into()));
+    }
+
+    /// Clips written from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Clip> {
+        self.clips.subscribe()
+    }
+}
+
+impl Drop for ClipRecorder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Client {
+    /// Starts buffering frames for clips. Sensor configs and sensors for the sidecars are picked
+    /// up from the `SensorConfig` and `SensorInfo` messages nodes send, e.g. in answer to
+    /// `read_config` and `read_sensor_info`.
+    pub fn record_clips(&self, options: ClipOptions) -> ClipRecorder {
+        let (triggers, trigger_rx) = mpsc::unbounded_channel();
+        let (clips, _) = broadcast::channel(EVENT_CAPACITY);
+        let task = tokio::spawn(record(
+            self.subscribe(),
+            trigger_rx,
+            Buffers::new(options.clone()),
+            options,
+            clips.clone(),
+        ));
+        ClipRecorder {
+            triggers,
+            clips,
+            task,
+        }
+    }
+}
+
+async fn record(
+    mut messages: broadcast::Receiver<Message>,
+    mut triggers: mpsc::UnboundedReceiver<(String, String)>,
+    mut buffers: Buffers,
+    options: ClipOptions,
+    clips: broadcast::Sender<Clip>,
+) {
+    let mut checks = time::interval(CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            msg = messages.recv() => match msg {
+                Ok(msg) => buffers.message(msg, SystemTime::now()),
+                // Missing a few frames only makes the clip choppier
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            Some((device, reason)) = triggers.recv() => {
+                buffers.trigger(&device, &reason, SystemTime::now());
+            }
+            _ = checks.tick() => {
+                for pending in buffers.due(SystemTime::now()) {
+                    // Encoding frames takes a while, don't hold up buffering
+                    tokio::spawn(finish(pending, options.clone(), clips.clone()));
+                }
+            }
+        }
+    }
+}
+
+async fn finish(pending: Pending, options: ClipOptions, clips: broadcast::Sender<Clip>) {
+    let device = pending.device.clone();
+    let written = tokio::task::spawn_blocking(move || write(pending, &options))
+        .await
+        .unwrap_or_else(|err| Err(io::Error::other(err)));
+    match written {
+        Ok(clip) => {
+            // Nobody listening isn't an error
+            let _ = clips.send(clip);
+        }
+        Err(err) => error!("Error writing clip of {}: {}", device, err),
+    }
+}
+
+struct Frame {
+    received_at: SystemTime,
+    reading: Arc<SensorData>,
+}
+
+/// A clip still taking frames
+struct Pending {
+    device: String,
+    reason: String,
+    triggered_at: SystemTime,
+    end: SystemTime,
+    sensor_config: Option<SensorConfig>,
+    sensor: Option<SensorInfo>,
+    frames: Vec<Frame>,
+}
+
+/// Recent frames and configs per node, and the clips being recorded.
+struct Buffers {
+    before: Duration,
+    after: Duration,
+    on_motion_alerts: bool,
+    recent: HashMap<String, VecDeque<Frame>>,
+    configs: HashMap<String, SensorConfig>,
+    sensors: HashMap<String, SensorInfo>,
+    pending: Vec<Pending>,
+}
+
+impl Buffers {
+    fn new(options: ClipOptions) -> Self {
+        Self {
+            before: options.before,
+            after: options.after,
+            on_motion_alerts: options.on_motion_alerts,
+            recent: HashMap::new(),
+            configs: HashMap::new(),
+            sensors: HashMap::new(),
+            pending: vec![],
+        }
+    }
+
+    fn message(&mut self, msg: Message, now: SystemTime) {
+        match &*msg.payload {
+            Payload::SensorConfig(config) => {
+                self.configs.insert(msg.client_id.clone(), config.clone());
+            }
+            Payload::SensorInfo(sensor) => {
+                self.sensors.insert(msg.client_id.clone(), sensor.clone());
+            }
+            _ => {}
+        }
+        if let Some(reading) = crate::motion::frame(&msg.payload) {
+            self.frame(&msg.client_id, reading.clone(), now);
+        }
+        if self.on_motion_alerts && matches!(*msg.payload, Payload::Motion(_)) {
+            self.trigger(&msg.client_id, "motion", now);
+        }
+    }
+
+    fn frame(&mut self, device: &str, reading: SensorData, now: SystemTime) {
+        let reading = Arc::new(reading);
+        for pending in &mut self.pending {
+            if pending.device == device
+                && now <= pending.end
+                && pending.frames.len() < MAX_CLIP_FRAMES
+            {
+                pending.frames.push(Frame {
+                    received_at: now,
+                    reading: reading.clone(),
+                });
+                if pending.frames.len() == MAX_CLIP_FRAMES {
+                    // Due with the next check
+                    pending.end = now;
+                }
+            }
+        }
+        let recent = self.recent.entry(device.into()).or_default();
+        recent.push_back(Frame {
+            received_at: now,
+            reading,
+        });
+        let oldest = now
+            .checked_sub(self.before)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        while recent.len() > MAX_BUFFERED_FRAMES
+            || recent.front().is_some_and(|f| f.received_at < oldest)
+        {
+            recent.pop_front();
+        }
+    }
+
+    fn trigger(&mut self, device: &str, reason: &str, now: SystemTime) {
+        let end = now + self.after;
+        let mut pending = self.pending.iter_mut().filter(|p| p.device == device);
+        if let Some(pending) = pending.find(|p| p.frames.len() < MAX_CLIP_FRAMES) {
+            pending.end = pending.end.max(end);
+            return;
+        }
+        let start = now
+            .checked_sub(self.before)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        // Frames a full clip that isn't written yet already has
+        let cut = self
+            .pending
+            .iter()
+            .filter(|p| p.device == device)
+            .map(|p| p.end)
+            .max();
+        let frames = self
+            .recent
+            .get(device)
+            .into_iter()
+            .flatten()
+            .filter(|f| f.received_at >= start && cut.is_none_or(|cut| f.received_at > cut))
+            .map(|f| Frame {
+                received_at: f.received_at,
+                reading: f.reading.clone(),
+            })
+            .collect();
+        self.pending.push(Pending {
+            device: device.into(),
+            reason: reason.into(),
+            triggered_at: now,
+            end,
+            sensor_config: self.configs.get(device).cloned(),
+            sensor: self.sensors.get(device).cloned(),
+            frames,
+        });
+    }
+
+    /// Takes out the clips whose time is up.
+    fn due(&mut self, now: SystemTime) -> Vec<Pending> {
+        let (due, pending) = self.pending.drain(..).partition(|p| p.end <= now);
+        self.pending = pending;
+        due
+    }
+}
+
+fn write(pending: Pending, options: &ClipOptions) -> io::Result<Clip> {
+    let mut frames = vec![];
+    for frame in &pending.frames {
+        match flock_imaging::reading_to_jpeg(&frame.reading, options.jpeg_quality) {
+            Ok((jpeg, size)) => frames.push((frame.received_at, jpeg, size)),
+            Err(err) => warn!("Skipping frame of {}: {}", pending.device, err),
+        }
+    }
+    if frames.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no frames around the event",
+        ));
+    }
+
+    let name = format!(
+        "{}-{}",
+        flock_imaging::file_name(&pending.device),
+        millis(pending.triggered_at)
+    );
+    fs::create_dir_all(&options.dir)?;
+    let (path, sidecar, frames) = match options.format {
+        ClipFormat::Avi => {
+            let path = options.dir.join(format!("{}.avi", name));
+            let frames = write_avi(&path, frames)?;
+            (path, options.dir.join(format!("{}.json", name)), frames)
+        }
+        ClipFormat::JpegSequence => {
+            let path = options.dir.join(name);
+            fs::create_dir_all(&path)?;
+            let mut written = vec![];
+            for (i, (received_at, jpeg, (width, height))) in frames.into_iter().enumerate() {
+                let file = format!("{:04}.jpg", i);
+                let metadata = CaptureMetadata {
+                    client_id: pending.device.clone(),
+                    captured_at: millis(received_at),
+                    sensor: pending.sensor.clone(),
+                    sensor_config: pending.sensor_config.clone(),
+                };
+                let jpeg = metadata::embed(&jpeg, &metadata).map_err(io::Error::other)?;
+                fs::write(path.join(&file), jpeg)?;
+                written.push(ClipFrame {
+                    received_at: millis(received_at),
+                    width,
+                    height,
+                    file: Some(file),
+                });
+            }
+            (path.clone(), path.join(SEQUENCE_SIDECAR), written)
+        }
+    };
+
+    let clip = Clip {
+        device: pending.device,
+        reason: pending.reason,
+        triggered_at: millis(pending.triggered_at),
+        sensor_config: pending.sensor_config,
+        sensor: pending.sensor,
+        frames,
+        path,
+    };
+    fs::write(sidecar, serde_json::to_vec_pretty(&clip)?)?;
+    Ok(clip)
+}
+
+/// Writes the frames the size of the first one, a node changing its frame size halfway
+/// through would need a second video. Frames are shown for their average spacing.
+fn write_avi(
+    path: &Path,
+    frames: Vec<(SystemTime, Vec<u8>, (u32, u32))>,
+) -> io::Result<Vec<ClipFrame>> {
+    let (first, last) = (frames[0].0, frames[frames.len() - 1].0);
+    let interval = match frames.len() {
+        1 => Duration::from_secs(1),
+        n => last.duration_since(first).unwrap_or_default() / (n as u32 - 1),
+    };
+    let (width, height) = frames[0].2;
+    let mut avi = AviWriter::new(BufWriter::new(File::create(path)?), width, height, interval)?;
+    let mut written = vec![];
+    for (received_at, jpeg, size) in frames {
+        if size != (width, height) {
+            continue;
+        }
+        avi.push(&jpeg)?;
+        written.push(ClipFrame {
+            received_at: millis(received_at),
+            width,
+            height,
+            file: None,
+        });
+    }
+    avi.finish()?;
+    Ok(written)
+}
+
+fn millis(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flock_api::{FrameFormat, PixelFormat};
+
+    fn time(ms: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    fn reading(level: u8) -> SensorData {
+        SensorData::Camera {
+            frame_buffer: vec![level; 4],
+            format: FrameFormat {
+                pixel_format: PixelFormat::Grayscale,
+                width: 2,
+                height: 2,
+            },
+        }
+    }
+
+    fn buffers() -> Buffers {
+        Buffers::new(ClipOptions {
+            before: Duration::from_secs(2),
+            after: Duration::from_secs(1),
+            ..ClipOptions::new("clips")
+        })
+    }
+
+    fn received(pending: &Pending) -> Vec<u64> {
+        pending
+            .frames
+            .iter()
+            .map(|f| millis(f.received_at))
+            .collect()
+    }
+
+    #[test]
+    fn clips_span_before_and_after() {
+        let mut buffers = buffers();
+        for ms in (0..=5000).step_by(500) {
+            buffers.frame("node", reading(0), time(ms));
+            buffers.frame("other", reading(0), time(ms));
+            if ms == 4000 {
+                buffers.trigger("node", "test", time(ms));
+            }
+        }
+        assert!(buffers.due(time(4999)).is_empty());
+        let due = buffers.due(time(5000));
+        assert_eq!(due.len(), 1);
+        assert_eq!(
+            received(&due[0]),
+            [2000, 2500, 3000, 3500, 4000, 4500, 5000]
+        );
+        assert!(buffers.due(time(9000)).is_empty());
+    }
+
+    #[test]
+    fn events_during_a_clip_extend_it() {
+        let mut buffers = buffers();
+        buffers.frame("node", reading(0), time(0));
+        buffers.trigger("node", "first", time(100));
+        buffers.trigger("node", "second", time(900));
+        buffers.frame("node", reading(0), time(1500));
+        assert!(buffers.due(time(1500)).is_empty());
+        let due = buffers.due(time(1900));
+        assert_eq!(due[0].reason, "first");
+        assert_eq!(received(&due[0]), [0, 1500]);
+    }
+
+    #[test]
+    fn full_clips_are_cut() {
+        let mut buffers = Buffers::new(ClipOptions {
+            before: Duration::from_secs(2),
+            after: Duration::from_secs(3600),
+            ..ClipOptions::new("clips")
+        });
+        let last = MAX_CLIP_FRAMES as u64;
+        buffers.trigger("node", "first", time(0));
+        for ms in 1..=last + 1 {
+            buffers.frame("node", reading(0), time(ms));
+        }
+        buffers.trigger("node", "second", time(last + 1));
+
+        let due = buffers.due(time(last + 1));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].reason, "first");
+        assert_eq!(due[0].frames.len(), MAX_CLIP_FRAMES);
+        buffers.frame("node", reading(0), time(last + 2));
+        let pending = &buffers.pending[0];
+        assert_eq!(pending.reason, "second");
+        assert_eq!(received(pending), [last + 1, last + 2]);
+    }
+
+    #[test]
+    fn motion_alerts_start_clips_with_the_config() {
+        let mut buffers = buffers();
+        let from = |payload| Message::new("node".into(), "controller".into(), payload);
+        let config = SensorConfig::Camera(flock_api::CameraSensorConfig {
+            brightness: 1,
+            contrast: 0,
+            saturation: 0,
+            sharpness: 0,
+            de_noise: 0,
+            special_effect: 0,
+            wb_mode: 0,
+            awb: true,
+            awb_gain: true,
+            gain_ceiling: 0,
+            lens_correction: true,
+            horizontal_mirror: false,
+            vertical_flip: false,
+        });
+        buffers.message(from(Payload::SensorConfig(config)), time(0));
+        let alert = flock_api::MotionAlert {
+            area: 50,
+            reading: Some(reading(9)),
+        };
+        buffers.message(from(Payload::Motion(alert)), time(100));
+
+        let due = buffers.due(time(1100));
+        assert_eq!(due[0].reason, "motion");
+        assert!(matches!(
+            &due[0].sensor_config,
+            Some(SensorConfig::Camera(config)) if config.brightness == 1
+        ));
+        assert_eq!(received(&due[0]), [100]);
+    }
+}
@@ -0,0 +1,504 @@
+//! Async client for talking to flock camera nodes over MQTT.
+//!
+//! Nodes receive instructions on their own topic (their client ID) and publish everything they
+//! have to say on the controller topic, without any request ID. A reply is therefore the next
+//! message from the node that has the payload asked for, and concurrent requests of the same
+//! kind to the same node can't be told apart.
+
+use flock_api::{
+    Burst, BurstShot, FrameEncoding, Instruction, Message, MotionDetection, Payload, Roi,
+    RoiStatus, SensorConfig, SensorData, SensorInfo, StoredCapture, StoredReading,
+};
+use log::*;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+pub mod clip;
+pub mod motion;
+
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Uncompressed frames are sent as JSON arrays of numbers, so messages get big
+const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+const EVENT_CAPACITY: usize = 64;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub host: String,
+    pub port: u16,
+    /// Our own client ID, also used as the sender of instructions
+    pub client_id: String,
+    /// Topic nodes publish on, `FLOCK_CONTROLLER_TOPIC` in the firmware build
+    pub controller_topic: String,
+    /// How long to wait for a node to answer, and for the broker to accept the connection
+    pub request_timeout: Duration,
+}
+
+impl ClientOptions {
+    pub fn new(host: &str, port: u16, client_id: &str, controller_topic: &str) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            client_id: client_id.into(),
+            controller_topic: controller_topic.into(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Client(rumqttc::ClientError),
+    Connection(rumqttc::ConnectionError),
+    /// No answer within the request timeout
+    Timeout,
+    /// The node answered with an error
+    Device(String),
+    /// The connection to the broker is gone for good
+    Disconnected,
+    /// This many messages went by before they could be looked at, the answer may be among them
+    Lagged(u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Client(err) => write!(f, "MQTT client error: {}", err),
+            Error::Connection(err) => write!(f, "MQTT connection error: {}", err),
+            Error::Timeout => write!(f, "timed out"),
+            Error::Device(message) => write!(f, "device error: {}", message),
+            Error::Disconnected => write!(f, "disconnected"),
+            Error::Lagged(count) => write!(f, "missed {} messages", count),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rumqttc::ClientError> for Error {
+    fn from(err: rumqttc::ClientError) -> Self {
+        Error::Client(err)
+    }
+}
+
+impl From<rumqttc::ConnectionError> for Error {
+    fn from(err: rumqttc::ConnectionError) -> Self {
+        Error::Connection(err)
+    }
+}
+
+pub struct Client {
+    mqtt: AsyncClient,
+    options: ClientOptions,
+    events: broadcast::Sender<Message>,
+    devices: Arc<Mutex<BTreeSet<String>>>,
+    event_loop: JoinHandle<()>,
+}
+
+impl Client {
+    /// Connects to the broker and starts listening on the controller topic.
+    pub async fn connect(options: ClientOptions) -> Result<Self, Error> {
+        let mut mqtt_options = MqttOptions::new(&options.client_id, &options.host, options.port);
+        mqtt_options
+            .set_keep_alive(Duration::from_secs(30))
+            .set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+        let (mqtt, mut event_loop) = AsyncClient::new(mqtt_options, EVENT_CAPACITY);
+        mqtt.subscribe(&options.controller_topic, QoS::AtMostOnce)
+            .await?;
+
+        // Requests sent before the subscription is in place could miss their reply
+        time::timeout(options.request_timeout, async {
+            loop {
+                if let Event::Incoming(Packet::SubAck(_)) = event_loop.poll().await? {
+                    return Ok::<_, Error>(());
+                }
+            }
+        })
+        .await
+        .map_err(|_| Error::Timeout)??;
+
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let devices = Arc::new(Mutex::new(BTreeSet::new()));
+        let event_loop = tokio::spawn(run(
+            event_loop,
+            options.controller_topic.clone(),
+            events.clone(),
+            devices.clone(),
+        ));
+        Ok(Self {
+            mqtt,
+            options,
+            events,
+            devices,
+            event_loop,
+        })
+    }
+
+    pub fn options(&self) -> &ClientOptions {
+        &self.options
+    }
+
+    /// Every message nodes publish on the controller topic from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Message> {
+        self.events.subscribe()
+    }
+
+    /// Client IDs of the nodes heard from since connecting.
+    pub fn devices(&self) -> Vec<String> {
+        self.devices.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Asks every node to answer, listens for `timeout` and lists the nodes heard from since
+    /// connecting. Always-on nodes answer right away, low-power nodes announce themselves when
+    /// they wake within `timeout`.
+    pub async fn discover(&self, timeout: Duration) -> Result<Vec<String>, Error> {
+        // Nodes only clear their own topic, so a retained probe would stay around for good
+        self.publish(
+            flock_api::transport::BROADCAST_ADDRESS,
+            Instruction::ReadPowerMode,
+            false,
+        )
+        .await?;
+        time::sleep(timeout).await;
+        Ok(self.devices())
+    }
+
+    /// Sends an instruction without waiting for an answer. The broker keeps it until the node
+    /// has handled it, so an instruction sent while a low-power node sleeps reaches it when it
+    /// wakes. Only the latest instruction is kept that way.
+    pub async fn send(&self, device: &str, instruction: Instruction) -> Result<(), Error> {
+        self.publish(device, instruction, true).await
+    }
+
+    async fn publish(
+        &self,
+        topic: &str,
+        instruction: Instruction,
+        retain: bool,
+    ) -> Result<(), Error> {
+        let msg = Message::new(
+            self.options.client_id.clone(),
+            topic.into(),
+            Payload::Instruction(instruction),
+        );
+        let payload = flock_api::codec::encode(&msg).expect("messages always serialize");
+        self.mqtt
+            .publish(topic, QoS::AtMostOnce, retain, payload)
+            .await?;
+        Ok(())
+    }
+
+    /// Sends an instruction and waits for the first message from `device` that `expect` accepts.
+    /// `expect` hands back payloads that aren't the answer so they are skipped.
+    pub async fn request<T>(
+        &self,
+        device: &str,
+        instruction: Instruction,
+        expect: impl Fn(Payload) -> Result<T, Payload>,
+    ) -> Result<T, Error> {
+        // Subscribe first so a quick reply isn't missed
+        let mut events = self.subscribe();
+        self.send(device, instruction).await?;
+        let deadline = Instant::now() + self.options.request_timeout;
+        loop {
+            let msg = match time::timeout_at(deadline, events.recv()).await {
+                Ok(Ok(msg)) => msg,
+                Ok(Err(broadcast::error::RecvError::Lagged(count))) => {
+                    return Err(Error::Lagged(count))
+                }
+                Ok(Err(broadcast::error::RecvError::Closed)) => return Err(Error::Disconnected),
+                Err(_) => return Err(Error::Timeout),
+            };
+            if let Some(reply) = reply(device, msg, &expect) {
+                return reply;
+            }
+        }
+    }
+
+    pub async fn read_sensor(&self, device: &str) -> Result<SensorData, Error> {
+        self.request(device, Instruction::ReadSensor, sensor_data)
+            .await
+    }
+
+    /// Reads the sensor with `encoding` instead of the node's frame encoding.
+    pub async fn read_sensor_encoded(
+        &self,
+        device: &str,
+        encoding: FrameEncoding,
+    ) -> Result<SensorData, Error> {
+        self.request(
+            device,
+            Instruction::ReadSensorEncoded(encoding),
+            sensor_data,
+        )
+        .await
+    }
+
+    /// Takes a burst and returns its shots in order. Nodes answer once the last shot is taken,
+    /// so the request timeout starts after the burst's intervals.
+    pub async fn burst(&self, device: &str, burst: Burst) -> Result<Vec<BurstShot>, Error> {
+        let mut events = self.subscribe();
+        let wait = Duration::from_millis(u64::from(burst.interval_ms) * u64::from(burst.count));
+        self.send(device, Instruction::Burst(burst)).await?;
+        let deadline = Instant::now() + wait + self.options.request_timeout;
+        let mut shots: Vec<BurstShot> = vec![];
+        loop {
+            let msg = match time::timeout_at(deadline, events.recv()).await {
+                Ok(Ok(msg)) => msg,
+                Ok(Err(broadcast::error::RecvError::Lagged(count))) => {
+                    return Err(Error::Lagged(count))
+                }
+                Ok(Err(broadcast::error::RecvError::Closed)) => return Err(Error::Disconnected),
+                Err(_) => return Err(Error::Timeout),
+            };
+            let expect = |payload| match payload {
+                Payload::BurstReading(shot) => Ok(shot),
+                other => Err(other),
+            };
+            if let Some(shot) = reply(device, msg, expect) {
+                let shot = shot?;
+                let count = usize::from(shot.count);
+                shots.push(shot);
+                if shots.len() == count {
+                    shots.sort_by_key(|shot| shot.index);
+                    return Ok(shots);
+                }
+            }
+        }
+    }
+
+    /// Captures on the node's SD card.
+    pub async fn list_captures(&self, device: &str) -> Result<Vec<StoredCapture>, Error> {
+        self.request(device, Instruction::ListCaptures, |payload| match payload {
+            Payload::StoredCaptures(captures) => Ok(captures),
+            other => Err(other),
+        })
+        .await
+    }
+
+    pub async fn fetch_capture(&self, device: &str, id: u32) -> Result<StoredReading, Error> {
+        // Nodes also publish stored readings on their own when they wake
+        self.request(
+            device,
+            Instruction::FetchCapture(id),
+            |payload| match payload {
+                Payload::StoredReading(stored) if stored.capture.id == id => Ok(stored),
+                other => Err(other),
+            },
+        )
+        .await
+    }
+
+    /// Sets how the node encodes readings from now on.
+    pub async fn write_frame_encoding(
+        &self,
+        device: &str,
+        encoding: FrameEncoding,
+    ) -> Result<FrameEncoding, Error> {
+        self.request(
+            device,
+            Instruction::WriteFrameEncoding(encoding),
+            |payload| match payload {
+                Payload::FrameEncoding(encoding) => Ok(encoding),
+                other => Err(other),
+            },
+        )
+        .await
+    }
+
+    pub async fn read_config(&self, device: &str) -> Result<SensorConfig, Error> {
+        self.request(device, Instruction::ReadSensorConfig, sensor_config)
+            .await
+    }
+
+    /// Writes the sensor config and returns the config the node ended up with.
+    pub async fn write_config(
+        &self,
+        device: &str,
+        config: SensorConfig,
+    ) -> Result<SensorConfig, Error> {
+        self.request(device, Instruction::WriteSensorConfig(config), sensor_config)
+            .await
+    }
+
+    pub async fn read_sensor_info(&self, device: &str) -> Result<SensorInfo, Error> {
+        self.request(
+            device,
+            Instruction::ReadSensorInfo,
+            |payload| match payload {
+                Payload::SensorInfo(info) => Ok(info),
+                other => Err(other),
+            },
+        )
+        .await
+    }
+
+    /// Sets the region of interest, `None` for whole frames. The status says what the frames
+    /// look like now.
+    pub async fn write_roi(&self, device: &str, roi: Option<Roi>) -> Result<RoiStatus, Error> {
+        self.request(
+            device,
+            Instruction::WriteRoi(roi),
+            |payload| match payload {
+                Payload::Roi(status) => Ok(status),
+                other => Err(other),
+            },
+        )
+        .await
+    }
+
+    /// Has the node detect motion itself, or stop with `None`. Alerts arrive as `Payload::Motion`
+    /// through `subscribe`.
+    pub async fn write_motion_detection(
+        &self,
+        device: &str,
+        settings: Option<MotionDetection>,
+    ) -> Result<Option<MotionDetection>, Error> {
+        self.request(
+            device,
+            Instruction::WriteMotionDetection(settings),
+            |payload| match payload {
+                Payload::MotionDetection(settings) => Ok(settings),
+                other => Err(other),
+            },
+        )
+        .await
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.event_loop.abort();
+    }
+}
+
+fn sensor_data(payload: Payload) -> Result<SensorData, Payload> {
+    match payload {
+        Payload::SensorReading(data) => Ok(data),
+        Payload::StoredReading(stored) => Ok(stored.reading),
+        other => Err(other),
+    }
+}
+
+fn sensor_config(payload: Payload) -> Result<SensorConfig, Payload> {
+    match payload {
+        Payload::SensorConfig(config) => Ok(config),
+        other => Err(other),
+    }
+}
+
+/// Whether `msg` answers a request to `device`, and with what.
+fn reply<T>(
+    device: &str,
+    msg: Message,
+    expect: impl Fn(Payload) -> Result<T, Payload>,
+) -> Option<Result<T, Error>> {
+    if msg.client_id != device {
+        return None;
+    }
+    match *msg.payload {
+        Payload::Error(message) => Some(Err(Error::Device(message))),
+        payload => expect(payload).ok().map(Ok),
+    }
+}
+
+async fn run(
+    mut event_loop: EventLoop,
+    controller_topic: String,
+    events: broadcast::Sender<Message>,
+    devices: Arc<Mutex<BTreeSet<String>>>,
+) {
+    loop {
+        let publish = match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::Publish(publish))) => publish,
+            Ok(_) => continue,
+            Err(err) => {
+                // The next poll reconnects, and the session keeps our subscription
+                warn!("MQTT connection error: {}", err);
+                time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        if publish.topic != controller_topic {
+            continue;
+        }
+        let msg = match flock_api::codec::decode(&publish.payload) {
+            Ok(msg) => msg,
+            Err(err) => {
+                warn!("Error decoding message: {}", err);
+                continue;
+            }
+        };
+        {
+            let mut devices = devices.lock().unwrap();
+            if let Payload::Disconnected = *msg.payload {
+                devices.remove(&msg.client_id);
+            } else {
+                devices.insert(msg.client_id.clone());
+            }
+        }
+        // Nobody listening isn't an error
+        let _ = events.send(msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from(device: &str, payload: Payload) -> Message {
+        Message::new(device.into(), "controller".into(), payload)
+    }
+
+    #[test]
+    fn reply_ignores_other_devices() {
+        let msg = from("other", Payload::SensorConfig(sample_config()));
+        assert!(reply("node", msg, sensor_config).is_none());
+    }
+
+    #[test]
+    fn reply_skips_unrelated_payloads() {
+        let msg = from("node", Payload::Connected);
+        assert!(reply("node", msg, sensor_config).is_none());
+
+        let msg = from("node", Payload::SensorConfig(sample_config()));
+        assert!(matches!(
+            reply("node", msg, sensor_config),
+            Some(Ok(SensorConfig::Camera(_)))
+        ));
+    }
+
+    #[test]
+    fn reply_surfaces_device_errors() {
+        let msg = from("node", Payload::Error("no sensor".into()));
+        assert!(matches!(
+            reply("node", msg, sensor_config),
+            Some(Err(Error::Device(ref message))) if message == "no sensor"
+        ));
+    }
+
+    fn sample_config() -> SensorConfig {
+        SensorConfig::Camera(flock_api::CameraSensorConfig {
+            brightness: 0,
+            contrast: 0,
+            saturation: 0,
+            sharpness: 0,
+            de_noise: 0,
+            special_effect: 0,
+            wb_mode: 0,
+            awb: true,
+            awb_gain: true,
+            gain_ceiling: 0,
+            lens_correction: true,
+            horizontal_mirror: false,
+            vertical_flip: false,
+        })
+    }
+}
@@ -0,0 +1,96 @@
+//! Motion events from the frames a node publishes.
+
+use crate::Client;
+use flock_api::{Message, Payload, SensorData};
+use flock_imaging::motion::MotionDetector;
+use log::*;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+pub use flock_imaging::motion::{Motion, MotionConfig, Region};
+
+const EVENT_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotionEvent {
+    pub device: String,
+    pub motion: Motion,
+}
+
+/// Runs a motion detector on every frame one node publishes, whoever asked for it, until
+/// dropped. Frames are timed by when they arrive.
+pub struct MotionWatch {
+    events: broadcast::Sender<MotionEvent>,
+    task: JoinHandle<()>,
+}
+
+impl MotionWatch {
+    /// Motion starting and ending from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<MotionEvent> {
+        self.events.subscribe()
+    }
+}
+
+impl Drop for MotionWatch {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Client {
+    pub fn watch_motion(&self, device: &str, config: MotionConfig) -> MotionWatch {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let task = tokio::spawn(watch(
+            self.subscribe(),
+            device.into(),
+            MotionDetector::new(config),
+            events.clone(),
+        ));
+        MotionWatch { events, task }
+    }
+}
+
+async fn watch(
+    mut messages: broadcast::Receiver<Message>,
+    device: String,
+    mut detector: MotionDetector,
+    events: broadcast::Sender<MotionEvent>,
+) {
+    loop {
+        let msg = match messages.recv().await {
+            Ok(msg) => msg,
+            // Missing a few frames only makes the comparison coarser
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        if msg.client_id != device {
+            continue;
+        }
+        let frame = match frame(&msg.payload).map(flock_imaging::decode_reading) {
+            Some(Ok(frame)) => frame,
+            Some(Err(err)) => {
+                warn!("Error decoding frame from {}: {}", device, err);
+                continue;
+            }
+            None => continue,
+        };
+        if let Some(motion) = detector.feed(&frame, std::time::SystemTime::now()) {
+            // Nobody listening isn't an error
+            let _ = events.send(MotionEvent {
+                device: device.clone(),
+                motion,
+            });
+        }
+    }
+}
+
+/// The frame a payload carries, if any.
+pub fn frame(payload: &Payload) -> Option<&SensorData> {
+    match payload {
+        Payload::SensorReading(reading) => Some(reading),
+        Payload::StoredReading(stored) => Some(&stored.reading),
+        Payload::BurstReading(shot) => Some(&shot.reading),
+        Payload::Motion(alert) => alert.reading.as_ref(),
+        _ => None,
+    }
+}
@@ -0,0 +1,124 @@
+use flock_api::{
+    MDNS_SERVICE_PROTO, MDNS_SERVICE_TYPE, MDNS_TXT_CLIENT_ID, MDNS_TXT_FIRMWARE_VERSION,
+    MDNS_TXT_SENSOR_MODEL,
+};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// A flock camera node found on the LAN
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredNode {
+    pub client_id: String,
+    pub sensor_model: Option<String>,
+    pub firmware_version: Option<String>,
+    pub hostname: String,
+    pub addresses: Vec<IpAddr>,
+    /// Port of the node's HTTP server, 0 if it isn't enabled
+    pub port: u16,
+}
+
+impl DiscoveredNode {
+    fn from_service_info(info: &ServiceInfo) -> Option<Self> {
+        let mut addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+        addresses.sort();
+        Some(Self {
+            client_id: info.get_property_val_str(MDNS_TXT_CLIENT_ID)?.to_string(),
+            sensor_model: info
+                .get_property_val_str(MDNS_TXT_SENSOR_MODEL)
+                .map(String::from),
+            firmware_version: info
+                .get_property_val_str(MDNS_TXT_FIRMWARE_VERSION)
+                .map(String::from),
+            hostname: info.get_hostname().to_string(),
+            addresses,
+            port: info.get_port(),
+        })
+    }
+}
+
+/// Fully qualified mDNS service type advertised by camera nodes
+pub fn service_type() -> String {
+    format!("{}.{}.local.", MDNS_SERVICE_TYPE, MDNS_SERVICE_PROTO)
+}
+
+/// Lists the nodes that answer within `timeout`.
+pub fn discover(timeout: Duration) -> Result<Vec<DiscoveredNode>, mdns_sd::Error> {
+    let daemon = ServiceDaemon::new()?;
+    let nodes = browse(&daemon, timeout);
+    daemon.shutdown()?;
+    nodes
+}
+
+/// Same as `discover`, on an existing daemon.
+pub fn browse(
+    daemon: &ServiceDaemon,
+    timeout: Duration,
+) -> Result<Vec<DiscoveredNode>, mdns_sd::Error> {
+    let receiver = daemon.browse(&service_type())?;
+    let deadline = Instant::now() + timeout;
+    let mut nodes: Vec<DiscoveredNode> = vec![];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        match receiver.recv_timeout(remaining) {
+            Ok(ServiceEvent::ServiceResolved(info)) => {
+                // Services without a client ID aren't flock nodes we can talk to
+                if let Some(node) = DiscoveredNode::from_service_info(&info) {
+                    nodes.retain(|n| n.client_id != node.client_id);
+                    nodes.push(node);
+                }
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    daemon.stop_browse(&service_type())?;
+    nodes.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+    Ok(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn responder(client_id: &str) -> ServiceInfo {
+        let properties = HashMap::from([
+            (MDNS_TXT_CLIENT_ID.to_string(), client_id.to_string()),
+            (MDNS_TXT_SENSOR_MODEL.to_string(), "OV2640".to_string()),
+            (MDNS_TXT_FIRMWARE_VERSION.to_string(), "0.1.0".to_string()),
+        ]);
+        // Announced on whatever interfaces the host has, like a node on the LAN would be
+        ServiceInfo::new(
+            &service_type(),
+            client_id,
+            &format!("{}.local.", client_id),
+            "",
+            80,
+            Some(properties),
+        )
+        .unwrap()
+        .enable_addr_auto()
+    }
+
+    #[test]
+    #[ignore = "uses real multicast on the host's interfaces"]
+    fn discovers_local_responder() {
+        // Other nodes may be up on the LAN, or other runs of this test
+        let client_id = format!("flock-client-test-{}", std::process::id());
+        let responder_daemon = ServiceDaemon::new().unwrap();
+        responder_daemon.register(responder(&client_id)).unwrap();
+
+        let nodes = discover(Duration::from_secs(3)).unwrap();
+        responder_daemon.shutdown().unwrap();
+
+        let node = nodes
+            .iter()
+            .find(|n| n.client_id == client_id)
+            .expect("responder wasn't discovered");
+        assert_eq!(node.sensor_model.as_deref(), Some("OV2640"));
+        assert_eq!(node.firmware_version.as_deref(), Some("0.1.0"));
+        assert_eq!(node.hostname, format!("{}.local.", client_id));
+        assert!(!node.addresses.is_empty());
+        assert_eq!(node.port, 80);
+    }
+}
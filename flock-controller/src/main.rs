@@ -1,7 +1,13 @@
+mod discovery;
+
+use gtk4::glib;
 use gtk4::prelude::*;
-use gtk4::{Application, ApplicationWindow};
+use gtk4::{Application, ApplicationWindow, Label, ListBox};
+use std::thread;
+use std::time::Duration;
 
 const APP_ID: &str = "org.gtk_rs.HelloWorld2";
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
 
 fn main() {
     // Create a new application
@@ -15,12 +21,47 @@ fn main() {
 }
 
 fn build_ui(app: &Application) {
+    let nodes = ListBox::new();
+
     // Create a window and set the title
     let window = ApplicationWindow::builder()
         .application(app)
         .title("My GTK App")
+        .child(&nodes)
         .build();
 
+    // Browse for camera nodes in the background, mDNS would block the UI thread
+    let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+    thread::spawn(move || {
+        tx.send(discovery::discover(DISCOVERY_TIMEOUT)).unwrap();
+    });
+    rx.attach(None, move |result| {
+        match result {
+            Ok(found) => {
+                for node in found {
+                    let addresses = node
+                        .addresses
+                        .iter()
+                        .map(|a| a.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let text = format!(
+                        "{} - {} - firmware {} - {} [{}] port {}",
+                        node.client_id,
+                        node.sensor_model.as_deref().unwrap_or("unknown sensor"),
+                        node.firmware_version.as_deref().unwrap_or("unknown"),
+                        node.hostname,
+                        addresses,
+                        node.port
+                    );
+                    nodes.append(&Label::new(Some(&text)));
+                }
+            }
+            Err(err) => eprintln!("Error discovering camera nodes: {}", err),
+        }
+        glib::Continue(false)
+    });
+
     // Present window
     window.present();
-}
\ No newline at end of file
+}
@@ -0,0 +1,109 @@
+//! Writes the reference frames `tests/reference.rs` decodes: the OV2640 colour bar test pattern
+//! as 64x8 frames with 8 bars of 8 pixels, one file per pixel format.
+//!
+//! The frames are encoded here from the bar colours, following esp32-camera's byte order (and
+//! for YUV, the JFIF equations), without going through the decoders, so those aren't checked
+//! against themselves.
+//!
+//! ```text
+//! cargo run -p flock-imaging --example colorbar_frames [DIR]
+//! ```
+//!
+//! `DIR` defaults to `tests/frames` in this crate.
+
+use std::fs;
+use std::path::PathBuf;
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 8;
+
+const COLOR_BARS: [[u8; 3]; 8] = [
+    [255, 255, 255],
+    [255, 255, 0],
+    [0, 255, 255],
+    [0, 255, 0],
+    [255, 0, 255],
+    [255, 0, 0],
+    [0, 0, 255],
+    [0, 0, 0],
+];
+
+fn bar(x: usize) -> [u8; 3] {
+    COLOR_BARS[x / (WIDTH / COLOR_BARS.len())]
+}
+
+/// Rounds half to even, which is how the committed frames were first generated. Yellow's U and
+/// cyan's V come out at exactly 0.5.
+fn clamp(c: f64) -> u8 {
+    c.round_ties_even().clamp(0.0, 255.0) as u8
+}
+
+fn yuv([r, g, b]: [u8; 3]) -> [u8; 3] {
+    let (r, g, b) = (f64::from(r), f64::from(g), f64::from(b));
+    [
+        clamp(0.299 * r + 0.587 * g + 0.114 * b),
+        clamp(-0.168_736 * r - 0.331_264 * g + 0.5 * b + 128.0),
+        clamp(0.5 * r - 0.418_688 * g - 0.081_312 * b + 128.0),
+    ]
+}
+
+/// Packs 8-bit channels into `bits` wide fields, red in the high bits, big endian.
+fn rgb16([r, g, b]: [u8; 3], bits: [u32; 3]) -> [u8; 2] {
+    let field = |c: u8, width: u32| u16::from(c) >> (8 - width);
+    let value = (field(r, bits[0]) << (bits[1] + bits[2]))
+        | (field(g, bits[1]) << bits[2])
+        | field(b, bits[2]);
+    value.to_be_bytes()
+}
+
+/// Every pixel of the frame, row by row, encoded by `encode`.
+fn per_pixel<const N: usize>(encode: impl Fn([u8; 3]) -> [u8; N]) -> Vec<u8> {
+    (0..HEIGHT)
+        .flat_map(|_| (0..WIDTH).flat_map(|x| encode(bar(x))))
+        .collect()
+}
+
+fn yuv422() -> Vec<u8> {
+    // Each pair of pixels shares the chroma of its first pixel, as `Y0 U Y1 V`
+    (0..HEIGHT)
+        .flat_map(|_| {
+            (0..WIDTH).step_by(2).flat_map(|x| {
+                let [y0, u, v] = yuv(bar(x));
+                let [y1, _, _] = yuv(bar(x + 1));
+                [y0, u, y1, v]
+            })
+        })
+        .collect()
+}
+
+fn yuv420() -> Vec<u8> {
+    // A full Y plane, then U and V planes at half resolution both ways
+    let mut frame = per_pixel(|c| [yuv(c)[0]]);
+    for channel in [1, 2] {
+        for _ in 0..HEIGHT / 2 {
+            frame.extend((0..WIDTH).step_by(2).map(|x| yuv(bar(x))[channel]));
+        }
+    }
+    frame
+}
+
+fn main() -> std::io::Result<()> {
+    let dir = std::env::args_os()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/frames"));
+    let frames = [
+        ("rgb565", per_pixel(|c| rgb16(c, [5, 6, 5]))),
+        ("rgb555", per_pixel(|c| rgb16(c, [5, 5, 5]))),
+        ("rgb444", per_pixel(|c| rgb16(c, [4, 4, 4]))),
+        ("rgb888", per_pixel(|[r, g, b]| [b, g, r])),
+        ("grayscale", per_pixel(|c| [yuv(c)[0]])),
+        ("yuv422", yuv422()),
+        ("yuv420", yuv420()),
+    ];
+    fs::create_dir_all(&dir)?;
+    for (name, frame) in frames {
+        fs::write(dir.join(format!("colorbar_64x8.{}", name)), frame)?;
+    }
+    Ok(())
+}
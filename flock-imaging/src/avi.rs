@@ -0,0 +1,189 @@
+//! Motion JPEG in AVI, which about every player opens.
+//!
+//! Each frame is a JPEG file stored as is, so frames nodes already encoded don't lose quality on
+//! the way. The writer streams frames to the output and only seeks back at the end to fill in
+//! the sizes and frame count, so clips and time-lapses don't have to fit in memory.
+
+use std::io::{self, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+/// Bytes from the start of the file to the fields `finish` fills in
+const RIFF_SIZE: u64 = 4;
+const TOTAL_FRAMES: u64 = 48;
+const MAX_FRAME_SIZE: u64 = 60;
+const STREAM_LENGTH: u64 = 140;
+const STREAM_BUFFER_SIZE: u64 = 144;
+const MOVI_SIZE: u64 = 216;
+/// Where the offsets in the index count from, the `movi` list type
+const MOVI_START: u64 = 220;
+
+/// Set in the main header when the file has an index
+const AVIF_HASINDEX: u32 = 0x10;
+/// Every JPEG stands on its own
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+pub struct AviWriter<W: Write + Seek> {
+    out: W,
+    /// Offset from `MOVI_START` and size of each frame
+    index: Vec<(u32, u32)>,
+    movi_len: u64,
+    max_frame_size: u32,
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    /// Starts a `width` x `height` video showing each frame for `frame_interval`. `out` should
+    /// be empty.
+    pub fn new(mut out: W, width: u32, height: u32, frame_interval: Duration) -> io::Result<Self> {
+        let micros = (frame_interval.as_micros() as u32).max(1);
+        let mut header = vec![];
+        header.extend(b"RIFF\0\0\0\0AVI LIST");
+        put_u32(&mut header, 4 + 8 + 56 + 8 + 4 + 8 + 56 + 8 + 40);
+        header.extend(b"hdrlavih");
+        put_u32(&mut header, 56);
+        // Frame interval, max bytes per second, padding, flags, total frames, initial frames,
+        // streams, buffer size, dimensions, then 4 reserved
+        let avih = [micros, 0, 0, AVIF_HASINDEX, 0, 0, 1, 0, width, height];
+        for value in avih.into_iter().chain([0; 4]) {
+            put_u32(&mut header, value);
+        }
+        header.extend(b"LIST");
+        put_u32(&mut header, 4 + 8 + 56 + 8 + 40);
+        header.extend(b"strlstrh");
+        put_u32(&mut header, 56);
+        header.extend(b"vidsMJPG");
+        // Flags, priority and language, initial frames, then the rate as 1 frame per `micros`
+        for value in [0, 0, 0, micros, 1_000_000, 0, 0, 0, u32::MAX, 0] {
+            put_u32(&mut header, value);
+        }
+        for value in [0, 0, width as u16, height as u16] {
+            header.extend(value.to_le_bytes());
+        }
+        header.extend(b"strf");
+        put_u32(&mut header, 40);
+        // BITMAPINFOHEADER: size, dimensions, 1 plane of 24 bits, MJPG, image size
+        put_u32(&mut header, 40);
+        put_u32(&mut header, width);
+        put_u32(&mut header, height);
+        header.extend(1u16.to_le_bytes());
+        header.extend(24u16.to_le_bytes());
+        header.extend(b"MJPG");
+        for value in [width * height * 3, 0, 0, 0, 0] {
+            put_u32(&mut header, value);
+        }
+        header.extend(b"LIST\0\0\0\0movi");
+        debug_assert_eq!(header.len() as u64, MOVI_START + 4);
+
+        out.write_all(&header)?;
+        Ok(Self {
+            out,
+            index: vec![],
+            movi_len: 4,
+            max_frame_size: 0,
+        })
+    }
+
+    /// Appends a frame, which has to be a JPEG file of the video's size.
+    pub fn push(&mut self, jpeg: &[u8]) -> io::Result<()> {
+        let size = u32::try_from(jpeg.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+        self.index.push((self.movi_len as u32, size));
+        self.out.write_all(b"00dc")?;
+        self.out.write_all(&size.to_le_bytes())?;
+        self.out.write_all(jpeg)?;
+        // Chunks start on even offsets
+        let padding = jpeg.len() % 2;
+        self.out.write_all(&[0][..padding])?;
+        self.movi_len += 8 + jpeg.len() as u64 + padding as u64;
+        if self.movi_len > u64::from(u32::MAX) / 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "AVI files without extensions end at 2 GB",
+            ));
+        }
+        self.max_frame_size = self.max_frame_size.max(size);
+        Ok(())
+    }
+
+    pub fn frames(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Writes the index and fills in the headers.
+    pub fn finish(mut self) -> io::Result<W> {
+        let mut idx1 = b"idx1".to_vec();
+        put_u32(&mut idx1, self.index.len() as u32 * 16);
+        for &(offset, size) in &self.index {
+            idx1.extend(b"00dc");
+            put_u32(&mut idx1, AVIIF_KEYFRAME);
+            put_u32(&mut idx1, offset);
+            put_u32(&mut idx1, size);
+        }
+        self.out.write_all(&idx1)?;
+
+        let riff_size = MOVI_START + self.movi_len + idx1.len() as u64 - 8;
+        let frames = self.index.len() as u32;
+        for (at, value) in [
+            (RIFF_SIZE, riff_size as u32),
+            (TOTAL_FRAMES, frames),
+            (MAX_FRAME_SIZE, self.max_frame_size),
+            (STREAM_LENGTH, frames),
+            (STREAM_BUFFER_SIZE, self.max_frame_size),
+            (MOVI_SIZE, self.movi_len as u32),
+        ] {
+            self.out.seek(SeekFrom::Start(at))?;
+            self.out.write_all(&value.to_le_bytes())?;
+        }
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend(value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+    use std::io::Cursor;
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn frames_are_indexed() {
+        let frames: Vec<Vec<u8>> = [0u8, 128, 255]
+            .iter()
+            .map(|&level| {
+                let img = RgbImage::from_pixel(16, 8, image::Rgb([level, level, level]));
+                crate::to_jpeg(&img, 80).unwrap()
+            })
+            .collect();
+        let mut avi =
+            AviWriter::new(Cursor::new(vec![]), 16, 8, Duration::from_millis(200)).unwrap();
+        for frame in &frames {
+            avi.push(frame).unwrap();
+        }
+        let data = avi.finish().unwrap().into_inner();
+
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+        assert_eq!(u32_at(&data, 32), 200_000);
+        assert_eq!(u32_at(&data, TOTAL_FRAMES as usize), 3);
+        assert_eq!((u32_at(&data, 64), u32_at(&data, 68)), (16, 8));
+        assert_eq!(&data[MOVI_START as usize..][..4], b"movi");
+
+        let movi_end = MOVI_START as usize + u32_at(&data, MOVI_SIZE as usize) as usize;
+        assert_eq!(&data[movi_end..][..4], b"idx1");
+        for (i, frame) in frames.iter().enumerate() {
+            let entry = movi_end + 8 + i * 16;
+            let chunk = MOVI_START as usize + u32_at(&data, entry + 8) as usize;
+            let size = u32_at(&data, entry + 12) as usize;
+            assert_eq!(&data[chunk..][..4], b"00dc");
+            assert_eq!(&data[chunk + 8..][..size], &frame[..]);
+        }
+    }
+}
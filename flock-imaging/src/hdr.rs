@@ -0,0 +1,395 @@
+//! Combines the shots of a burst.
+//!
+//! Brackets are merged with exposure fusion (Mertens, Kautz and Van Reeth, 2007): every pixel of
+//! every shot is weighted by how much detail it shows (contrast), how colourful it is
+//! (saturation) and how far it is from under or over exposure, and the shots are blended in a
+//! Laplacian pyramid so the weights don't leave seams. Unlike merging to a radiance map, this
+//! needs neither exposure times nor the sensor's response curve, which nodes don't report, and
+//! its result is already tone mapped.
+
+use crate::Error;
+use image::{Rgb, RgbImage};
+
+/// Exponents of the three quality measures. 0 leaves a measure out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FusionWeights {
+    pub contrast: f32,
+    /// Leave out (set to 0) for grayscale frames, where it's 0 everywhere
+    pub saturation: f32,
+    pub exposedness: f32,
+}
+
+impl Default for FusionWeights {
+    /// The paper's
+    fn default() -> Self {
+        Self {
+            contrast: 1.0,
+            saturation: 1.0,
+            exposedness: 1.0,
+        }
+    }
+}
+
+/// Spread of the well-exposedness curve around mid gray
+const EXPOSEDNESS_SIGMA: f32 = 0.2;
+/// Pyramids stop above this size
+const MIN_LEVEL_SIZE: u32 = 8;
+
+/// Merges differently exposed shots of the same scene into one image.
+pub fn fuse(shots: &[RgbImage]) -> Result<RgbImage, Error> {
+    fuse_with(shots, &FusionWeights::default())
+}
+
+pub fn fuse_with(shots: &[RgbImage], weights: &FusionWeights) -> Result<RgbImage, Error> {
+    let (width, height) = same_size(shots)?;
+    let images: Vec<Buffer> = shots.iter().map(Buffer::from_image).collect();
+    let mut maps: Vec<Buffer> = images.iter().map(|img| weight_map(img, weights)).collect();
+    // Normalize, so the weights of each pixel sum to 1
+    for i in 0..(width * height) as usize {
+        let total: f32 = maps.iter().map(|m| m.data[i]).sum();
+        maps.iter_mut().for_each(|m| m.data[i] /= total);
+    }
+
+    let levels = levels(width, height);
+    let mut blended: Option<Vec<Buffer>> = None;
+    for (img, map) in images.iter().zip(&maps) {
+        let laplacian = laplacian_pyramid(img, levels);
+        let gaussian = gaussian_pyramid(map, levels);
+        let weighted = laplacian.iter().zip(&gaussian).map(|(l, g)| l.scaled_by(g));
+        blended = Some(match blended {
+            None => weighted.collect(),
+            Some(sum) => sum.iter().zip(weighted).map(|(a, b)| a.add(&b)).collect(),
+        });
+    }
+    Ok(collapse(blended.expect("at least one shot")).to_image())
+}
+
+/// Index of the sharpest shot, by the variance of its Laplacian, or `None` if there are none.
+pub fn sharpest(shots: &[RgbImage]) -> Option<usize> {
+    let sharpness = |img: &RgbImage| {
+        let laplacian = laplacian_of(&Buffer::from_image(img).luma());
+        let n = laplacian.data.len() as f32;
+        let mean = laplacian.data.iter().sum::<f32>() / n;
+        laplacian
+            .data
+            .iter()
+            .map(|v| (v - mean).powi(2))
+            .sum::<f32>()
+            / n
+    };
+    shots
+        .iter()
+        .map(sharpness)
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+}
+
+fn same_size(shots: &[RgbImage]) -> Result<(u32, u32), Error> {
+    let dimensions = shots.first().ok_or(Error::Mismatch)?.dimensions();
+    if shots.iter().any(|s| s.dimensions() != dimensions) {
+        return Err(Error::Mismatch);
+    }
+    Ok(dimensions)
+}
+
+fn levels(width: u32, height: u32) -> usize {
+    let mut levels = 1;
+    let mut size = width.min(height);
+    while size / 2 >= MIN_LEVEL_SIZE {
+        size = size.div_ceil(2);
+        levels += 1;
+    }
+    levels
+}
+
+fn weight_map(img: &Buffer, weights: &FusionWeights) -> Buffer {
+    let contrast = laplacian_of(&img.luma());
+    let data = img
+        .data
+        .chunks_exact(3)
+        .zip(&contrast.data)
+        .map(|(px, c)| {
+            let mean = px.iter().sum::<f32>() / 3.0;
+            let saturation = (px.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / 3.0).sqrt();
+            let exposedness: f32 = px
+                .iter()
+                .map(|v| (-(v - 0.5).powi(2) / (2.0 * EXPOSEDNESS_SIGMA.powi(2))).exp())
+                .product();
+            c.abs().powf(weights.contrast)
+                * saturation.powf(weights.saturation)
+                * exposedness.powf(weights.exposedness)
+                // Keeps flat, gray areas from having no weight in any shot
+                + 1e-12
+        })
+        .collect();
+    Buffer {
+        data,
+        channels: 1,
+        ..*img
+    }
+}
+
+fn gaussian_pyramid(img: &Buffer, levels: usize) -> Vec<Buffer> {
+    let mut pyramid = vec![img.clone()];
+    while pyramid.len() < levels {
+        let next = pyramid.last().unwrap().downsample();
+        pyramid.push(next);
+    }
+    pyramid
+}
+
+fn laplacian_pyramid(img: &Buffer, levels: usize) -> Vec<Buffer> {
+    let gaussian = gaussian_pyramid(img, levels);
+    let mut pyramid: Vec<Buffer> = gaussian
+        .windows(2)
+        .map(|pair| pair[0].sub(&pair[1].upsample(pair[0].width, pair[0].height)))
+        .collect();
+    pyramid.push(gaussian.last().unwrap().clone());
+    pyramid
+}
+
+fn collapse(mut pyramid: Vec<Buffer>) -> Buffer {
+    let mut img = pyramid.pop().unwrap();
+    while let Some(level) = pyramid.pop() {
+        img = level.add(&img.upsample(level.width, level.height));
+    }
+    img
+}
+
+/// 4-neighbour Laplacian of a single channel, with edges repeated.
+fn laplacian_of(plane: &Buffer) -> Buffer {
+    let data = (0..plane.height)
+        .flat_map(|y| (0..plane.width).map(move |x| (x as i64, y as i64)))
+        .map(|(x, y)| {
+            let at = |dx, dy| plane.at(x + dx, y + dy, 0);
+            at(-1, 0) + at(1, 0) + at(0, -1) + at(0, 1) - 4.0 * at(0, 0)
+        })
+        .collect();
+    Buffer {
+        data,
+        ..plane.clone()
+    }
+}
+
+/// Interleaved channels, from 0 to 1.
+#[derive(Debug, Clone)]
+struct Buffer {
+    width: u32,
+    height: u32,
+    channels: usize,
+    data: Vec<f32>,
+}
+
+impl Buffer {
+    fn from_image(img: &RgbImage) -> Self {
+        Self {
+            width: img.width(),
+            height: img.height(),
+            channels: 3,
+            data: img.as_raw().iter().map(|&v| f32::from(v) / 255.0).collect(),
+        }
+    }
+
+    fn to_image(&self) -> RgbImage {
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            let c = |i| {
+                (self.at(x.into(), y.into(), i) * 255.0)
+                    .round()
+                    .clamp(0.0, 255.0) as u8
+            };
+            Rgb([c(0), c(1), c(2)])
+        })
+    }
+
+    fn luma(&self) -> Buffer {
+        let data = self
+            .data
+            .chunks_exact(3)
+            .map(|px| 0.299 * px[0] + 0.587 * px[1] + 0.114 * px[2])
+            .collect();
+        Buffer {
+            data,
+            channels: 1,
+            ..*self
+        }
+    }
+
+    /// Channel `c` at `x`, `y`, clamped to the edges.
+    fn at(&self, x: i64, y: i64, c: usize) -> f32 {
+        let x = x.clamp(0, i64::from(self.width) - 1) as usize;
+        let y = y.clamp(0, i64::from(self.height) - 1) as usize;
+        self.data[(y * self.width as usize + x) * self.channels + c]
+    }
+
+    fn zip_with(&self, other: &Buffer, f: impl Fn(f32, f32) -> f32) -> Buffer {
+        Buffer {
+            data: self
+                .data
+                .iter()
+                .zip(&other.data)
+                .map(|(a, b)| f(*a, *b))
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    fn add(&self, other: &Buffer) -> Buffer {
+        self.zip_with(other, |a, b| a + b)
+    }
+
+    fn sub(&self, other: &Buffer) -> Buffer {
+        self.zip_with(other, |a, b| a - b)
+    }
+
+    /// Multiplies every channel by the matching pixel of the single channel `weights`.
+    fn scaled_by(&self, weights: &Buffer) -> Buffer {
+        let data = self
+            .data
+            .chunks_exact(self.channels)
+            .zip(&weights.data)
+            .flat_map(|(px, w)| px.iter().map(move |v| v * w))
+            .collect();
+        Buffer {
+            data,
+            ..self.clone()
+        }
+    }
+
+    /// Blurs with the 5-tap binomial kernel and halves both ways.
+    fn downsample(&self) -> Buffer {
+        const KERNEL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+        let (width, height) = (self.width.div_ceil(2), self.height.div_ceil(2));
+        let mut data = Vec::with_capacity((width * height) as usize * self.channels);
+        for y in 0..i64::from(height) {
+            for x in 0..i64::from(width) {
+                for c in 0..self.channels {
+                    let mut sum = 0.0;
+                    for (j, ky) in KERNEL.iter().enumerate() {
+                        for (i, kx) in KERNEL.iter().enumerate() {
+                            let (sx, sy) = (2 * x + i as i64 - 2, 2 * y + j as i64 - 2);
+                            sum += kx * ky * self.at(sx, sy, c);
+                        }
+                    }
+                    data.push(sum);
+                }
+            }
+        }
+        Buffer {
+            width,
+            height,
+            channels: self.channels,
+            data,
+        }
+    }
+
+    /// Bilinear upscale to `width` x `height`.
+    fn upsample(&self, width: u32, height: u32) -> Buffer {
+        let mut data = Vec::with_capacity((width * height) as usize * self.channels);
+        for y in 0..height {
+            let sy = (y as f32 + 0.5) / 2.0 - 0.5;
+            let (y0, fy) = (sy.floor(), sy - sy.floor());
+            for x in 0..width {
+                let sx = (x as f32 + 0.5) / 2.0 - 0.5;
+                let (x0, fx) = (sx.floor(), sx - sx.floor());
+                let (x0, y0) = (x0 as i64, y0 as i64);
+                for c in 0..self.channels {
+                    let top = self.at(x0, y0, c) * (1.0 - fx) + self.at(x0 + 1, y0, c) * fx;
+                    let bottom =
+                        self.at(x0, y0 + 1, c) * (1.0 - fx) + self.at(x0 + 1, y0 + 1, c) * fx;
+                    data.push(top * (1.0 - fy) + bottom * fy);
+                }
+            }
+        }
+        Buffer {
+            width,
+            height,
+            channels: self.channels,
+            data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A colourful checkerboard scaled by `exposure`, clipped like a sensor would.
+    fn scene(exposure: f32) -> RgbImage {
+        RgbImage::from_fn(64, 48, |x, y| {
+            let base = if (x / 4 + y / 4) % 2 == 0 {
+                [0.8, 0.3, 0.2]
+            } else {
+                [0.2, 0.5, 0.7]
+            };
+            // Brighter towards the right, more than one exposure can hold
+            let light = 0.2 + 2.0 * x as f32 / 64.0;
+            Rgb(base.map(|v| (v * light * exposure * 255.0).round().min(255.0) as u8))
+        })
+    }
+
+    /// Mean absolute difference between horizontally neighbouring pixels in columns `xs`.
+    fn detail(img: &RgbImage, xs: std::ops::Range<u32>) -> f32 {
+        let mut sum = 0.0;
+        let mut n = 0.0;
+        for y in 0..img.height() {
+            for x in xs.clone() {
+                let (a, b) = (img.get_pixel(x, y).0, img.get_pixel(x + 1, y).0);
+                sum += a
+                    .iter()
+                    .zip(b)
+                    .map(|(a, b)| a.abs_diff(b) as f32)
+                    .sum::<f32>();
+                n += 1.0;
+            }
+        }
+        sum / n
+    }
+
+    #[test]
+    fn reconstructs_a_single_shot() {
+        let img = scene(1.0);
+        let fused = fuse(std::slice::from_ref(&img)).unwrap();
+        let off = img
+            .as_raw()
+            .iter()
+            .zip(fused.as_raw())
+            .any(|(a, b)| a.abs_diff(*b) > 1);
+        assert!(!off);
+    }
+
+    #[test]
+    fn keeps_detail_from_each_bracket() {
+        let (dark, bright) = (scene(0.4), scene(1.2));
+        let fused = fuse(&[dark.clone(), bright.clone()]).unwrap();
+        // The bright shot clips on the right, the dark one is murky on the left. Either way the
+        // fused image keeps (nearly) the detail of the better one.
+        for xs in [0..16, 47..63] {
+            let best = detail(&dark, xs.clone()).max(detail(&bright, xs.clone()));
+            let worst = detail(&dark, xs.clone()).min(detail(&bright, xs.clone()));
+            let fused = detail(&fused, xs);
+            assert!(
+                fused > best * 0.9 && fused > worst * 1.2,
+                "{} {} {}",
+                fused,
+                best,
+                worst
+            );
+        }
+    }
+
+    #[test]
+    fn picks_the_sharpest_shot() {
+        let sharp = scene(1.0);
+        let blurry = image::imageops::blur(&sharp, 2.0);
+        assert_eq!(sharpest(&[blurry.clone(), sharp, blurry]), Some(1));
+        assert_eq!(sharpest(&[]), None);
+    }
+
+    #[test]
+    fn shots_must_match() {
+        assert!(matches!(fuse(&[]), Err(Error::Mismatch)));
+        let small = RgbImage::new(8, 8);
+        assert!(matches!(fuse(&[scene(1.0), small]), Err(Error::Mismatch)));
+    }
+}
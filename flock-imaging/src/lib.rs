@@ -0,0 +1,332 @@
+//! Turns frames from camera nodes into images.
+//!
+//! Nodes send frames exactly as esp32-camera hands them over, in the `PixelFormat` the camera was
+//! initialized with. Byte order per format:
+//!
+//! - `Rgb565`: 2 bytes per pixel, big endian, `RRRRRGGG GGGBBBBB`
+//! - `Rgb555`: 2 bytes per pixel, big endian, `xRRRRRGG GGGBBBBB`
+//! - `Rgb444`: 2 bytes per pixel, big endian, `xxxxRRRR GGGGBBBB`
+//! - `Rgb888`: 3 bytes per pixel in B, G, R order
+//! - `Grayscale`: 1 byte per pixel, the luma (Y) channel
+//! - `Yuv422`: 4 bytes per 2 pixels, `Y0 U Y1 V`
+//! - `Yuv420`: 1.5 bytes per pixel, planar: a full resolution Y plane followed by U and V planes at
+//!   half resolution both ways. esp32-camera has no converter for this format to check against.
+//! - `Jpeg`: a JPEG file, the frame's width and height are ignored
+//!
+//! `Raw` (Bayer data) depends on the sensor and isn't supported. YUV uses the full-range JFIF
+//! equations, like esp32-camera's converters. Channels narrower than 8 bits are scaled up so
+//! that their maximum maps to 255.
+
+use flock_api::{FrameFormat, PixelFormat, SensorData};
+use image::{ImageOutputFormat, RgbImage};
+use std::fmt;
+use std::io::Cursor;
+use std::path::Path;
+
+pub use image;
+
+pub mod avi;
+pub mod hdr;
+pub mod metadata;
+pub mod motion;
+pub mod timelapse;
+
+#[derive(Debug)]
+pub enum Error {
+    Unsupported(PixelFormat),
+    /// The frame is shorter or longer than its format says
+    Length {
+        expected: usize,
+        actual: usize,
+    },
+    Image(image::ImageError),
+    Io(std::io::Error),
+    /// Images to combine are missing or differ in size
+    Mismatch,
+    /// The file isn't a well-formed JPEG or PNG, or its metadata is broken
+    Malformed(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unsupported(format) => write!(f, "can't decode {:?} frames", format),
+            Error::Length { expected, actual } => write!(
+                f,
+                "frame is {} bytes, expected {} for its format",
+                actual, expected
+            ),
+            Error::Image(err) => write!(f, "image error: {}", err),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Mismatch => write!(f, "images are missing or differ in size"),
+            Error::Malformed(problem) => write!(f, "malformed file: {}", problem),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<image::ImageError> for Error {
+    fn from(err: image::ImageError) -> Self {
+        Error::Image(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// Size in bytes of an uncompressed frame, or `None` for formats without a fixed size and
+/// frames too large to address.
+pub fn frame_len(format: &FrameFormat) -> Option<usize> {
+    let (w, h) = (format.width as usize, format.height as usize);
+    let pixels = w.checked_mul(h)?;
+    match format.pixel_format {
+        PixelFormat::Grayscale => Some(pixels),
+        PixelFormat::Rgb565 | PixelFormat::Rgb555 | PixelFormat::Rgb444 => pixels.checked_mul(2),
+        PixelFormat::Rgb888 => pixels.checked_mul(3),
+        PixelFormat::Yuv422 => w.div_ceil(2).checked_mul(h)?.checked_mul(4),
+        PixelFormat::Yuv420 => w
+            .div_ceil(2)
+            .checked_mul(h.div_ceil(2))?
+            .checked_mul(2)?
+            .checked_add(pixels),
+        PixelFormat::Jpeg | PixelFormat::Raw => None,
+    }
+}
+
+pub fn decode(data: &[u8], format: &FrameFormat) -> Result<RgbImage, Error> {
+    match format.pixel_format {
+        PixelFormat::Jpeg => {
+            return Ok(
+                image::load_from_memory_with_format(data, image::ImageFormat::Jpeg)?.to_rgb8(),
+            )
+        }
+        PixelFormat::Raw => return Err(Error::Unsupported(format.pixel_format)),
+        _ => {}
+    }
+    if format.width == 0 || format.height == 0 {
+        return Err(Error::Malformed("frame has no pixels"));
+    }
+    let expected = frame_len(format).ok_or(Error::Malformed("frame is too large"))?;
+    if data.len() != expected {
+        return Err(Error::Length {
+            expected,
+            actual: data.len(),
+        });
+    }
+
+    let (width, height) = (format.width, format.height);
+    let rgb: Vec<u8> = match format.pixel_format {
+        PixelFormat::Rgb565 => data
+            .chunks_exact(2)
+            .flat_map(|p| {
+                let px = u16::from_be_bytes([p[0], p[1]]);
+                [scale(px >> 11, 5), scale(px >> 5, 6), scale(px, 5)]
+            })
+            .collect(),
+        PixelFormat::Rgb555 => data
+            .chunks_exact(2)
+            .flat_map(|p| {
+                let px = u16::from_be_bytes([p[0], p[1]]);
+                [scale(px >> 10, 5), scale(px >> 5, 5), scale(px, 5)]
+            })
+            .collect(),
+        PixelFormat::Rgb444 => data
+            .chunks_exact(2)
+            .flat_map(|p| {
+                let px = u16::from_be_bytes([p[0], p[1]]);
+                [scale(px >> 8, 4), scale(px >> 4, 4), scale(px, 4)]
+            })
+            .collect(),
+        PixelFormat::Rgb888 => data
+            .chunks_exact(3)
+            .flat_map(|p| [p[2], p[1], p[0]])
+            .collect(),
+        PixelFormat::Grayscale => data.iter().flat_map(|&y| [y, y, y]).collect(),
+        PixelFormat::Yuv422 => {
+            let row_len = width.div_ceil(2) as usize * 4;
+            data.chunks_exact(row_len)
+                .flat_map(|row| {
+                    row.chunks_exact(4)
+                        .flat_map(|p| [yuv_to_rgb(p[0], p[1], p[3]), yuv_to_rgb(p[2], p[1], p[3])])
+                        // Odd widths pad the last pair
+                        .take(width as usize)
+                        .flatten()
+                })
+                .collect()
+        }
+        PixelFormat::Yuv420 => {
+            let (w, h) = (width as usize, height as usize);
+            let chroma_w = w.div_ceil(2);
+            let (luma, chroma) = data.split_at(w * h);
+            let (u, v) = chroma.split_at(chroma.len() / 2);
+            (0..h)
+                .flat_map(|y| (0..w).map(move |x| (x, y)))
+                .flat_map(|(x, y)| {
+                    let c = y / 2 * chroma_w + x / 2;
+                    yuv_to_rgb(luma[y * w + x], u[c], v[c])
+                })
+                .collect()
+        }
+        PixelFormat::Jpeg | PixelFormat::Raw => unreachable!("no fixed frame length"),
+    };
+    Ok(RgbImage::from_raw(width, height, rgb).expect("length checked above"))
+}
+
+/// Decodes a reading from any node. Readings from nodes that predate frame descriptions come
+/// with the default format, which is what those nodes sent.
+pub fn decode_reading(reading: &SensorData) -> Result<RgbImage, Error> {
+    match reading {
+        SensorData::Camera {
+            frame_buffer,
+            format,
+        } => decode(frame_buffer, format),
+    }
+}
+
+pub fn to_png(img: &RgbImage) -> Result<Vec<u8>, Error> {
+    let mut out = Cursor::new(vec![]);
+    img.write_to(&mut out, ImageOutputFormat::Png)?;
+    Ok(out.into_inner())
+}
+
+/// `quality` goes from 1 to 100.
+pub fn to_jpeg(img: &RgbImage, quality: u8) -> Result<Vec<u8>, Error> {
+    let mut out = Cursor::new(vec![]);
+    img.write_to(&mut out, ImageOutputFormat::Jpeg(quality))?;
+    Ok(out.into_inner())
+}
+
+/// A reading as a JPEG file, and its width and height. JPEG frames are passed through as they
+/// are, others encoded with `quality`.
+pub fn reading_to_jpeg(reading: &SensorData, quality: u8) -> Result<(Vec<u8>, (u32, u32)), Error> {
+    let SensorData::Camera {
+        frame_buffer,
+        format,
+    } = reading;
+    if let PixelFormat::Jpeg = format.pixel_format {
+        let size =
+            image::io::Reader::with_format(Cursor::new(frame_buffer), image::ImageFormat::Jpeg)
+                .into_dimensions()?;
+        return Ok((frame_buffer.clone(), size));
+    }
+    let img = decode(frame_buffer, format)?;
+    Ok((to_jpeg(&img, quality)?, img.dimensions()))
+}
+
+/// Saves in the format the file extension asks for, like `image` does.
+pub fn save(img: &RgbImage, path: &Path) -> Result<(), Error> {
+    Ok(img.save(path)?)
+}
+
+/// Turns a client ID into a single path component. Client IDs are free-form, so this keeps them
+/// from reaching outside the directory frames are saved in.
+pub fn file_name(device: &str) -> String {
+    device
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Widens a `bits` wide channel in the low bits of `value` to 8 bits.
+fn scale(value: u16, bits: u32) -> u8 {
+    let max = (1 << bits) - 1;
+    ((u32::from(value) & max) * 255 / max) as u8
+}
+
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let (y, u, v) = (f32::from(y), f32::from(u) - 128.0, f32::from(v) - 128.0);
+    let clamp = |c: f32| c.round().clamp(0.0, 255.0) as u8;
+    [
+        clamp(y + 1.402 * v),
+        clamp(y - 0.344_136 * u - 0.714_136 * v),
+        clamp(y + 1.772 * u),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(pixel_format: PixelFormat, width: u32, height: u32) -> FrameFormat {
+        FrameFormat {
+            pixel_format,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn narrow_channels_span_the_full_range() {
+        let img = decode(&[0xFF, 0xFF], &format(PixelFormat::Rgb565, 1, 1)).unwrap();
+        assert_eq!(img.get_pixel(0, 0).0, [255, 255, 255]);
+        let img = decode(&[0x0F, 0x00], &format(PixelFormat::Rgb444, 1, 1)).unwrap();
+        assert_eq!(img.get_pixel(0, 0).0, [255, 0, 0]);
+    }
+
+    #[test]
+    fn wrong_lengths_are_rejected() {
+        let err = decode(&[0; 10], &format(PixelFormat::Rgb888, 2, 2)).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Length {
+                expected: 12,
+                actual: 10
+            }
+        ));
+        assert!(matches!(
+            decode(&[0; 4], &format(PixelFormat::Raw, 2, 2)),
+            Err(Error::Unsupported(PixelFormat::Raw))
+        ));
+    }
+
+    #[test]
+    fn empty_and_oversized_frames_are_rejected() {
+        assert!(matches!(
+            decode(&[], &format(PixelFormat::Yuv422, 0, 4)),
+            Err(Error::Malformed(_))
+        ));
+        assert_eq!(
+            frame_len(&format(PixelFormat::Rgb888, u32::MAX, u32::MAX)),
+            None
+        );
+        assert!(matches!(
+            decode(&[0; 12], &format(PixelFormat::Rgb888, u32::MAX, u32::MAX)),
+            Err(Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn odd_sizes_decode() {
+        // 3x3 YUV422 pads each row to 2 pairs, YUV420 has 2x2 chroma planes
+        let img = decode(&[128; 24], &format(PixelFormat::Yuv422, 3, 3)).unwrap();
+        assert_eq!(img.dimensions(), (3, 3));
+        let img = decode(&[128; 17], &format(PixelFormat::Yuv420, 3, 3)).unwrap();
+        assert_eq!(img.get_pixel(2, 2).0, [128, 128, 128]);
+    }
+
+    #[test]
+    fn exports_round_trip() {
+        let img = RgbImage::from_fn(16, 16, |x, y| image::Rgb([x as u8 * 16, y as u8 * 16, 0]));
+        let png = image::load_from_memory(&to_png(&img).unwrap()).unwrap();
+        assert_eq!(png.to_rgb8(), img);
+
+        let jpeg = to_jpeg(&img, 90).unwrap();
+        let decoded = decode(&jpeg, &format(PixelFormat::Jpeg, 0, 0)).unwrap();
+        assert_eq!(decoded.dimensions(), (16, 16));
+    }
+
+    #[test]
+    fn device_names_stay_in_the_directory() {
+        assert_eq!(file_name("../cam 1/x"), "___cam_1_x");
+    }
+}
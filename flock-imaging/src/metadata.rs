@@ -0,0 +1,622 @@
+//! Which node took a frame, when and with which settings, embedded in the saved image.
+//!
+//! JPEGs get an EXIF segment: the sensor model as `Model`, the capture time as `DateTime`,
+//! `DateTimeOriginal` and `SubSecTimeOriginal` in UTC, the client ID as `BodySerialNumber`, and
+//! all of `CaptureMetadata` as JSON in `UserComment`, which is what `read` goes by. PNGs get
+//! `tEXt` chunks: `Flock Client ID`, `Flock Captured At` in milliseconds since the Unix epoch, and
+//! `Flock Sensor` and `Flock Sensor Config` as JSON, next to the standard `Creation Time` and
+//! `Source`. PNG text is Latin-1, other characters in client IDs are saved as `?`.
+//!
+//! Embedding replaces metadata embedded before and leaves everything else, including the image
+//! data, byte for byte as it was.
+
+use crate::timelapse::utc;
+use crate::Error;
+use flock_api::{SensorConfig, SensorInfo};
+use image::{ImageFormat, RgbImage};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+const JPEG_APP0: u8 = 0xE0;
+const JPEG_APP1: u8 = 0xE1;
+/// Start of scan, the entropy-coded image data follows
+const JPEG_SOS: u8 = 0xDA;
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// What `image` saves JPEGs with, to keep saving with metadata the same as without
+const JPEG_QUALITY: u8 = 75;
+
+const TAG_MODEL: u16 = 0x0110;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+const TAG_USER_COMMENT: u16 = 0x9286;
+const TAG_SUB_SEC_TIME_ORIGINAL: u16 = 0x9291;
+const TAG_BODY_SERIAL_NUMBER: u16 = 0xA431;
+const TYPE_ASCII: u16 = 2;
+const TYPE_LONG: u16 = 4;
+const TYPE_UNDEFINED: u16 = 7;
+/// Character code `UserComment` starts with
+const USER_COMMENT_ASCII: &[u8] = b"ASCII\0\0\0";
+
+const KEY_CLIENT_ID: &str = "Flock Client ID";
+const KEY_CAPTURED_AT: &str = "Flock Captured At";
+const KEY_SENSOR: &str = "Flock Sensor";
+const KEY_SENSOR_CONFIG: &str = "Flock Sensor Config";
+const KEY_CREATION_TIME: &str = "Creation Time";
+const KEY_SOURCE: &str = "Source";
+/// Keywords `embed` writes, and replaces when they're already there
+const KEYS: [&str; 6] = [
+    KEY_CLIENT_ID,
+    KEY_CAPTURED_AT,
+    KEY_SENSOR,
+    KEY_SENSOR_CONFIG,
+    KEY_CREATION_TIME,
+    KEY_SOURCE,
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CaptureMetadata {
+    pub client_id: String,
+    /// Milliseconds since the Unix epoch
+    pub captured_at: u64,
+    /// The node's sensor, if it said
+    #[serde(default)]
+    pub sensor: Option<SensorInfo>,
+    /// The node's sensor config at the time, if it's known
+    #[serde(default)]
+    pub sensor_config: Option<SensorConfig>,
+}
+
+/// Adds `metadata` to a JPEG or PNG file.
+pub fn embed(file: &[u8], metadata: &CaptureMetadata) -> Result<Vec<u8>, Error> {
+    if file.starts_with(&JPEG_SOI) {
+        embed_exif(file, metadata)
+    } else if file.starts_with(PNG_SIGNATURE) {
+        embed_text(file, metadata)
+    } else {
+        Err(Error::Malformed("not a JPEG or PNG file"))
+    }
+}
+
+/// The metadata `embed` added to a JPEG or PNG file, `None` if there is none.
+pub fn read(file: &[u8]) -> Result<Option<CaptureMetadata>, Error> {
+    if file.starts_with(&JPEG_SOI) {
+        read_exif(file)
+    } else if file.starts_with(PNG_SIGNATURE) {
+        read_text(file)
+    } else {
+        Err(Error::Malformed("not a JPEG or PNG file"))
+    }
+}
+
+pub fn read_file(path: &Path) -> Result<Option<CaptureMetadata>, Error> {
+    read(&fs::read(path)?)
+}
+
+/// Saves `img` like `crate::save`, with `metadata` if the extension is .jpg or .png.
+pub fn save(img: &RgbImage, path: &Path, metadata: &CaptureMetadata) -> Result<(), Error> {
+    let data = match ImageFormat::from_path(path)? {
+        ImageFormat::Png => crate::to_png(img)?,
+        ImageFormat::Jpeg => crate::to_jpeg(img, JPEG_QUALITY)?,
+        // Other formats are saved without
+        _ => return crate::save(img, path),
+    };
+    fs::write(path, embed(&data, metadata)?)?;
+    Ok(())
+}
+
+/// JSON with everything beyond ASCII escaped, for EXIF's ASCII strings.
+fn ascii_json<T: Serialize>(value: &T) -> String {
+    let json = serde_json::to_string(value).expect("metadata always serializes");
+    let mut ascii = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() {
+            ascii.push(c);
+        } else {
+            // Only strings hold other characters, and JSON strings take UTF-16 escapes
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                ascii += &format!("\\u{:04x}", unit);
+            }
+        }
+    }
+    ascii
+}
+
+/// A JPEG segment's marker and contents
+type Segment<'a> = (u8, &'a [u8]);
+/// A PNG chunk's type and data
+type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+/// JPEG segments before the image data, and the rest of the file.
+fn jpeg_segments(file: &[u8]) -> Result<(Vec<Segment<'_>>, &[u8]), Error> {
+    let mut segments = vec![];
+    let mut at = JPEG_SOI.len();
+    loop {
+        let header = file
+            .get(at..at + 4)
+            .ok_or(Error::Malformed("JPEG ends before its image data"))?;
+        if header[0] != 0xFF {
+            return Err(Error::Malformed("JPEG segment without a marker"));
+        }
+        let marker = header[1];
+        let len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+        let contents = file
+            .get(at + 4..at + 2 + len)
+            .filter(|_| len >= 2)
+            .ok_or(Error::Malformed("JPEG segment is cut off"))?;
+        if marker == JPEG_SOS {
+            return Ok((segments, &file[at..]));
+        }
+        segments.push((marker, contents));
+        at += 2 + len;
+    }
+}
+
+struct IfdEntry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    data: Vec<u8>,
+}
+
+impl IfdEntry {
+    fn ascii(tag: u16, text: &str) -> Self {
+        let mut data: Vec<u8> = text
+            .chars()
+            .map(|c| if c.is_ascii() { c as u8 } else { b'?' })
+            .collect();
+        data.push(0);
+        Self {
+            tag,
+            kind: TYPE_ASCII,
+            count: data.len() as u32,
+            data,
+        }
+    }
+}
+
+/// Bytes an IFD with `entries` takes up, values included.
+fn ifd_len(entries: &[IfdEntry]) -> usize {
+    let values: usize = entries
+        .iter()
+        .filter(|entry| entry.data.len() > 4)
+        .map(|entry| entry.data.len() + entry.data.len() % 2)
+        .sum();
+    2 + entries.len() * 12 + 4 + values
+}
+
+/// Appends an IFD at `out.len()`, which is its offset in the little-endian TIFF data.
+fn put_ifd(out: &mut Vec<u8>, entries: &[IfdEntry]) {
+    let mut values_at = out.len() + 2 + entries.len() * 12 + 4;
+    let mut values = vec![];
+    out.extend((entries.len() as u16).to_le_bytes());
+    for entry in entries {
+        out.extend(entry.tag.to_le_bytes());
+        out.extend(entry.kind.to_le_bytes());
+        out.extend(entry.count.to_le_bytes());
+        if entry.data.len() <= 4 {
+            let mut inline = [0; 4];
+            inline[..entry.data.len()].copy_from_slice(&entry.data);
+            out.extend(inline);
+        } else {
+            out.extend((values_at as u32).to_le_bytes());
+            values.extend(&entry.data);
+            // Values start on even offsets
+            if entry.data.len() % 2 == 1 {
+                values.push(0);
+            }
+            values_at += entry.data.len() + entry.data.len() % 2;
+        }
+    }
+    // No next IFD
+    out.extend(0u32.to_le_bytes());
+    out.extend(values);
+}
+
+fn exif(metadata: &CaptureMetadata) -> Vec<u8> {
+    let secs = metadata.captured_at / 1000;
+    // EXIF dates are "YYYY:MM:DD HH:MM:SS"
+    let date_time = utc(secs).replacen('-', ":", 2);
+    let mut comment = USER_COMMENT_ASCII.to_vec();
+    comment.extend(ascii_json(metadata).bytes());
+    let exif_entries = [
+        IfdEntry::ascii(TAG_DATE_TIME_ORIGINAL, &date_time),
+        IfdEntry::ascii(TAG_OFFSET_TIME_ORIGINAL, "+00:00"),
+        IfdEntry {
+            tag: TAG_USER_COMMENT,
+            kind: TYPE_UNDEFINED,
+            count: comment.len() as u32,
+            data: comment,
+        },
+        IfdEntry::ascii(
+            TAG_SUB_SEC_TIME_ORIGINAL,
+            &format!("{:03}", metadata.captured_at % 1000),
+        ),
+        IfdEntry::ascii(TAG_BODY_SERIAL_NUMBER, &metadata.client_id),
+    ];
+
+    // Tags have to be in ascending order
+    let mut entries = vec![];
+    let model = metadata.sensor.as_ref().and_then(|s| s.model.as_deref());
+    if let Some(model) = model {
+        entries.push(IfdEntry::ascii(TAG_MODEL, model));
+    }
+    entries.push(IfdEntry::ascii(TAG_DATE_TIME, &date_time));
+    entries.push(IfdEntry {
+        tag: TAG_EXIF_IFD,
+        kind: TYPE_LONG,
+        count: 1,
+        data: vec![0; 4],
+    });
+    // The Exif IFD follows IFD 0, which starts after the 8 byte header
+    let exif_ifd = 8 + ifd_len(&entries) as u32;
+    entries.last_mut().expect("just pushed").data = exif_ifd.to_le_bytes().to_vec();
+
+    let mut tiff = b"II*\0".to_vec();
+    tiff.extend(8u32.to_le_bytes());
+    put_ifd(&mut tiff, &entries);
+    put_ifd(&mut tiff, &exif_entries);
+    tiff
+}
+
+fn embed_exif(file: &[u8], metadata: &CaptureMetadata) -> Result<Vec<u8>, Error> {
+    let mut app1 = EXIF_HEADER.to_vec();
+    app1.extend(exif(metadata));
+    if app1.len() + 2 > usize::from(u16::MAX) {
+        return Err(Error::Malformed("metadata too large for a JPEG segment"));
+    }
+
+    let (segments, rest) = jpeg_segments(file)?;
+    let mut out = JPEG_SOI.to_vec();
+    // JFIF wants its APP0 segment first, EXIF goes right after
+    let (app0, others): (Vec<_>, Vec<_>) = segments
+        .into_iter()
+        .filter(|(marker, contents)| !(*marker == JPEG_APP1 && contents.starts_with(EXIF_HEADER)))
+        .partition(|(marker, _)| *marker == JPEG_APP0);
+    for (marker, contents) in app0 {
+        put_segment(&mut out, marker, contents);
+    }
+    put_segment(&mut out, JPEG_APP1, &app1);
+    for (marker, contents) in others {
+        put_segment(&mut out, marker, contents);
+    }
+    out.extend(rest);
+    Ok(out)
+}
+
+/// Appends a segment, which has to be shorter than 64 KB.
+fn put_segment(out: &mut Vec<u8>, marker: u8, contents: &[u8]) {
+    out.extend([0xFF, marker]);
+    out.extend((contents.len() as u16 + 2).to_be_bytes());
+    out.extend(contents);
+}
+
+/// Reads TIFF data in either byte order.
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl Tiff<'_> {
+    fn u16(&self, at: usize) -> Option<u16> {
+        let bytes = self.data.get(at..at + 2)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        let bytes = self.data.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    /// Where `tag`'s entry in the IFD at `ifd` is.
+    fn entry(&self, ifd: usize, tag: u16) -> Option<usize> {
+        let count = self.u16(ifd)?;
+        (0..usize::from(count))
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| self.u16(entry) == Some(tag))
+    }
+
+    /// The value of an ASCII or UNDEFINED `tag`.
+    fn bytes(&self, ifd: usize, tag: u16) -> Option<&[u8]> {
+        let entry = self.entry(ifd, tag)?;
+        let len = self.u32(entry + 4)? as usize;
+        let at = if len <= 4 {
+            entry + 8
+        } else {
+            self.u32(entry + 8)? as usize
+        };
+        self.data.get(at..at.checked_add(len)?)
+    }
+
+    /// The value of a LONG `tag`.
+    fn long(&self, ifd: usize, tag: u16) -> Option<u32> {
+        self.u32(self.entry(ifd, tag)? + 8)
+    }
+}
+
+fn read_exif(file: &[u8]) -> Result<Option<CaptureMetadata>, Error> {
+    let (segments, _) = jpeg_segments(file)?;
+    let Some(data) = segments.into_iter().find_map(|(marker, contents)| {
+        (marker == JPEG_APP1)
+            .then(|| contents.strip_prefix(EXIF_HEADER))
+            .flatten()
+    }) else {
+        return Ok(None);
+    };
+    let tiff = Tiff {
+        data,
+        little_endian: data.starts_with(b"II"),
+    };
+    let comment = tiff
+        .u32(4)
+        .and_then(|ifd0| tiff.long(ifd0 as usize, TAG_EXIF_IFD))
+        .and_then(|exif_ifd| tiff.bytes(exif_ifd as usize, TAG_USER_COMMENT));
+    // EXIF written by cameras and editors, without ours
+    let Some(json) = comment.and_then(|c| c.strip_prefix(USER_COMMENT_ASCII)) else {
+        return Ok(None);
+    };
+    match serde_json::from_slice(json) {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn crc32(data: impl IntoIterator<Item = u8>) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// PNG chunks, checking lengths but not checksums.
+fn png_chunks(file: &[u8]) -> Result<Vec<Chunk<'_>>, Error> {
+    let mut chunks = vec![];
+    let mut at = PNG_SIGNATURE.len();
+    while at < file.len() {
+        let header = file
+            .get(at..at + 8)
+            .ok_or(Error::Malformed("PNG chunk is cut off"))?;
+        let len = u32::from_be_bytes(header[..4].try_into().expect("4 bytes")) as usize;
+        let kind = header[4..].try_into().expect("4 bytes");
+        let data = file
+            .get(at + 8..)
+            .and_then(|rest| rest.get(..len))
+            .filter(|_| file.len() - at - 8 - len >= 4)
+            .ok_or(Error::Malformed("PNG chunk is cut off"))?;
+        chunks.push((kind, data));
+        at += 8 + len + 4;
+    }
+    Ok(chunks)
+}
+
+fn put_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend((data.len() as u32).to_be_bytes());
+    out.extend(kind);
+    out.extend(data);
+    out.extend(crc32(kind.iter().chain(data).copied()).to_be_bytes());
+}
+
+fn latin1(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| u8::try_from(u32::from(c)).unwrap_or(b'?'))
+        .collect()
+}
+
+fn embed_text(file: &[u8], metadata: &CaptureMetadata) -> Result<Vec<u8>, Error> {
+    let mut texts = vec![
+        (KEY_CLIENT_ID, metadata.client_id.clone()),
+        (KEY_CAPTURED_AT, metadata.captured_at.to_string()),
+        (
+            KEY_CREATION_TIME,
+            format!(
+                "{}.{:03} UTC",
+                utc(metadata.captured_at / 1000),
+                metadata.captured_at % 1000
+            ),
+        ),
+    ];
+    if let Some(sensor) = &metadata.sensor {
+        texts.push((KEY_SENSOR, ascii_json(sensor)));
+        if let Some(model) = &sensor.model {
+            texts.push((KEY_SOURCE, model.clone()));
+        }
+    }
+    if let Some(config) = &metadata.sensor_config {
+        texts.push((KEY_SENSOR_CONFIG, ascii_json(config)));
+    }
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    for (kind, data) in png_chunks(file)? {
+        let ours = &kind == b"tEXt"
+            && KEYS.iter().any(|key| {
+                data.strip_prefix(key.as_bytes())
+                    .is_some_and(|rest| rest.first() == Some(&0))
+            });
+        if ours {
+            continue;
+        }
+        put_chunk(&mut out, &kind, data);
+        // IHDR comes first, text can follow right away
+        if &kind == b"IHDR" {
+            for (key, text) in &texts {
+                let mut data = key.as_bytes().to_vec();
+                data.push(0);
+                data.extend(latin1(text));
+                put_chunk(&mut out, b"tEXt", &data);
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn read_text(file: &[u8]) -> Result<Option<CaptureMetadata>, Error> {
+    let texts: Vec<(&[u8], String)> = png_chunks(file)?
+        .into_iter()
+        .filter(|(kind, _)| kind == b"tEXt")
+        .filter_map(|(_, data)| {
+            let nul = data.iter().position(|&b| b == 0)?;
+            let text = data[nul + 1..].iter().map(|&b| char::from(b)).collect();
+            Some((&data[..nul], text))
+        })
+        .collect();
+    let text = |key: &str| {
+        texts
+            .iter()
+            .find(|(k, _)| *k == key.as_bytes())
+            .map(|(_, text)| text.as_str())
+    };
+    let (Some(client_id), Some(captured_at)) = (text(KEY_CLIENT_ID), text(KEY_CAPTURED_AT)) else {
+        return Ok(None);
+    };
+    fn json<T: DeserializeOwned>(text: Option<&str>) -> Result<Option<T>, Error> {
+        text.map(serde_json::from_str)
+            .transpose()
+            .map_err(|_| Error::Malformed("metadata isn't valid JSON"))
+    }
+    Ok(Some(CaptureMetadata {
+        client_id: client_id.into(),
+        captured_at: captured_at
+            .parse()
+            .map_err(|_| Error::Malformed("capture time isn't a number"))?,
+        sensor: json(text(KEY_SENSOR))?,
+        sensor_config: json(text(KEY_SENSOR_CONFIG))?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flock_api::CameraSensorConfig;
+
+    fn metadata() -> CaptureMetadata {
+        CaptureMetadata {
+            client_id: "flock-client-1".into(),
+            captured_at: 1_660_000_000_123,
+            sensor: Some(SensorInfo {
+                manufacturer_id: 0x7FA2,
+                product_id: 0x2642,
+                version: 0x42,
+                model: Some("OV2640".into()),
+            }),
+            sensor_config: Some(SensorConfig::Camera(CameraSensorConfig {
+                brightness: 2,
+                contrast: -1,
+                saturation: 0,
+                sharpness: 0,
+                de_noise: 0,
+                special_effect: 0,
+                wb_mode: 0,
+                awb: true,
+                awb_gain: true,
+                gain_ceiling: 0,
+                lens_correction: true,
+                horizontal_mirror: false,
+                vertical_flip: true,
+            })),
+        }
+    }
+
+    fn image() -> RgbImage {
+        RgbImage::from_fn(16, 8, |x, y| image::Rgb([x as u8 * 16, y as u8 * 32, 128]))
+    }
+
+    fn assert_recovered(read: Option<CaptureMetadata>) {
+        let read = read.expect("metadata");
+        assert_eq!(read.client_id, "flock-client-1");
+        assert_eq!(read.captured_at, 1_660_000_000_123);
+        assert_eq!(read.sensor, metadata().sensor);
+        let Some(SensorConfig::Camera(config)) = read.sensor_config else {
+            panic!("sensor config missing");
+        };
+        assert_eq!((config.brightness, config.contrast), (2, -1));
+        assert!(config.vertical_flip);
+    }
+
+    #[test]
+    fn jpeg_metadata_round_trips() {
+        let jpeg = crate::to_jpeg(&image(), 80).unwrap();
+        assert!(read(&jpeg).unwrap().is_none());
+
+        let tagged = embed(&jpeg, &metadata()).unwrap();
+        assert_recovered(read(&tagged).unwrap());
+        // Still a JPEG of the same image, and embedding again doesn't pile up segments
+        let decoded = image::load_from_memory(&tagged).unwrap().to_rgb8();
+        assert_eq!(decoded, image::load_from_memory(&jpeg).unwrap().to_rgb8());
+        assert_eq!(embed(&tagged, &metadata()).unwrap(), tagged);
+    }
+
+    #[test]
+    fn exif_has_the_standard_fields() {
+        let tiff = exif(&metadata());
+        let tiff = Tiff {
+            data: &tiff,
+            little_endian: true,
+        };
+        let ifd0 = tiff.u32(4).unwrap() as usize;
+        assert_eq!(tiff.bytes(ifd0, TAG_MODEL), Some(&b"OV2640\0"[..]));
+        assert_eq!(
+            tiff.bytes(ifd0, TAG_DATE_TIME),
+            Some(&b"2022:08:08 23:06:40\0"[..])
+        );
+        let exif_ifd = tiff.long(ifd0, TAG_EXIF_IFD).unwrap() as usize;
+        let serial = tiff.bytes(exif_ifd, TAG_BODY_SERIAL_NUMBER);
+        assert_eq!(serial, Some(&b"flock-client-1\0"[..]));
+        let sub_sec = tiff.bytes(exif_ifd, TAG_SUB_SEC_TIME_ORIGINAL);
+        assert_eq!(sub_sec, Some(&b"123\0"[..]));
+    }
+
+    #[test]
+    fn png_metadata_round_trips() {
+        let png = crate::to_png(&image()).unwrap();
+        assert!(read(&png).unwrap().is_none());
+
+        let tagged = embed(&png, &metadata()).unwrap();
+        assert_recovered(read(&tagged).unwrap());
+        assert_eq!(image::load_from_memory(&tagged).unwrap().to_rgb8(), image());
+        assert_eq!(embed(&tagged, &metadata()).unwrap(), tagged);
+    }
+
+    #[test]
+    fn client_ids_beyond_ascii_survive_in_json() {
+        let metadata = CaptureMetadata {
+            client_id: "küche-📷".into(),
+            ..metadata()
+        };
+        let jpeg = embed(&crate::to_jpeg(&image(), 80).unwrap(), &metadata).unwrap();
+        assert_eq!(read(&jpeg).unwrap().unwrap().client_id, "küche-📷");
+        let png = embed(&crate::to_png(&image()).unwrap(), &metadata).unwrap();
+        assert_eq!(read(&png).unwrap().unwrap().client_id, "küche-?");
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(matches!(read(b"GIF89a"), Err(Error::Malformed(_))));
+        assert!(matches!(
+            read(&[0xFF, 0xD8, 0xFF, 0xE0, 0x00]),
+            Err(Error::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,311 @@
+//! Motion detection from frame differences.
+//!
+//! Frames are downscaled to luma and compared with a background that slowly follows the scene,
+//! so gradual changes like the sun going behind a cloud are absorbed while things moving about
+//! aren't. A frame shows motion when enough of the watched area differs from the background.
+
+use image::imageops::{self, FilterType};
+use image::RgbImage;
+use std::time::{Duration, SystemTime};
+
+/// Frames are compared at this width, which also evens out noise
+const ANALYSIS_WIDTH: u32 = 160;
+/// How far the background moves towards each frame
+const BACKGROUND_RATE: f32 = 0.1;
+/// Slower where the frame differs, so moving things don't leave ghosts behind, while something
+/// that stays, like a parked car, still becomes background after a few hundred frames
+const CHANGED_RATE: f32 = 0.01;
+
+/// A rectangle in fractions of the frame's width and height, so it holds across frame sizes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Region {
+    fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MotionConfig {
+    /// From 0 to 1, how small a change in brightness counts
+    pub sensitivity: f32,
+    /// Fraction of the watched area that has to change
+    pub min_area: f32,
+    /// Parts of the frame to ignore, like a swaying tree or a clock
+    pub masks: Vec<Region>,
+    /// Motion only ends after this long without any, so one event isn't split into several and
+    /// a new one can't start right after
+    pub cooldown: Duration,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        Self {
+            sensitivity: 0.8,
+            min_area: 0.01,
+            masks: vec![],
+            cooldown: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Motion {
+    Started {
+        at: SystemTime,
+        /// Fraction of the watched area that changed
+        area: f32,
+        /// Around everything that changed
+        bounds: Region,
+    },
+    Ended {
+        at: SystemTime,
+        started: SystemTime,
+    },
+}
+
+struct Background {
+    /// Size of the frames it was built from
+    frame_size: (u32, u32),
+    luma: Vec<f32>,
+    watched: Vec<bool>,
+}
+
+pub struct MotionDetector {
+    config: MotionConfig,
+    background: Option<Background>,
+    /// Start and latest motion of the ongoing event
+    active: Option<(SystemTime, SystemTime)>,
+}
+
+impl MotionDetector {
+    pub fn new(config: MotionConfig) -> Self {
+        Self {
+            config,
+            background: None,
+            active: None,
+        }
+    }
+
+    pub fn config(&self) -> &MotionConfig {
+        &self.config
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// Takes the next frame, captured `at`. The first frame, and the first after the frame size
+    /// changes, only sets the background.
+    pub fn feed(&mut self, frame: &RgbImage, at: SystemTime) -> Option<Motion> {
+        let (width, height) = analysis_size(frame.dimensions());
+        let luma: Vec<f32> = imageops::resize(
+            &imageops::grayscale(frame),
+            width,
+            height,
+            FilterType::Triangle,
+        )
+        .into_raw()
+        .into_iter()
+        .map(f32::from)
+        .collect();
+
+        let background = match &mut self.background {
+            Some(bg) if bg.frame_size == frame.dimensions() => bg,
+            _ => {
+                self.background = Some(self.new_background(frame.dimensions(), width, luma));
+                return None;
+            }
+        };
+
+        let threshold = 4.0 + (1.0 - self.config.sensitivity.clamp(0.0, 1.0)) * 60.0;
+        let (mut watched, mut changed) = (0, 0);
+        let (mut min, mut max) = ((width, height), (0, 0));
+        for (i, (cur, bg)) in luma.iter().zip(background.luma.iter_mut()).enumerate() {
+            let differs = (cur - *bg).abs() > threshold;
+            if differs && background.watched[i] {
+                changed += 1;
+                let (x, y) = (i as u32 % width, i as u32 / width);
+                min = (min.0.min(x), min.1.min(y));
+                max = (max.0.max(x), max.1.max(y));
+            }
+            watched += usize::from(background.watched[i]);
+            *bg += (cur - *bg)
+                * if differs {
+                    CHANGED_RATE
+                } else {
+                    BACKGROUND_RATE
+                };
+        }
+        let area = if watched == 0 {
+            0.0
+        } else {
+            changed as f32 / watched as f32
+        };
+
+        if changed > 0 && area >= self.config.min_area {
+            if let Some((_, last)) = &mut self.active {
+                *last = at;
+                return None;
+            }
+            self.active = Some((at, at));
+            let bounds = Region {
+                x: min.0 as f32 / width as f32,
+                y: min.1 as f32 / height as f32,
+                width: (max.0 - min.0 + 1) as f32 / width as f32,
+                height: (max.1 - min.1 + 1) as f32 / height as f32,
+            };
+            return Some(Motion::Started { at, area, bounds });
+        }
+        let (started, last) = self.active?;
+        // A clock going backwards counts as no time passing
+        let quiet = at.duration_since(last).unwrap_or_default();
+        if quiet < self.config.cooldown {
+            return None;
+        }
+        self.active = None;
+        Some(Motion::Ended { at, started })
+    }
+
+    fn new_background(&self, frame_size: (u32, u32), width: u32, luma: Vec<f32>) -> Background {
+        let height = luma.len() as u32 / width;
+        let watched = (0..luma.len() as u32)
+            .map(|i| {
+                // Pixel centers, as fractions of the frame
+                let x = (i % width) as f32 / width as f32 + 0.5 / width as f32;
+                let y = (i / width) as f32 / height as f32 + 0.5 / height as f32;
+                !self.config.masks.iter().any(|m| m.contains(x, y))
+            })
+            .collect();
+        Background {
+            frame_size,
+            luma,
+            watched,
+        }
+    }
+}
+
+/// Scales frames wider than `ANALYSIS_WIDTH` down to it, keeping the aspect ratio.
+fn analysis_size((width, height): (u32, u32)) -> (u32, u32) {
+    if width <= ANALYSIS_WIDTH {
+        return (width, height);
+    }
+    let height = (height * ANALYSIS_WIDTH / width).max(1);
+    (ANALYSIS_WIDTH, height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    fn time(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    /// A gray 320x240 scene with a dark `size` square at `x`, `y`.
+    fn scene(square: Option<(u32, u32, u32)>) -> RgbImage {
+        RgbImage::from_fn(320, 240, |px, py| match square {
+            Some((x, y, size)) if (x..x + size).contains(&px) && (y..y + size).contains(&py) => {
+                Rgb([20, 20, 20])
+            }
+            _ => Rgb([160, 160, 160]),
+        })
+    }
+
+    fn config() -> MotionConfig {
+        MotionConfig {
+            cooldown: Duration::from_secs(3),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn still_scenes_stay_quiet() {
+        let mut detector = MotionDetector::new(config());
+        for t in 0..10 {
+            assert_eq!(detector.feed(&scene(Some((40, 40, 60))), time(t)), None);
+        }
+    }
+
+    #[test]
+    fn motion_starts_and_ends_after_the_cooldown() {
+        let mut detector = MotionDetector::new(config());
+        assert_eq!(detector.feed(&scene(None), time(0)), None);
+        let Some(Motion::Started { at, area, bounds }) =
+            detector.feed(&scene(Some((160, 80, 64))), time(1))
+        else {
+            panic!("expected motion to start");
+        };
+        assert_eq!(at, time(1));
+        assert!((area - 64.0 * 64.0 / (320.0 * 240.0)).abs() < 0.01);
+        assert!((bounds.x - 0.5).abs() < 0.02 && (bounds.width - 0.2).abs() < 0.02);
+
+        // Still moving, then gone, and the event holds until 3s after the last motion
+        assert_eq!(detector.feed(&scene(Some((200, 80, 64))), time(2)), None);
+        assert_eq!(detector.feed(&scene(None), time(3)), None);
+        assert!(detector.is_active());
+        assert_eq!(detector.feed(&scene(None), time(4)), None);
+        assert_eq!(
+            detector.feed(&scene(None), time(5)),
+            Some(Motion::Ended {
+                at: time(5),
+                started: time(1)
+            })
+        );
+    }
+
+    #[test]
+    fn masked_and_small_changes_are_ignored() {
+        let mask = Region {
+            x: 0.0,
+            y: 0.0,
+            width: 0.5,
+            height: 1.0,
+        };
+        let mut detector = MotionDetector::new(MotionConfig {
+            masks: vec![mask],
+            ..config()
+        });
+        detector.feed(&scene(None), time(0));
+        assert_eq!(detector.feed(&scene(Some((20, 20, 100))), time(1)), None);
+        // 8x8 is under 1% of the frame
+        assert_eq!(detector.feed(&scene(Some((200, 20, 8))), time(2)), None);
+        assert!(detector
+            .feed(&scene(Some((200, 20, 64))), time(3))
+            .is_some());
+    }
+
+    #[test]
+    fn sensitivity_sets_the_threshold() {
+        let faint = |level| RgbImage::from_pixel(320, 240, Rgb([level, level, level]));
+        let mut dull = MotionDetector::new(MotionConfig {
+            sensitivity: 0.2,
+            ..config()
+        });
+        let mut keen = MotionDetector::new(MotionConfig {
+            sensitivity: 0.9,
+            ..config()
+        });
+        for detector in [&mut dull, &mut keen] {
+            detector.feed(&faint(100), time(0));
+        }
+        assert_eq!(dull.feed(&faint(130), time(1)), None);
+        assert!(keen.feed(&faint(130), time(1)).is_some());
+    }
+
+    #[test]
+    fn new_frame_sizes_reset_the_background() {
+        let mut detector = MotionDetector::new(config());
+        detector.feed(&scene(None), time(0));
+        let small = RgbImage::from_pixel(64, 48, Rgb([0, 0, 0]));
+        assert_eq!(detector.feed(&small, time(1)), None);
+        assert_eq!(detector.feed(&small, time(2)), None);
+    }
+}
@@ -0,0 +1,356 @@
+//! Time-lapses from captures taken over hours or days.
+//!
+//! Frames are streamed into an MJPEG AVI or an animated GIF one at a time, so a long series
+//! doesn't have to fit in memory. Deflickering evens out the jumps in brightness auto exposure
+//! leaves between captures, scaling each frame towards a running average of the frames before
+//! it, which still follows slow changes like dusk.
+
+use crate::avi::AviWriter;
+use crate::Error;
+use flock_api::StoredCapture;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::imageops::{self, FilterType};
+use image::{Delay, DynamicImage, Frame, Rgb, RgbImage};
+use std::io::{Seek, Write};
+use std::ops::RangeBounds;
+use std::time::Duration;
+
+/// How far the running average moves towards each frame's brightness
+const DEFLICKER_RATE: f32 = 0.2;
+/// Deflickering brightens or darkens a frame by at most this factor
+const MAX_GAIN: f32 = 2.0;
+/// GIF quantization speed from 1 (best) to 30 (fastest)
+const GIF_SPEED: i32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimelapseFormat {
+    Avi,
+    Gif,
+}
+
+#[derive(Debug, Clone)]
+pub struct TimelapseOptions {
+    pub format: TimelapseFormat,
+    /// How long each capture is shown
+    pub frame_interval: Duration,
+    pub deflicker: bool,
+    /// Stamp each frame with its capture time, in UTC
+    pub timestamps: bool,
+    /// JPEG quality of AVI frames
+    pub jpeg_quality: u8,
+}
+
+impl Default for TimelapseOptions {
+    fn default() -> Self {
+        Self {
+            format: TimelapseFormat::Avi,
+            frame_interval: Duration::from_millis(100),
+            deflicker: false,
+            timestamps: false,
+            jpeg_quality: 90,
+        }
+    }
+}
+
+/// The captures taken within `range`, in Unix seconds, oldest first.
+pub fn select(captures: &[StoredCapture], range: impl RangeBounds<u64>) -> Vec<StoredCapture> {
+    let mut selected: Vec<StoredCapture> = captures
+        .iter()
+        .filter(|capture| range.contains(&capture.captured_at))
+        .cloned()
+        .collect();
+    selected.sort_by_key(|capture| (capture.captured_at, capture.id));
+    selected
+}
+
+enum Encoder<W: Write + Seek> {
+    /// Waiting for the first frame, which sets the video's size
+    Pending(W),
+    Avi(AviWriter<W>),
+    Gif(GifEncoder<W>),
+}
+
+pub struct TimelapseWriter<W: Write + Seek> {
+    options: TimelapseOptions,
+    encoder: Option<Encoder<W>>,
+    size: Option<(u32, u32)>,
+    /// Running average of mean brightness, for deflickering
+    brightness: Option<f32>,
+    frames: usize,
+}
+
+impl<W: Write + Seek> TimelapseWriter<W> {
+    pub fn new(out: W, options: TimelapseOptions) -> Self {
+        Self {
+            options,
+            encoder: Some(Encoder::Pending(out)),
+            size: None,
+            brightness: None,
+            frames: 0,
+        }
+    }
+
+    /// Appends a frame captured at `captured_at`, in Unix seconds. Frames of another size than
+    /// the first are scaled to it.
+    pub fn push(&mut self, frame: &RgbImage, captured_at: u64) -> Result<(), Error> {
+        let size = *self.size.get_or_insert(frame.dimensions());
+        let mut frame = if frame.dimensions() == size {
+            frame.clone()
+        } else {
+            imageops::resize(frame, size.0, size.1, FilterType::Triangle)
+        };
+        if self.options.deflicker {
+            self.deflicker(&mut frame);
+        }
+        if self.options.timestamps {
+            stamp(&mut frame, &utc(captured_at));
+        }
+
+        let encoder = match self.encoder.take().expect("only taken while pushing") {
+            Encoder::Pending(out) => self.start(out, size)?,
+            encoder => encoder,
+        };
+        let encoder = self.encoder.insert(encoder);
+        match encoder {
+            Encoder::Pending(_) => unreachable!("started above"),
+            Encoder::Avi(avi) => avi
+                .push(&crate::to_jpeg(&frame, self.options.jpeg_quality)?)
+                .map_err(Error::Io)?,
+            Encoder::Gif(gif) => {
+                let frame = DynamicImage::ImageRgb8(frame).to_rgba8();
+                let delay = Delay::from_saturating_duration(self.options.frame_interval);
+                gif.encode_frame(Frame::from_parts(frame, 0, 0, delay))?;
+            }
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Finishes the file and returns the number of frames in it.
+    pub fn finish(self) -> Result<usize, Error> {
+        match self.encoder.expect("only taken while pushing") {
+            Encoder::Pending(_) => return Err(Error::Mismatch),
+            Encoder::Avi(avi) => {
+                avi.finish().map_err(Error::Io)?;
+            }
+            // The GIF trailer is written on drop
+            Encoder::Gif(gif) => drop(gif),
+        }
+        Ok(self.frames)
+    }
+
+    fn start(&self, out: W, (width, height): (u32, u32)) -> Result<Encoder<W>, Error> {
+        Ok(match self.options.format {
+            TimelapseFormat::Avi => Encoder::Avi(
+                AviWriter::new(out, width, height, self.options.frame_interval)
+                    .map_err(Error::Io)?,
+            ),
+            TimelapseFormat::Gif => {
+                let mut gif = GifEncoder::new_with_speed(out, GIF_SPEED);
+                gif.set_repeat(Repeat::Infinite)?;
+                Encoder::Gif(gif)
+            }
+        })
+    }
+
+    fn deflicker(&mut self, frame: &mut RgbImage) {
+        let mean = mean_brightness(frame);
+        let target = match &mut self.brightness {
+            Some(average) => {
+                *average += (mean - *average) * DEFLICKER_RATE;
+                *average
+            }
+            None => *self.brightness.insert(mean),
+        };
+        if mean < 1.0 {
+            return;
+        }
+        let gain = (target / mean).clamp(1.0 / MAX_GAIN, MAX_GAIN);
+        for channel in frame.iter_mut() {
+            *channel = (f32::from(*channel) * gain).round().min(255.0) as u8;
+        }
+    }
+}
+
+fn mean_brightness(frame: &RgbImage) -> f32 {
+    let total: u64 = frame
+        .pixels()
+        .map(|Rgb([r, g, b])| u64::from(*r) * 77 + u64::from(*g) * 150 + u64::from(*b) * 29)
+        .sum();
+    total as f32 / 256.0 / (frame.width() * frame.height()).max(1) as f32
+}
+
+/// `secs` since the Unix epoch as `YYYY-MM-DD HH:MM:SS`, in UTC.
+pub fn utc(secs: u64) -> String {
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+    // Howard Hinnant's civil_from_days, for days since 1970-01-01
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+
+/// Rows of a 5x7 glyph, the leftmost pixel in the highest bit. Only what timestamps need.
+fn glyph(c: char) -> [u8; 7] {
+    match c {
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        _ => [0; 7],
+    }
+}
+
+/// Draws `text` in white on black in the bottom left corner, scaled with the frame.
+fn stamp(frame: &mut RgbImage, text: &str) {
+    let scale = (frame.width() / 320).max(1);
+    let advance = (GLYPH_WIDTH + 1) * scale;
+    let (box_width, box_height) = (
+        advance * text.chars().count() as u32 + scale,
+        (GLYPH_HEIGHT + 2) * scale,
+    );
+    if box_width > frame.width() || box_height > frame.height() {
+        return;
+    }
+    let top = frame.height() - box_height;
+    for y in top..frame.height() {
+        for x in 0..box_width {
+            frame.put_pixel(x, y, Rgb([0, 0, 0]));
+        }
+    }
+    for (i, c) in text.chars().enumerate() {
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (0x10 >> col) == 0 {
+                    continue;
+                }
+                let x = scale + i as u32 * advance + col * scale;
+                let y = top + scale + row as u32 * scale;
+                for (dx, dy) in (0..scale).flat_map(|dx| (0..scale).map(move |dy| (dx, dy))) {
+                    frame.put_pixel(x + dx, y + dy, Rgb([255, 255, 255]));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flock_api::FrameFormat;
+    use std::io::Cursor;
+
+    fn capture(id: u32, captured_at: u64) -> StoredCapture {
+        StoredCapture {
+            id,
+            captured_at,
+            size: 0,
+            uploaded: true,
+            format: FrameFormat::default(),
+        }
+    }
+
+    fn gray(level: u8) -> RgbImage {
+        RgbImage::from_pixel(64, 48, Rgb([level, level, level]))
+    }
+
+    #[test]
+    fn selects_the_range_in_order() {
+        let captures = [capture(3, 300), capture(1, 100), capture(2, 200)];
+        let ids = |selected: Vec<StoredCapture>| selected.iter().map(|c| c.id).collect::<Vec<_>>();
+        assert_eq!(ids(select(&captures, ..)), [1, 2, 3]);
+        assert_eq!(ids(select(&captures, 150..=300)), [2, 3]);
+    }
+
+    #[test]
+    fn dates_are_utc() {
+        assert_eq!(utc(0), "1970-01-01 00:00:00");
+        assert_eq!(utc(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(utc(1_660_000_000), "2022-08-08 23:06:40");
+    }
+
+    #[test]
+    fn deflickering_evens_out_brightness() {
+        let mut writer = TimelapseWriter::new(
+            Cursor::new(vec![]),
+            TimelapseOptions {
+                deflicker: true,
+                ..Default::default()
+            },
+        );
+        let mut means = vec![];
+        for level in [100, 140, 100, 140, 100, 140] {
+            let mut frame = gray(level);
+            writer.deflicker(&mut frame);
+            means.push(mean_brightness(&frame));
+        }
+        // Down from jumps of 40 between frames
+        let jumps = means.windows(2).map(|pair| (pair[1] - pair[0]).abs());
+        assert!(jumps.fold(0.0, f32::max) < 10.0, "{:?}", means);
+    }
+
+    #[test]
+    fn timestamps_are_drawn() {
+        let mut frame = gray(128);
+        stamp(&mut frame, "10:00");
+        // Black box along the bottom edge, with white strokes in it
+        assert_eq!(frame.get_pixel(0, 47).0, [0, 0, 0]);
+        assert!(frame.pixels().any(|p| p.0 == [255, 255, 255]));
+        assert_eq!(frame.get_pixel(63, 0).0, [128, 128, 128]);
+    }
+
+    #[test]
+    fn writes_both_formats() {
+        for format in [TimelapseFormat::Avi, TimelapseFormat::Gif] {
+            let mut out = Cursor::new(vec![]);
+            let mut writer = TimelapseWriter::new(
+                &mut out,
+                TimelapseOptions {
+                    format,
+                    timestamps: true,
+                    ..Default::default()
+                },
+            );
+            writer.push(&gray(50), 0).unwrap();
+            // Scaled to the first frame's size
+            writer
+                .push(&RgbImage::from_pixel(32, 24, Rgb([200, 200, 200])), 60)
+                .unwrap();
+            assert_eq!(writer.finish().unwrap(), 2);
+            let data = out.into_inner();
+            let magic: &[u8] = match format {
+                TimelapseFormat::Avi => b"RIFF",
+                TimelapseFormat::Gif => b"GIF8",
+            };
+            assert_eq!(&data[..4], magic);
+        }
+
+        let empty = TimelapseWriter::new(Cursor::new(vec![]), TimelapseOptions::default());
+        assert!(matches!(empty.finish(), Err(Error::Mismatch)));
+    }
+}
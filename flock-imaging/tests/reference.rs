@@ -0,0 +1,100 @@
+//! Decodes reference frames of the OV2640 colour bar test pattern, one per pixel format.
+//!
+//! `tests/frames/colorbar_64x8.<format>` are 64x8 frames with 8 bars of 8 pixels, written by
+//! `examples/colorbar_frames.rs` following esp32-camera's byte order (and for YUV, the JFIF
+//! equations), so the decoders aren't checked against themselves.
+
+use flock_api::{FrameFormat, PixelFormat, SensorData};
+use std::fs;
+use std::path::Path;
+
+const WIDTH: u32 = 64;
+const HEIGHT: u32 = 8;
+
+const COLOR_BARS: [[u8; 3]; 8] = [
+    [255, 255, 255],
+    [255, 255, 0],
+    [0, 255, 255],
+    [0, 255, 0],
+    [255, 0, 255],
+    [255, 0, 0],
+    [0, 0, 255],
+    [0, 0, 0],
+];
+
+fn reference(name: &str, pixel_format: PixelFormat) -> image::RgbImage {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/frames")
+        .join(format!("colorbar_64x8.{}", name));
+    let reading = SensorData::Camera {
+        frame_buffer: fs::read(path).unwrap(),
+        format: FrameFormat {
+            pixel_format,
+            width: WIDTH,
+            height: HEIGHT,
+        },
+    };
+    let img = flock_imaging::decode_reading(&reading).unwrap();
+    assert_eq!(img.dimensions(), (WIDTH, HEIGHT));
+    img
+}
+
+/// Checks every pixel against its bar, allowing each channel to be off by `tolerance`.
+fn assert_bars(img: &image::RgbImage, expected: impl Fn([u8; 3]) -> [u8; 3], tolerance: u8) {
+    for (x, y, px) in img.enumerate_pixels() {
+        let want = expected(COLOR_BARS[(x / 8) as usize]);
+        let off =
+            px.0.iter()
+                .zip(want)
+                .any(|(a, b)| a.abs_diff(b) > tolerance);
+        assert!(!off, "pixel {},{} is {:?}, expected {:?}", x, y, px.0, want);
+    }
+}
+
+#[test]
+fn rgb_formats() {
+    for (name, format) in [
+        ("rgb565", PixelFormat::Rgb565),
+        ("rgb555", PixelFormat::Rgb555),
+        ("rgb444", PixelFormat::Rgb444),
+        ("rgb888", PixelFormat::Rgb888),
+    ] {
+        // The bars only use 0 and 255, which every channel width represents exactly
+        assert_bars(&reference(name, format), |c| c, 0);
+    }
+}
+
+#[test]
+fn grayscale() {
+    let luma = |[r, g, b]: [u8; 3]| {
+        let y = (0.299 * f32::from(r) + 0.587 * f32::from(g) + 0.114 * f32::from(b)).round() as u8;
+        [y, y, y]
+    };
+    assert_bars(&reference("grayscale", PixelFormat::Grayscale), luma, 0);
+}
+
+#[test]
+fn yuv_formats() {
+    // Chroma is shared between neighbouring pixels, but bars are wide enough that no pair or
+    // 2x2 block straddles two of them. What's left is rounding.
+    assert_bars(&reference("yuv422", PixelFormat::Yuv422), |c| c, 2);
+    assert_bars(&reference("yuv420", PixelFormat::Yuv420), |c| c, 2);
+}
+
+#[test]
+fn jpeg_export_keeps_the_bars() {
+    let img = reference("rgb565", PixelFormat::Rgb565);
+    let jpeg = flock_imaging::to_jpeg(&img, 95).unwrap();
+    let format = FrameFormat {
+        pixel_format: PixelFormat::Jpeg,
+        width: 0,
+        height: 0,
+    };
+    let decoded = flock_imaging::decode(&jpeg, &format).unwrap();
+    // Lossy, and ringing at the bar edges, so only check the middle of each bar
+    for (i, bar) in COLOR_BARS.iter().enumerate() {
+        let px = decoded.get_pixel(i as u32 * 8 + 4, HEIGHT / 2).0;
+        let off = px.iter().zip(bar).any(|(a, b)| a.abs_diff(*b) > 24);
+        assert!(!off, "bar {} is {:?}, expected {:?}", i, px, bar);
+    }
+}
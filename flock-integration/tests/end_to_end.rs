@@ -0,0 +1,398 @@
+use flock_api::{
+    Burst, FrameEncoding, Instruction, Payload, PixelFormat, Roi, SensorConfig, SensorData,
+    ShotSettings,
+};
+use flock_client::clip::{Clip, ClipFormat, ClipOptions};
+use flock_client::motion::{Motion, MotionConfig, MotionEvent};
+use flock_client::Error;
+use flock_imaging::metadata;
+use flock_integration::{expect_message, Harness};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time;
+
+const NODE: &str = "flock-client-sim-0";
+
+/// Starts a node and waits until the client has heard it announce itself.
+async fn connected(harness: &mut Harness) -> flock_client::Client {
+    let client = harness.client().await;
+    let mut events = client.subscribe();
+    harness.spawn_node(NODE);
+    expect_message(&mut events, |msg| {
+        msg.client_id == NODE && matches!(*msg.payload, Payload::Connected)
+    })
+    .await;
+    client
+}
+
+async fn next_event(events: &mut broadcast::Receiver<MotionEvent>) -> MotionEvent {
+    time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("timed out waiting for motion")
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn node_announces_itself() {
+    let mut harness = Harness::start();
+    let client = connected(&mut harness).await;
+    assert_eq!(client.devices(), vec![NODE.to_string()]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn discovers_nodes_that_connected_earlier() {
+    let mut harness = Harness::start();
+    drop(connected(&mut harness).await);
+
+    let client = harness.client().await;
+    assert!(client.devices().is_empty());
+    let devices = client.discover(Duration::from_secs(1)).await.unwrap();
+    assert_eq!(devices, vec![NODE.to_string()]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn instructions_wait_for_nodes_to_connect() {
+    let mut harness = Harness::start();
+    let client = harness.client().await;
+    let mut events = client.subscribe();
+    let jpeg = FrameEncoding::Jpeg { quality: 80 };
+    client
+        .send(NODE, Instruction::WriteFrameEncoding(jpeg))
+        .await
+        .unwrap();
+
+    harness.spawn_node(NODE);
+    expect_message(&mut events, |msg| {
+        msg.client_id == NODE && matches!(*msg.payload, Payload::FrameEncoding(e) if e == jpeg)
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn capture() {
+    let mut harness = Harness::start();
+    let client = connected(&mut harness).await;
+
+    let SensorData::Camera { frame_buffer, .. } = client.read_sensor(NODE).await.unwrap();
+    // QVGA RGB565, the firmware's default
+    assert_eq!(frame_buffer.len(), 320 * 240 * 2);
+    // The color bar starts with white
+    assert_eq!(frame_buffer[..2], [0xFF, 0xFF]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn jpeg_per_request_and_per_stream() {
+    let mut harness = Harness::start();
+    let client = connected(&mut harness).await;
+    let jpeg = FrameEncoding::Jpeg { quality: 80 };
+    let is_jpeg = |reading: &SensorData| {
+        let SensorData::Camera {
+            frame_buffer,
+            format,
+        } = reading;
+        format.pixel_format == PixelFormat::Jpeg && frame_buffer.starts_with(&[0xFF, 0xD8])
+    };
+
+    let reading = client.read_sensor_encoded(NODE, jpeg).await.unwrap();
+    assert!(is_jpeg(&reading));
+    let SensorData::Camera { format, .. } = reading;
+    assert_eq!((format.width, format.height), (320, 240));
+    // A one-off, the node still sends raw frames by default
+    assert!(!is_jpeg(&client.read_sensor(NODE).await.unwrap()));
+
+    assert_eq!(client.write_frame_encoding(NODE, jpeg).await.unwrap(), jpeg);
+    assert!(is_jpeg(&client.read_sensor(NODE).await.unwrap()));
+    let raw = client
+        .read_sensor_encoded(NODE, FrameEncoding::Raw)
+        .await
+        .unwrap();
+    assert!(!is_jpeg(&raw));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn region_of_interest() {
+    let mut harness = Harness::start();
+    let client = connected(&mut harness).await;
+
+    // Straddles the first two 40 pixel wide color bars
+    let roi = Roi {
+        x: 20,
+        y: 100,
+        width: 40,
+        height: 40,
+        output_width: Some(20),
+        output_height: Some(10),
+    };
+    let status = client.write_roi(NODE, Some(roi)).await.unwrap();
+    assert_eq!((status.width, status.height), (20, 10));
+    assert!(!status.windowed);
+
+    let SensorData::Camera {
+        frame_buffer,
+        format,
+    } = client.read_sensor(NODE).await.unwrap();
+    assert_eq!((format.width, format.height), (20, 10));
+    assert_eq!(frame_buffer.len(), 20 * 10 * 2);
+    // White, then yellow
+    assert_eq!(frame_buffer[..2], [0xFF, 0xFF]);
+    assert_eq!(frame_buffer[38..40], [0xFF, 0xE0]);
+
+    let outside = Roi { x: 300, ..roi };
+    assert!(matches!(
+        client.write_roi(NODE, Some(outside)).await,
+        Err(Error::Device(_))
+    ));
+
+    let status = client.write_roi(NODE, None).await.unwrap();
+    assert_eq!((status.width, status.height), (320, 240));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn exposure_bracketing_burst() {
+    let mut harness = Harness::start();
+    let client = connected(&mut harness).await;
+
+    let shots = [-2, -1, 0]
+        .into_iter()
+        .map(|level| ShotSettings {
+            ae_level: Some(level),
+            ..Default::default()
+        })
+        .collect();
+    let burst = Burst {
+        count: 4,
+        interval_ms: 50,
+        shots,
+        encoding: Some(FrameEncoding::Raw),
+    };
+    let shots = client.burst(NODE, burst).await.unwrap();
+    assert_eq!(shots.len(), 4);
+    assert!(shots
+        .iter()
+        .enumerate()
+        .all(|(i, s)| usize::from(s.index) == i));
+    assert!(shots[3].offset_ms >= 150);
+    // The last shot has no overrides
+    assert_eq!(shots[3].settings, ShotSettings::default());
+
+    // The white bar in the top left corner brightens with every step, up to full white
+    let corner: Vec<u16> = shots
+        .iter()
+        .map(|shot| {
+            let SensorData::Camera { frame_buffer, .. } = &shot.reading;
+            u16::from_be_bytes([frame_buffer[0], frame_buffer[1]])
+        })
+        .collect();
+    assert!(corner[0] < corner[1] && corner[1] < corner[2]);
+    assert_eq!(corner[2..], [0xFFFF, 0xFFFF]);
+
+    let too_long = Burst {
+        count: 100,
+        interval_ms: 0,
+        shots: vec![],
+        encoding: None,
+    };
+    assert!(matches!(
+        client.burst(NODE, too_long).await,
+        Err(Error::Device(_))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn motion_events() {
+    let mut harness = Harness::start();
+    let client = connected(&mut harness).await;
+    let watch = client.watch_motion(
+        NODE,
+        MotionConfig {
+            cooldown: Duration::ZERO,
+            ..Default::default()
+        },
+    );
+    let mut events = watch.subscribe();
+
+    // Mirroring reverses the color bars, as if everything in view moved
+    let SensorConfig::Camera(mut config) = client.read_config(NODE).await.unwrap();
+    for mirror in [false, false, true, false] {
+        config.horizontal_mirror = mirror;
+        client
+            .write_config(NODE, SensorConfig::Camera(config.clone()))
+            .await
+            .unwrap();
+        client.read_sensor(NODE).await.unwrap();
+    }
+
+    let started = next_event(&mut events).await;
+    assert_eq!(started.device, NODE);
+    let Motion::Started { area, .. } = started.motion else {
+        panic!("expected motion to start, got {:?}", started.motion);
+    };
+    assert!(area > 0.5);
+    assert!(matches!(
+        next_event(&mut events).await.motion,
+        Motion::Ended { .. }
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn clips_around_events() {
+    let mut harness = Harness::start();
+    let client = connected(&mut harness).await;
+    let dir = std::env::temp_dir().join(format!("flock-clips-{}", std::process::id()));
+    let recorder = client.record_clips(ClipOptions {
+        before: Duration::from_secs(2),
+        after: Duration::from_secs(1),
+        ..ClipOptions::new(&dir)
+    });
+    let mut clips = recorder.subscribe();
+
+    client.read_config(NODE).await.unwrap();
+    for _ in 0..3 {
+        client.read_sensor(NODE).await.unwrap();
+    }
+    // Triggers don't queue up behind messages, let the recorder take in the config
+    time::sleep(Duration::from_millis(100)).await;
+    recorder.trigger(NODE, "doorbell");
+    for _ in 0..2 {
+        client.read_sensor(NODE).await.unwrap();
+    }
+
+    let clip = time::timeout(Duration::from_secs(5), clips.recv())
+        .await
+        .expect("timed out waiting for the clip")
+        .unwrap();
+    assert_eq!(
+        (clip.device.as_str(), clip.reason.as_str()),
+        (NODE, "doorbell")
+    );
+    assert_eq!(clip.frames.len(), 5);
+    assert!(clip
+        .frames
+        .iter()
+        .all(|f| (f.width, f.height) == (320, 240)));
+    assert!(clip.sensor_config.is_some());
+    assert_eq!(&std::fs::read(&clip.path).unwrap()[..4], b"RIFF");
+    let sidecar: Clip =
+        serde_json::from_slice(&std::fs::read(clip.path.with_extension("json")).unwrap()).unwrap();
+    assert_eq!(sidecar.frames, clip.frames);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sequence_frames_carry_metadata() {
+    let mut harness = Harness::start();
+    let client = connected(&mut harness).await;
+    let dir = std::env::temp_dir().join(format!("flock-sequence-{}", std::process::id()));
+    let recorder = client.record_clips(ClipOptions {
+        before: Duration::from_secs(2),
+        after: Duration::ZERO,
+        format: ClipFormat::JpegSequence,
+        ..ClipOptions::new(&dir)
+    });
+    let mut clips = recorder.subscribe();
+
+    client.read_config(NODE).await.unwrap();
+    let sensor = client.read_sensor_info(NODE).await.unwrap();
+    assert_eq!(sensor.model.as_deref(), Some("OV2640"));
+    client.read_sensor(NODE).await.unwrap();
+    time::sleep(Duration::from_millis(100)).await;
+    recorder.trigger(NODE, "doorbell");
+
+    let clip = time::timeout(Duration::from_secs(5), clips.recv())
+        .await
+        .expect("timed out waiting for the clip")
+        .unwrap();
+    let frame = &clip.frames[0];
+    let file = clip.path.join(frame.file.as_ref().unwrap());
+    let embedded = metadata::read_file(&file).unwrap().unwrap();
+    assert_eq!(embedded.client_id, NODE);
+    assert_eq!(embedded.captured_at, frame.received_at);
+    assert_eq!(embedded.sensor, Some(sensor));
+    assert!(embedded.sensor_config.is_some());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn config_write_and_readback() {
+    let mut harness = Harness::start();
+    let client = connected(&mut harness).await;
+
+    let SensorConfig::Camera(mut config) = client.read_config(NODE).await.unwrap();
+    config.contrast = -1;
+    config.vertical_flip = true;
+    let SensorConfig::Camera(written) = client
+        .write_config(NODE, SensorConfig::Camera(config))
+        .await
+        .unwrap();
+    assert_eq!(written.contrast, -1);
+    assert!(written.vertical_flip);
+
+    let SensorConfig::Camera(read_back) = client.read_config(NODE).await.unwrap();
+    assert_eq!(read_back.contrast, -1);
+    assert!(read_back.vertical_flip);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn error_replies() {
+    let mut harness = Harness::start();
+    let client = connected(&mut harness).await;
+
+    let result = client
+        .request(NODE, Instruction::ListCaptures, |payload| match payload {
+            Payload::StoredCaptures(captures) => Ok(captures),
+            other => Err(other),
+        })
+        .await;
+    assert!(matches!(result, Err(Error::Device(_))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unknown_node_times_out() {
+    let harness = Harness::start();
+    let client = harness.client().await;
+    assert!(matches!(
+        client.read_config("flock-client-missing").await,
+        Err(Error::Timeout)
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn disconnect_is_detected() {
+    let mut harness = Harness::start();
+    let client = harness.client().await;
+    let mut events = client.subscribe();
+    let node = harness.spawn_node(NODE);
+    expect_message(&mut events, |msg| {
+        msg.client_id == NODE && matches!(*msg.payload, Payload::Connected)
+    })
+    .await;
+
+    node.kill();
+    expect_message(&mut events, |msg| {
+        msg.client_id == NODE && matches!(*msg.payload, Payload::Disconnected)
+    })
+    .await;
+    assert!(client.devices().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn many_nodes() {
+    let harness_nodes = 10;
+    let mut harness = Harness::start();
+    let client = harness.client().await;
+    let mut events = client.subscribe();
+    for i in 0..harness_nodes {
+        harness.spawn_node(&format!("flock-client-sim-{}", i));
+    }
+    for _ in 0..harness_nodes {
+        expect_message(&mut events, |msg| {
+            matches!(*msg.payload, Payload::Connected)
+        })
+        .await;
+    }
+    assert_eq!(client.devices().len(), harness_nodes);
+
+    for device in client.devices() {
+        assert!(client.read_sensor(&device).await.is_ok());
+    }
+}
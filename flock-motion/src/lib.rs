@@ -0,0 +1,277 @@
+//! Motion detection small enough to run on a camera node.
+//!
+//! Frames are averaged down to a `GRID_WIDTH` x `GRID_HEIGHT` grayscale grid and compared with a
+//! background grid that follows the scene, slowly where it changed so moving things don't leave
+//! ghosts behind. A shift that every cell shares, like auto exposure settling or a light going
+//! on, is taken out before comparing. There's no heap use and no `std`, and the state is plain
+//! data so a node can keep it in RTC memory across deep sleep.
+
+#![no_std]
+// The node's toolchain predates `div_ceil`
+#![allow(clippy::manual_div_ceil)]
+
+pub const GRID_WIDTH: usize = 32;
+pub const GRID_HEIGHT: usize = 24;
+pub const CELLS: usize = GRID_WIDTH * GRID_HEIGHT;
+
+/// Background grid cells are 8.8 fixed point, and move this many bits' worth towards each frame
+/// (1/8 where the frame matches the background, 1/64 where it doesn't)
+const BACKGROUND_SHIFT: u32 = 3;
+const CHANGED_SHIFT: u32 = 6;
+
+/// Frame layouts luma can be read from without decoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// 1 byte per pixel
+    Grayscale,
+    /// 4 bytes per 2 pixels, `Y0 U Y1 V`
+    Yuv422,
+    /// 2 bytes per pixel, big endian
+    Rgb565,
+}
+
+/// A frame averaged down to luma cells, row by row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grid(pub [u8; CELLS]);
+
+impl Grid {
+    /// `None` if the frame is smaller than the grid or `data` is too short for it.
+    pub fn from_frame(data: &[u8], layout: Layout, width: usize, height: usize) -> Option<Grid> {
+        if width < GRID_WIDTH || height < GRID_HEIGHT {
+            return None;
+        }
+        let row_len = match layout {
+            Layout::Grayscale => width,
+            Layout::Yuv422 => (width + 1) / 2 * 4,
+            Layout::Rgb565 => width * 2,
+        };
+        if data.len() < row_len * height {
+            return None;
+        }
+        let luma = |row: &[u8], x: usize| match layout {
+            Layout::Grayscale => u32::from(row[x]),
+            Layout::Yuv422 => u32::from(row[x / 2 * 4 + x % 2 * 2]),
+            Layout::Rgb565 => {
+                let px = u32::from(row[x * 2]) << 8 | u32::from(row[x * 2 + 1]);
+                let (r, g, b) = ((px >> 11) & 0x1F, (px >> 5) & 0x3F, px & 0x1F);
+                let (r, g, b) = (r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2);
+                (77 * r + 150 * g + 29 * b) >> 8
+            }
+        };
+
+        let mut sums = [0u32; CELLS];
+        for (y, row) in data.chunks_exact(row_len).take(height).enumerate() {
+            let cells = &mut sums[y * GRID_HEIGHT / height * GRID_WIDTH..][..GRID_WIDTH];
+            for x in 0..width {
+                cells[x * GRID_WIDTH / width] += luma(row, x);
+            }
+        }
+        let mut grid = [0u8; CELLS];
+        for (i, (cell, sum)) in grid.iter_mut().zip(sums).enumerate() {
+            let (cx, cy) = (i % GRID_WIDTH, i / GRID_WIDTH);
+            let pixels = span(cx, GRID_WIDTH, width) * span(cy, GRID_HEIGHT, height);
+            *cell = (sum / pixels as u32) as u8;
+        }
+        Some(Grid(grid))
+    }
+}
+
+/// Number of pixels out of `size` that fall in cell `cell` of `cells`.
+fn span(cell: usize, cells: usize, size: usize) -> usize {
+    // Pixel `p` falls in cell `p * cells / size`, so cell `c` starts at the first pixel with
+    // `p * cells >= c * size`
+    let start = |c: usize| (c * size + cells - 1) / cells;
+    start(cell + 1) - start(cell)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Thresholds {
+    /// Luma difference for a cell to count as changed
+    pub threshold: u8,
+    /// Changed cells, in thousandths of the grid, that make up motion
+    pub min_area: u16,
+    /// Minimum time between two triggers
+    pub cooldown_ms: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trigger {
+    /// Changed cells, in thousandths of the grid
+    pub area: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct Detector {
+    background: [u16; CELLS],
+    primed: bool,
+    last_trigger: Option<u64>,
+}
+
+impl Detector {
+    pub const fn new() -> Self {
+        Self {
+            background: [0; CELLS],
+            primed: false,
+            last_trigger: None,
+        }
+    }
+
+    /// Forgets the background, e.g. after the camera's view or settings changed.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Compares `grid`, taken at `now_ms` on any monotonic clock, with the background and takes
+    /// it in. The first grid after a reset only sets the background.
+    pub fn feed(&mut self, grid: &Grid, now_ms: u64, thresholds: &Thresholds) -> Option<Trigger> {
+        if !self.primed {
+            for (bg, cell) in self.background.iter_mut().zip(grid.0) {
+                *bg = u16::from(cell) << 8;
+            }
+            self.primed = true;
+            return None;
+        }
+
+        let frame_total: i32 = grid.0.iter().map(|&cell| i32::from(cell)).sum();
+        let background_total: i32 = self.background.iter().map(|&bg| i32::from(bg >> 8)).sum();
+        let offset = (frame_total - background_total) / CELLS as i32;
+        let mut changed = 0;
+        for (bg, cell) in self.background.iter_mut().zip(grid.0) {
+            let expected = i32::from(*bg >> 8) + offset;
+            let differs = (i32::from(cell) - expected).abs() > i32::from(thresholds.threshold);
+            changed += usize::from(differs);
+            let shift = if differs {
+                CHANGED_SHIFT
+            } else {
+                BACKGROUND_SHIFT
+            };
+            let step = ((i32::from(cell) << 8) - i32::from(*bg)) >> shift;
+            *bg = (i32::from(*bg) + step) as u16;
+        }
+
+        let area = (changed * 1000 / CELLS) as u16;
+        if changed == 0 || area < thresholds.min_area {
+            return None;
+        }
+        if let Some(last) = self.last_trigger {
+            if now_ms.saturating_sub(last) < u64::from(thresholds.cooldown_ms) {
+                return None;
+            }
+        }
+        self.last_trigger = Some(now_ms);
+        Some(Trigger { area })
+    }
+}
+
+impl Default for Detector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        threshold: 16,
+        min_area: 10,
+        cooldown_ms: 1000,
+    };
+
+    fn flat(level: u8) -> Grid {
+        Grid([level; CELLS])
+    }
+
+    /// `level`, with the top left `size` x `size` cells at `spot`.
+    fn with_spot(level: u8, spot: u8, size: usize) -> Grid {
+        let mut grid = flat(level);
+        for y in 0..size {
+            grid.0[y * GRID_WIDTH..][..size].fill(spot);
+        }
+        grid
+    }
+
+    #[test]
+    fn layouts_average_to_the_same_grid() {
+        // 64x48 with a bright left half, 2x2 pixels per cell
+        let (w, h) = (64, 48);
+        let level = |x: usize| if x < w / 2 { 200u8 } else { 40 };
+        let gray: [u8; 64 * 48] = core::array::from_fn(|i| level(i % w));
+        let yuv: [u8; 64 * 48 * 2] = core::array::from_fn(|i| {
+            let x = i % (w * 2) / 4 * 2 + i % 4 / 2;
+            if i % 2 == 0 {
+                level(x)
+            } else {
+                128
+            }
+        });
+        let rgb: [u8; 64 * 48 * 2] = core::array::from_fn(|i| {
+            // White or black, which RGB565 holds exactly
+            let on = level(i % (w * 2) / 2) > 128;
+            if on {
+                0xFF
+            } else {
+                0x00
+            }
+        });
+
+        let gray = Grid::from_frame(&gray, Layout::Grayscale, w, h).unwrap();
+        assert_eq!(gray.0[0], 200);
+        assert_eq!(gray.0[GRID_WIDTH - 1], 40);
+        assert_eq!(Grid::from_frame(&yuv, Layout::Yuv422, w, h).unwrap(), gray);
+        let rgb = Grid::from_frame(&rgb, Layout::Rgb565, w, h).unwrap();
+        assert_eq!((rgb.0[0], rgb.0[GRID_WIDTH - 1]), (255, 0));
+    }
+
+    #[test]
+    fn uneven_sizes_cover_every_pixel() {
+        // 40 columns over 32 cells: some cells get 2 pixels, some 1
+        let data = [90u8; 40 * 30];
+        let grid = Grid::from_frame(&data, Layout::Grayscale, 40, 30).unwrap();
+        assert_eq!(grid, flat(90));
+        assert_eq!((0..32).map(|c| span(c, 32, 40)).sum::<usize>(), 40);
+    }
+
+    #[test]
+    fn small_or_short_frames_are_refused() {
+        assert_eq!(
+            Grid::from_frame(&[0; 16 * 12], Layout::Grayscale, 16, 12),
+            None
+        );
+        assert_eq!(Grid::from_frame(&[0; 100], Layout::Rgb565, 32, 24), None);
+    }
+
+    #[test]
+    fn brightness_shifts_are_not_motion() {
+        let mut detector = Detector::new();
+        detector.feed(&flat(80), 0, &THRESHOLDS);
+        assert_eq!(detector.feed(&flat(140), 100, &THRESHOLDS), None);
+        assert!(detector
+            .feed(&with_spot(140, 250, 8), 200, &THRESHOLDS)
+            .is_some());
+    }
+
+    #[test]
+    fn triggers_respect_area_and_cooldown() {
+        let mut detector = Detector::new();
+        detector.feed(&flat(100), 0, &THRESHOLDS);
+        // 4 cells is 5 thousandths
+        assert_eq!(
+            detector.feed(&with_spot(100, 20, 2), 100, &THRESHOLDS),
+            None
+        );
+        assert_eq!(
+            detector.feed(&with_spot(100, 20, 8), 200, &THRESHOLDS),
+            Some(Trigger { area: 83 })
+        );
+        assert_eq!(detector.feed(&flat(100), 300, &THRESHOLDS), None);
+        assert_eq!(
+            detector.feed(&with_spot(100, 220, 8), 900, &THRESHOLDS),
+            None
+        );
+        assert!(detector
+            .feed(&with_spot(100, 20, 8), 1300, &THRESHOLDS)
+            .is_some());
+    }
+}
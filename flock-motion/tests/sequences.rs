@@ -0,0 +1,80 @@
+//! Runs the detector over 80x60 grayscale sequences at 2 frames a second.
+//!
+//! `tests/sequences/<name>.pgm` hold one binary PGM per frame, back to back. They were made by a
+//! separate script that renders a fixed scene with sensor noise, so the expected triggers are
+//! known exactly:
+//!
+//! - `still`: 30 frames of the scene
+//! - `lights`: 30 frames, with exposure creeping up 1% a frame from frame 10 to 20 and a light
+//!   going on (+25) at frame 22
+//! - `walk`: 40 frames, with someone walking in from the left at frame 10, 4 pixels a frame, and
+//!   gone from frame 30
+
+use flock_motion::{Detector, Grid, Layout, Thresholds};
+use std::fs;
+use std::path::Path;
+
+const FRAME_MS: u64 = 500;
+
+const THRESHOLDS: Thresholds = Thresholds {
+    threshold: 12,
+    min_area: 5,
+    cooldown_ms: 2000,
+};
+
+fn sequence(name: &str) -> Vec<Grid> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/sequences")
+        .join(format!("{}.pgm", name));
+    let data = fs::read(path).unwrap();
+    let mut rest = &data[..];
+    let mut grids = vec![];
+    while !rest.is_empty() {
+        // "P5\n<width> <height>\n255\n"
+        let mut header = rest.splitn(4, |&b| b == b'\n');
+        let magic = header.next().unwrap();
+        let size = std::str::from_utf8(header.next().unwrap()).unwrap();
+        let max = header.next().unwrap();
+        assert_eq!((magic, max), (&b"P5"[..], &b"255"[..]));
+        let (width, height) = size.split_once(' ').unwrap();
+        let (width, height): (usize, usize) = (width.parse().unwrap(), height.parse().unwrap());
+        let pixels = header.next().unwrap();
+        grids.push(Grid::from_frame(pixels, Layout::Grayscale, width, height).unwrap());
+        rest = &pixels[width * height..];
+    }
+    grids
+}
+
+/// Frame numbers that triggered.
+fn triggers(name: &str) -> Vec<usize> {
+    let mut detector = Detector::new();
+    sequence(name)
+        .iter()
+        .enumerate()
+        .filter(|(i, grid)| {
+            detector
+                .feed(grid, *i as u64 * FRAME_MS, &THRESHOLDS)
+                .is_some()
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[test]
+fn noise_alone_never_triggers() {
+    assert_eq!(sequence("still").len(), 30);
+    assert_eq!(triggers("still"), []);
+}
+
+#[test]
+fn exposure_and_lighting_changes_never_trigger() {
+    assert_eq!(triggers("lights"), []);
+}
+
+#[test]
+fn someone_walking_through_triggers_every_cooldown() {
+    let triggers = triggers("walk");
+    assert_eq!(triggers.first(), Some(&10));
+    // 2s cooldown is 4 frames, and the walker is in view until frame 29
+    assert_eq!(triggers, [10, 14, 18, 22, 26]);
+}
@@ -0,0 +1,121 @@
+//! Host side of the serial transport, for camera nodes tethered over USB.
+
+use flock_api::framing::{FramedReceiver, FramedSender};
+use nix::sys::termios::{self, BaudRate, SetArg};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Baud rate the firmware's serial link runs at
+pub const DEFAULT_BAUD_RATE: u32 = 921_600;
+
+/// Opens the serial device at `path` in raw mode and splits it into the two halves of a framed
+/// link. `address` is what the sending half reports as its own address.
+pub fn open(
+    path: impl AsRef<Path>,
+    baud_rate: u32,
+    address: String,
+) -> io::Result<(FramedSender<File>, FramedReceiver<File>)> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(nix::libc::O_NOCTTY)
+        .open(path)?;
+    configure(&file, baud_rate)?;
+    let reader = file.try_clone()?;
+    Ok((FramedSender::new(file, address), FramedReceiver::new(reader)))
+}
+
+/// Puts a terminal into raw 8N1 mode, so frames pass through untouched.
+pub fn configure(file: &File, baud_rate: u32) -> io::Result<()> {
+    let mut attrs = termios::tcgetattr(file.as_raw_fd()).map_err(io::Error::from)?;
+    termios::cfmakeraw(&mut attrs);
+    let baud_rate = baud(baud_rate)?;
+    termios::cfsetispeed(&mut attrs, baud_rate).map_err(io::Error::from)?;
+    termios::cfsetospeed(&mut attrs, baud_rate).map_err(io::Error::from)?;
+    termios::tcsetattr(file.as_raw_fd(), SetArg::TCSANOW, &attrs).map_err(io::Error::from)?;
+    Ok(())
+}
+
+fn baud(rate: u32) -> io::Result<BaudRate> {
+    Ok(match rate {
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115_200 => BaudRate::B115200,
+        230_400 => BaudRate::B230400,
+        460_800 => BaudRate::B460800,
+        921_600 => BaudRate::B921600,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported baud rate {}", rate),
+            ))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flock_api::transport::{Incoming, TransportReceiver, TransportSender};
+    use flock_api::{Instruction, Message, Payload};
+    use nix::pty::openpty;
+    use std::os::unix::io::FromRawFd;
+    use std::thread;
+
+    #[test]
+    fn round_trip_over_pty() {
+        let pty = openpty(None, None).unwrap();
+        let device_path = nix::unistd::ttyname(pty.slave).unwrap();
+        let host = unsafe { File::from_raw_fd(pty.master) };
+        let slave = unsafe { File::from_raw_fd(pty.slave) };
+        // Otherwise the line discipline echoes whatever the host writes before the device opens
+        configure(&slave, DEFAULT_BAUD_RATE).unwrap();
+
+        // Stands in for the node: answers the first instruction it receives
+        let device = thread::spawn(move || {
+            let (mut tx, mut rx) = open(&device_path, DEFAULT_BAUD_RATE, "node".into()).unwrap();
+            assert!(matches!(rx.receive(), Some(Ok(Incoming::Connected))));
+            match rx.receive() {
+                Some(Ok(Incoming::Message(msg))) => {
+                    assert!(matches!(
+                        *msg.payload,
+                        Payload::Instruction(Instruction::ReadSensorConfig)
+                    ));
+                    tx.send(&Message::new(
+                        "node".into(),
+                        msg.client_id,
+                        Payload::Error("no sensor".into()),
+                    ))
+                    .unwrap();
+                }
+                other => panic!("unexpected {:?}", other.map(|r| r.map_err(|e| e.to_string()))),
+            }
+        });
+
+        let reader = host.try_clone().unwrap();
+        let mut tx = FramedSender::new(host, "host".into());
+        let mut rx = FramedReceiver::new(reader);
+        tx.send(&Message::new(
+            "host".into(),
+            "node".into(),
+            Payload::Instruction(Instruction::ReadSensorConfig),
+        ))
+        .unwrap();
+
+        assert!(matches!(rx.receive(), Some(Ok(Incoming::Connected))));
+        match rx.receive() {
+            Some(Ok(Incoming::Message(msg))) => {
+                assert_eq!(msg.client_id, "node");
+                assert_eq!(msg.recipient, "host");
+                assert!(matches!(*msg.payload, Payload::Error(ref e) if e == "no sensor"));
+            }
+            other => panic!("unexpected {:?}", other.map(|r| r.map_err(|e| e.to_string()))),
+        }
+        device.join().unwrap();
+    }
+}
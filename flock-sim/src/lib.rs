@@ -0,0 +1,8 @@
+//! Simulated camera nodes, for working on the controller side without hardware.
+
+pub mod mqtt;
+pub mod node;
+pub mod source;
+
+pub use node::SimNode;
+pub use source::FrameSource;
@@ -0,0 +1,58 @@
+use clap::Parser;
+use flock_sim::{mqtt, FrameSource, SimNode};
+use std::path::PathBuf;
+use std::thread;
+
+/// Runs simulated camera nodes against an MQTT broker.
+#[derive(Parser, Debug)]
+#[command(name = "flock-sim", version)]
+struct Args {
+    #[arg(long, env = "FLOCK_MQTT_HOST", default_value = "localhost")]
+    host: String,
+    #[arg(long, env = "FLOCK_MQTT_PORT", default_value_t = 1883)]
+    port: u16,
+    /// Topic the nodes publish on, the firmware's FLOCK_CONTROLLER_TOPIC
+    #[arg(
+        long,
+        env = "FLOCK_CONTROLLER_TOPIC",
+        default_value = "flock-controller"
+    )]
+    controller_topic: String,
+    /// Number of nodes to run
+    #[arg(long, short = 'n', default_value_t = 1)]
+    count: usize,
+    /// Node N gets the client ID `<prefix><N>`. The firmware's are `flock-client-<FLOCK_CLIENT_ID>`.
+    #[arg(long, default_value = "flock-client-sim-")]
+    id_prefix: String,
+    /// Image file, or directory of images to loop through. Defaults to the color bar test pattern.
+    #[arg(long)]
+    frames: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    // RUST_LOG overrides the level
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let source = match &args.frames {
+        Some(path) => FrameSource::open(path)?,
+        None => FrameSource::ColorBar,
+    };
+
+    let handles: Vec<_> = (0..args.count)
+        .map(|i| {
+            let client_id = format!("{}{}", args.id_prefix, i);
+            let (sender, receiver) =
+                mqtt::connect(&args.host, args.port, &client_id, &args.controller_topic);
+            let node = SimNode::new(client_id, args.controller_topic.clone(), source.clone());
+            thread::spawn(move || node.run(sender, receiver))
+        })
+        .collect();
+    println!(
+        "Running {} simulated node(s) against {}:{}",
+        args.count, args.host, args.port
+    );
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    Ok(())
+}
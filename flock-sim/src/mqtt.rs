@@ -0,0 +1,135 @@
+use flock_api::transport::{Incoming, TransportReceiver, TransportSender};
+use flock_api::{Message, Payload};
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Packet, QoS, RecvTimeoutError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// A QVGA RGB565 frame is ~150 KB, and more than that once it's a JSON array
+const MAX_PACKET_SIZE: usize = 4 * 1024 * 1024;
+// How often the receiver checks its kill switch
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Connects to the broker as `client_id` and subscribes to the topic of the same name, where
+/// the controller sends the node its instructions, and to the broadcast topic. Like the
+/// firmware, the node leaves a `Disconnected` message as its last will on the controller topic.
+pub fn connect(
+    host: &str,
+    port: u16,
+    client_id: &str,
+    controller_topic: &str,
+) -> (MqttSender, MqttReceiver) {
+    let will = Message::new(
+        client_id.into(),
+        controller_topic.into(),
+        Payload::Disconnected,
+    );
+    let mut options = MqttOptions::new(client_id, host, port);
+    options
+        .set_last_will(LastWill::new(
+            controller_topic,
+            flock_api::codec::encode(&will).unwrap(),
+            QoS::AtMostOnce,
+            false,
+        ))
+        .set_keep_alive(Duration::from_secs(30))
+        .set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE)
+        // Keep the subscription across reconnects
+        .set_clean_session(false);
+    let (client, connection) = Client::new(options, 16);
+    // Only queued here, it goes out once the connection is polled
+    client.subscribe(client_id, QoS::AtMostOnce).unwrap();
+    client
+        .subscribe(flock_api::transport::BROADCAST_ADDRESS, QoS::AtMostOnce)
+        .unwrap();
+    (
+        MqttSender {
+            client,
+            topic: client_id.into(),
+        },
+        MqttReceiver {
+            connection,
+            killed: Arc::new(AtomicBool::new(false)),
+        },
+    )
+}
+
+/// Publishes messages on the topic named by their recipient.
+pub struct MqttSender {
+    client: Client,
+    topic: String,
+}
+
+impl TransportSender for MqttSender {
+    type Error = rumqttc::ClientError;
+
+    fn address(&self) -> &str {
+        &self.topic
+    }
+
+    fn subscribe(&mut self, address: &str) -> Result<(), Self::Error> {
+        self.client.subscribe(address, QoS::AtMostOnce)
+    }
+
+    fn send(&mut self, msg: &Message) -> Result<(), Self::Error> {
+        let payload = flock_api::codec::encode(msg).unwrap();
+        self.client
+            .publish(msg.recipient.as_str(), QoS::AtMostOnce, false, payload)
+    }
+
+    /// Clears the handled instruction the broker retained, like the firmware does.
+    fn acknowledge(&mut self) -> Result<(), Self::Error> {
+        self.client
+            .publish(self.topic.as_str(), QoS::AtLeastOnce, true, vec![])
+    }
+}
+
+pub struct MqttReceiver {
+    connection: Connection,
+    killed: Arc<AtomicBool>,
+}
+
+impl MqttReceiver {
+    pub fn kill_switch(&self) -> KillSwitch {
+        KillSwitch(self.killed.clone())
+    }
+}
+
+/// Drops a node's connection without saying goodbye, like a node losing power would, so the
+/// broker publishes its last will.
+#[derive(Clone)]
+pub struct KillSwitch(Arc<AtomicBool>);
+
+impl KillSwitch {
+    pub fn kill(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+impl TransportReceiver for MqttReceiver {
+    type Error = rumqttc::ConnectionError;
+
+    fn receive(&mut self) -> Option<Result<Incoming, Self::Error>> {
+        while !self.killed.load(Ordering::SeqCst) {
+            let event = match self.connection.recv_timeout(POLL_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return None,
+            };
+            return Some(Ok(match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => Incoming::Connected,
+                // An empty payload is a retained message being cleared
+                Ok(Event::Incoming(Packet::Publish(p))) if p.payload.is_empty() => continue,
+                Ok(Event::Incoming(Packet::Publish(p))) => {
+                    match flock_api::codec::decode(&p.payload) {
+                        Ok(msg) => Incoming::Message(msg),
+                        Err(err) => Incoming::Malformed(format!("{:?}", err)),
+                    }
+                }
+                Ok(_) => continue,
+                Err(err) => return Some(Err(err)),
+            }));
+        }
+        None
+    }
+}
@@ -0,0 +1,356 @@
+use crate::source::{self, FrameSource};
+use flock_api::transport::{Incoming, TransportReceiver, TransportSender};
+use flock_api::{
+    Burst, BurstShot, CameraSensorConfig, FrameEncoding, FrameFormat, Instruction, Message,
+    Payload, PixelFormat, PowerMode, Roi, RoiStatus, SensorConfig, SensorData, SensorInfo,
+    ShotSettings,
+};
+use log::*;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Same limit as the firmware
+const MAX_BURST_SHOTS: u8 = 16;
+/// Manual exposure that gives frames as they come from the source
+const SOURCE_AEC_VALUE: f32 = 300.0;
+
+/// A camera node that answers like flock-camera-sensor, with frames from a `FrameSource`.
+pub struct SimNode {
+    client_id: String,
+    controller_topic: String,
+    source: FrameSource,
+    config: CameraSensorConfig,
+    encoding: FrameEncoding,
+    roi: Option<Roi>,
+    readings: usize,
+}
+
+impl SimNode {
+    pub fn new(client_id: String, controller_topic: String, source: FrameSource) -> Self {
+        Self {
+            client_id,
+            controller_topic,
+            source,
+            // What the firmware configures the sensor with on boot
+            config: CameraSensorConfig {
+                brightness: 2,
+                contrast: 0,
+                saturation: 0,
+                sharpness: 0,
+                de_noise: 0,
+                special_effect: 0,
+                wb_mode: 0,
+                awb: true,
+                awb_gain: true,
+                gain_ceiling: 0,
+                lens_correction: true,
+                horizontal_mirror: false,
+                vertical_flip: false,
+            },
+            encoding: FrameEncoding::Raw,
+            roi: None,
+            readings: 0,
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// Answers to `incoming`, addressed to the controller. Only bursts get more than one.
+    pub fn handle(&mut self, incoming: Incoming) -> Vec<Message> {
+        let payloads = match incoming {
+            Incoming::Connected => vec![Payload::Connected],
+            Incoming::Message(msg) => match *msg.payload {
+                Payload::Instruction(Instruction::Burst(burst)) => self.burst(&burst),
+                Payload::Instruction(instruction) => vec![self.handle_instruction(instruction)],
+                _ => vec![],
+            },
+            Incoming::Malformed(message) => vec![Payload::Error(message)],
+        };
+        payloads
+            .into_iter()
+            .map(|p| Message::new(self.client_id.clone(), self.controller_topic.clone(), p))
+            .collect()
+    }
+
+    fn handle_instruction(&mut self, instruction: Instruction) -> Payload {
+        match instruction {
+            Instruction::ReadSensor => self.read_sensor(self.encoding),
+            Instruction::ReadSensorEncoded(encoding) => self.read_sensor(encoding),
+            Instruction::ReadSensorConfig => Payload::SensorConfig(self.sensor_config()),
+            Instruction::WriteSensorConfig(SensorConfig::Camera(config)) => {
+                self.config = config;
+                Payload::SensorConfig(self.sensor_config())
+            }
+            // The AI-Thinker ESP32-CAM's sensor
+            Instruction::ReadSensorInfo => Payload::SensorInfo(SensorInfo {
+                manufacturer_id: 0x7FA2,
+                product_id: 0x2642,
+                version: 0x42,
+                model: Some("OV2640".into()),
+            }),
+            Instruction::ReadPowerMode => Payload::PowerMode(PowerMode::AlwaysOn),
+            Instruction::ReadFrameEncoding => Payload::FrameEncoding(self.encoding),
+            Instruction::WriteFrameEncoding(encoding) => {
+                self.encoding = encoding;
+                Payload::FrameEncoding(self.encoding)
+            }
+            Instruction::ReadRoi => Payload::Roi(self.roi_status()),
+            Instruction::WriteRoi(roi) => {
+                let frame = source::FRAME_FORMAT;
+                if let Some(Err(err)) = roi
+                    .as_ref()
+                    .map(|roi| flock_api::frame::check_roi(roi, frame.width, frame.height))
+                {
+                    return Payload::Error(format!("Invalid region: {}", err));
+                }
+                self.roi = roi;
+                Payload::Roi(self.roi_status())
+            }
+            other => Payload::Error(format!("{:?} is not supported by the simulator", other)),
+        }
+    }
+
+    fn read_sensor(&mut self, encoding: FrameEncoding) -> Payload {
+        match self.take_frame(encoding, 1.0) {
+            Ok(reading) => Payload::SensorReading(reading),
+            Err(err) => Payload::Error(err),
+        }
+    }
+
+    /// Takes every shot before answering, like the firmware. Gain overrides are ignored, exposure
+    /// overrides scale pixel values.
+    fn burst(&mut self, burst: &Burst) -> Vec<Payload> {
+        if burst.count == 0 || burst.count > MAX_BURST_SHOTS {
+            return vec![Payload::Error(format!(
+                "Bursts take 1 to {} shots",
+                MAX_BURST_SHOTS
+            ))];
+        }
+        let encoding = burst.encoding.unwrap_or(self.encoding);
+        let interval = Duration::from_millis(burst.interval_ms.into());
+        let start = Instant::now();
+        let mut shots = vec![];
+        for index in 0..burst.count {
+            let settings = burst
+                .shots
+                .get(usize::from(index))
+                .cloned()
+                .unwrap_or_default();
+            if let Some(wait) = (interval * u32::from(index)).checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+            let offset_ms = start.elapsed().as_millis() as u32;
+            let reading = match self.take_frame(encoding, exposure(&settings)) {
+                Ok(reading) => reading,
+                Err(err) => return vec![Payload::Error(err)],
+            };
+            shots.push(Payload::BurstReading(BurstShot {
+                index,
+                count: burst.count,
+                offset_ms,
+                settings,
+                reading,
+            }));
+        }
+        shots
+    }
+
+    /// The next frame from the source, with `exposure` times the source's.
+    fn take_frame(&mut self, encoding: FrameEncoding, exposure: f32) -> Result<SensorData, String> {
+        let mut frame_buffer = self.source.frame(self.readings);
+        self.readings += 1;
+        source::expose(&mut frame_buffer, exposure);
+        source::orient(
+            &mut frame_buffer,
+            self.config.horizontal_mirror,
+            self.config.vertical_flip,
+        );
+        // There's no sensor to window, so crop like a node with a sensor that can't
+        let (frame_buffer, format) = match &self.roi {
+            Some(roi) => match flock_api::frame::crop(&frame_buffer, &source::FRAME_FORMAT, roi) {
+                Ok(cropped) => cropped,
+                Err(err) => return Err(format!("Error cropping frame: {}", err)),
+            },
+            None => (frame_buffer, source::FRAME_FORMAT),
+        };
+        let reading = SensorData::Camera {
+            frame_buffer,
+            format,
+        };
+        let quality = match encoding {
+            FrameEncoding::Raw => return Ok(reading),
+            FrameEncoding::Jpeg { quality } => quality,
+        };
+        // The node gets to JPEG with esp32-camera's converter, the result is close enough
+        match flock_imaging::decode_reading(&reading)
+            .and_then(|img| flock_imaging::to_jpeg(&img, quality))
+        {
+            Ok(frame_buffer) => Ok(SensorData::Camera {
+                frame_buffer,
+                format: FrameFormat {
+                    pixel_format: PixelFormat::Jpeg,
+                    ..format
+                },
+            }),
+            Err(err) => Err(format!("Error encoding frame as JPEG: {}", err)),
+        }
+    }
+
+    fn roi_status(&self) -> RoiStatus {
+        let (width, height) = match &self.roi {
+            Some(roi) => roi.output_size(),
+            None => (source::FRAME_WIDTH, source::FRAME_HEIGHT),
+        };
+        RoiStatus {
+            roi: self.roi,
+            windowed: false,
+            width,
+            height,
+        }
+    }
+
+    fn sensor_config(&self) -> SensorConfig {
+        SensorConfig::Camera(self.config.clone())
+    }
+
+    /// Serves instructions until the transport closes. Like the firmware, replies go out from a
+    /// separate thread so a transport that is busy delivering can't block on its own sends.
+    pub fn run<S, R>(mut self, mut sender: S, mut receiver: R)
+    where
+        S: TransportSender + Send + 'static,
+        R: TransportReceiver,
+    {
+        // `None` acknowledges the instruction just handled
+        let (tx, rx) = mpsc::channel::<Option<Message>>();
+        let client_id = self.client_id.clone();
+        let publisher = thread::spawn(move || {
+            for msg in rx {
+                let sent = match &msg {
+                    Some(msg) => sender.send(msg),
+                    None => sender.acknowledge(),
+                };
+                if let Err(err) = sent {
+                    error!("{}: error sending message: {:?}", client_id, err);
+                }
+            }
+        });
+
+        while let Some(incoming) = receiver.receive() {
+            let incoming = match incoming {
+                Ok(incoming) => incoming,
+                Err(err) => {
+                    warn!("{}: transport error: {:?}", self.client_id, err);
+                    thread::sleep(RETRY_DELAY);
+                    continue;
+                }
+            };
+            // Undecodable retained messages would be replayed on every reconnect too
+            let received = matches!(incoming, Incoming::Message(_) | Incoming::Malformed(_));
+            if self
+                .handle(incoming)
+                .into_iter()
+                .map(Some)
+                .chain(received.then_some(None))
+                .any(|msg| tx.send(msg).is_err())
+            {
+                break;
+            }
+        }
+        drop(tx);
+        publisher.join().unwrap();
+    }
+}
+
+/// How much light a shot gets relative to the source. AE levels are taken as half stops.
+fn exposure(settings: &ShotSettings) -> f32 {
+    match (settings.aec_value, settings.ae_level) {
+        (Some(value), _) => f32::from(value) / SOURCE_AEC_VALUE,
+        (None, Some(level)) => 2f32.powf(f32::from(level) / 2.0),
+        (None, None) => 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flock_api::transport::Hub;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn next_payload(rx: &mut flock_api::transport::ChannelReceiver) -> Payload {
+        match rx.receive_timeout(TIMEOUT) {
+            Some(Incoming::Message(msg)) => {
+                assert_eq!(msg.client_id, "flock-client-sim-0");
+                *msg.payload
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn answers_like_the_firmware() {
+        let hub = Hub::new();
+        let (mut controller_tx, mut controller_rx) = hub.connect("flock-controller");
+        assert!(matches!(
+            controller_rx.receive_timeout(TIMEOUT),
+            Some(Incoming::Connected)
+        ));
+
+        let node = SimNode::new(
+            "flock-client-sim-0".into(),
+            "flock-controller".into(),
+            FrameSource::ColorBar,
+        );
+        let (node_tx, node_rx) = hub.connect(node.client_id());
+        thread::spawn(move || node.run(node_tx, node_rx));
+        assert!(matches!(
+            next_payload(&mut controller_rx),
+            Payload::Connected
+        ));
+
+        let mut instruct = |instruction| {
+            controller_tx
+                .send(&Message::new(
+                    "flock-controller".into(),
+                    "flock-client-sim-0".into(),
+                    Payload::Instruction(instruction),
+                ))
+                .unwrap();
+            next_payload(&mut controller_rx)
+        };
+
+        let Payload::SensorConfig(SensorConfig::Camera(mut config)) =
+            instruct(Instruction::ReadSensorConfig)
+        else {
+            panic!("expected sensor config");
+        };
+        assert_eq!(config.brightness, 2);
+
+        config.horizontal_mirror = true;
+        assert!(matches!(
+            instruct(Instruction::WriteSensorConfig(SensorConfig::Camera(config))),
+            Payload::SensorConfig(SensorConfig::Camera(c)) if c.horizontal_mirror
+        ));
+
+        let Payload::SensorReading(SensorData::Camera { frame_buffer, .. }) =
+            instruct(Instruction::ReadSensor)
+        else {
+            panic!("expected sensor reading");
+        };
+        assert_eq!(
+            frame_buffer.len(),
+            (source::FRAME_WIDTH * source::FRAME_HEIGHT * 2) as usize
+        );
+        // Mirrored, so the black bar comes first
+        assert_eq!(frame_buffer[..2], [0, 0]);
+
+        assert!(matches!(
+            instruct(Instruction::ListCaptures),
+            Payload::Error(_)
+        ));
+    }
+}
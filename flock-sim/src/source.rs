@@ -0,0 +1,174 @@
+use anyhow::{bail, Context};
+use flock_api::{FrameFormat, PixelFormat};
+use std::path::Path;
+
+/// QVGA, the firmware's default frame size
+pub const FRAME_WIDTH: u32 = 320;
+pub const FRAME_HEIGHT: u32 = 240;
+pub const FRAME_FORMAT: FrameFormat = FrameFormat {
+    pixel_format: PixelFormat::Rgb565,
+    width: FRAME_WIDTH,
+    height: FRAME_HEIGHT,
+};
+
+// The 8 bars of the OV2640 test pattern, left to right
+const COLOR_BARS: [[u8; 3]; 8] = [
+    [255, 255, 255],
+    [255, 255, 0],
+    [0, 255, 255],
+    [0, 255, 0],
+    [255, 0, 255],
+    [255, 0, 0],
+    [0, 0, 255],
+    [0, 0, 0],
+];
+
+/// Where a simulated node gets its frames from. Frames are RGB565 (big endian, as the camera
+/// driver delivers them) at `FRAME_WIDTH` x `FRAME_HEIGHT`, like the firmware's default config.
+#[derive(Clone)]
+pub enum FrameSource {
+    /// The sensor's built-in test pattern, as enabled with `set_color_bar`
+    ColorBar,
+    /// Cycles through decoded images, one per reading
+    Images(Vec<Vec<u8>>),
+}
+
+impl FrameSource {
+    /// Loads a single image, or every image in a directory (in file name order).
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut paths = if path.is_dir() {
+            let mut paths = vec![];
+            for entry in path
+                .read_dir()
+                .with_context(|| format!("Error reading {}", path.display()))?
+            {
+                let path = entry?.path();
+                if image::ImageFormat::from_path(&path).is_ok() {
+                    paths.push(path);
+                }
+            }
+            paths
+        } else {
+            vec![path.to_path_buf()]
+        };
+        paths.sort();
+        if paths.is_empty() {
+            bail!("No images in {}", path.display());
+        }
+        let frames = paths
+            .iter()
+            .map(|path| {
+                let img = image::open(path)
+                    .with_context(|| format!("Error decoding {}", path.display()))?;
+                Ok(to_rgb565(&img))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(FrameSource::Images(frames))
+    }
+
+    /// Frame for the `index`th reading
+    pub fn frame(&self, index: usize) -> Vec<u8> {
+        match self {
+            FrameSource::ColorBar => color_bar(),
+            FrameSource::Images(frames) => frames[index % frames.len()].clone(),
+        }
+    }
+}
+
+fn rgb565([r, g, b]: [u8; 3]) -> [u8; 2] {
+    let px = (u16::from(r) >> 3) << 11 | (u16::from(g) >> 2) << 5 | u16::from(b) >> 3;
+    px.to_be_bytes()
+}
+
+fn to_rgb565(img: &image::DynamicImage) -> Vec<u8> {
+    let img = img
+        .resize_exact(
+            FRAME_WIDTH,
+            FRAME_HEIGHT,
+            image::imageops::FilterType::Triangle,
+        )
+        .to_rgb8();
+    img.pixels().flat_map(|px| rgb565(px.0)).collect()
+}
+
+fn color_bar() -> Vec<u8> {
+    let bar_width = FRAME_WIDTH / COLOR_BARS.len() as u32;
+    (0..FRAME_HEIGHT)
+        .flat_map(|_| 0..FRAME_WIDTH)
+        .flat_map(|x| rgb565(COLOR_BARS[(x / bar_width) as usize]))
+        .collect()
+}
+
+/// Scales every channel of an RGB565 frame by `factor`, saturating like an overexposed sensor.
+pub fn expose(frame: &mut [u8], factor: f32) {
+    if factor == 1.0 {
+        return;
+    }
+    for px in frame.chunks_exact_mut(2) {
+        let v = u16::from_be_bytes([px[0], px[1]]);
+        let scale = |c: u16, max: u16| ((f32::from(c) * factor).round() as u16).min(max);
+        let v =
+            scale(v >> 11, 0x1F) << 11 | scale((v >> 5) & 0x3F, 0x3F) << 5 | scale(v & 0x1F, 0x1F);
+        px.copy_from_slice(&v.to_be_bytes());
+    }
+}
+
+/// Mirrors and/or flips an RGB565 frame in place, as the sensor would.
+pub fn orient(frame: &mut [u8], horizontal_mirror: bool, vertical_flip: bool) {
+    let row_len = FRAME_WIDTH as usize * 2;
+    if horizontal_mirror {
+        for row in frame.chunks_exact_mut(row_len) {
+            let mut px: Vec<[u8; 2]> = row.chunks_exact(2).map(|p| [p[0], p[1]]).collect();
+            px.reverse();
+            row.copy_from_slice(&px.concat());
+        }
+    }
+    if vertical_flip {
+        let rows: Vec<Vec<u8>> = frame.chunks_exact(row_len).rev().map(Vec::from).collect();
+        frame.copy_from_slice(&rows.concat());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(frame: &[u8], x: u32, y: u32) -> [u8; 2] {
+        let i = ((y * FRAME_WIDTH + x) * 2) as usize;
+        [frame[i], frame[i + 1]]
+    }
+
+    #[test]
+    fn color_bar_matches_sensor_pattern() {
+        let frame = FrameSource::ColorBar.frame(0);
+        assert_eq!(frame.len(), (FRAME_WIDTH * FRAME_HEIGHT * 2) as usize);
+        assert_eq!(pixel(&frame, 0, 0), [0xFF, 0xFF]);
+        assert_eq!(pixel(&frame, FRAME_WIDTH - 1, FRAME_HEIGHT - 1), [0, 0]);
+        // Red bar
+        assert_eq!(pixel(&frame, FRAME_WIDTH / 8 * 5, 10), [0xF8, 0x00]);
+    }
+
+    #[test]
+    fn orient_mirrors_and_flips() {
+        let mut frame = FrameSource::ColorBar.frame(0);
+        orient(&mut frame, true, false);
+        assert_eq!(pixel(&frame, 0, 0), [0, 0]);
+        assert_eq!(pixel(&frame, FRAME_WIDTH - 1, 0), [0xFF, 0xFF]);
+
+        orient(&mut frame, false, true);
+        assert_eq!(
+            pixel(&frame, FRAME_WIDTH - 1, FRAME_HEIGHT - 1),
+            [0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn expose_scales_and_saturates() {
+        let mut frame = rgb565([64, 128, 240]).to_vec();
+        expose(&mut frame, 0.5);
+        assert_eq!(frame, rgb565([32, 64, 120]));
+        let mut frame = rgb565([64, 128, 240]).to_vec();
+        expose(&mut frame, 4.0);
+        assert_eq!(frame, rgb565([255, 255, 255]));
+    }
+}
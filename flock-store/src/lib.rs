@@ -0,0 +1,669 @@
+//! Library of the frames the controller received, on disk with an SQLite index.
+//!
+//! Frames are filed under the library's directory as `<device>/<YYYY-MM-DD>/<HHMMSS-mmm>.<ext>`
+//! by capture time in UTC, JPEG frames as they arrived and everything else converted to PNG, with
+//! `flock_imaging::metadata` embedded so files copied out of the library still say where they
+//! came from.
+//! `index.sqlite3` next to them says which node took each frame when, with which sensor config,
+//! and how it's tagged. The GUI, the CLI and the gateway can share a library at the same time,
+//! SQLite takes turns between them.
+
+use flock_api::{PixelFormat, SensorConfig, SensorData};
+use flock_imaging::metadata::{self, CaptureMetadata};
+use rusqlite::types::{Type, Value};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub const INDEX_FILE: &str = "index.sqlite3";
+/// `PRAGMA user_version` of the index, bump with a migration in `Store::open`
+const SCHEMA_VERSION: i32 = 1;
+/// How long to wait for another process writing to the index
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+    CREATE TABLE captures (
+        id INTEGER PRIMARY KEY,
+        device TEXT NOT NULL,
+        captured_at INTEGER NOT NULL,
+        path TEXT NOT NULL UNIQUE,
+        pixel_format TEXT NOT NULL,
+        width INTEGER NOT NULL,
+        height INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        sensor_config TEXT
+    );
+    CREATE INDEX captures_by_device ON captures (device, captured_at);
+    CREATE INDEX captures_by_time ON captures (captured_at);
+    CREATE TABLE tags (
+        capture_id INTEGER NOT NULL REFERENCES captures (id) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (capture_id, tag)
+    );
+    CREATE INDEX tags_by_tag ON tags (tag);
+";
+
+const COLUMNS: &str =
+    "id, device, captured_at, path, pixel_format, width, height, size, sensor_config";
+
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    Io(io::Error),
+    Imaging(flock_imaging::Error),
+    /// The index was made by a newer version of flock
+    Version(i32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sqlite(err) => write!(f, "index error: {}", err),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Imaging(err) => write!(f, "{}", err),
+            Error::Version(version) => write!(
+                f,
+                "index has schema version {}, this version of flock knows up to {}",
+                version, SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Sqlite(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<flock_imaging::Error> for Error {
+    fn from(err: flock_imaging::Error) -> Self {
+        Error::Imaging(err)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub id: i64,
+    pub device: String,
+    /// Milliseconds since the Unix epoch
+    pub captured_at: u64,
+    /// Relative to the library's directory
+    pub path: PathBuf,
+    /// As the node sent the frame, the file is JPEG or PNG
+    pub pixel_format: PixelFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes on disk
+    pub size: u64,
+    /// The node's sensor config when the frame was taken, if known
+    pub sensor_config: Option<SensorConfig>,
+    pub tags: Vec<String>,
+}
+
+/// Captures matching all of the set fields, oldest first unless `newest_first`.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    pub device: Option<String>,
+    /// Milliseconds since the Unix epoch, inclusive
+    pub from: Option<u64>,
+    /// Milliseconds since the Unix epoch, inclusive
+    pub to: Option<u64>,
+    pub tag: Option<String>,
+    pub newest_first: bool,
+    pub limit: Option<usize>,
+}
+
+/// What `Store::prune` removes, oldest captures first.
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    /// Captures kept per device
+    pub max_count: Option<usize>,
+    /// Disk space all captures together may take up
+    pub max_bytes: Option<u64>,
+}
+
+pub struct Store {
+    root: PathBuf,
+    db: Connection,
+}
+
+impl Store {
+    /// Opens the library in `root`, creating it if needed.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self, Error> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        let db = Connection::open(root.join(INDEX_FILE))?;
+        db.busy_timeout(BUSY_TIMEOUT)?;
+        // Readers don't block the writer, and the other way around
+        db.pragma_update(None, "journal_mode", "WAL")?;
+        db.pragma_update(None, "foreign_keys", true)?;
+        let version: i32 = db.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        match version {
+            0 => {
+                db.execute_batch(SCHEMA)?;
+                db.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+            }
+            SCHEMA_VERSION => {}
+            newer => return Err(Error::Version(newer)),
+        }
+        Ok(Self { root, db })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where a capture's file is.
+    pub fn path(&self, capture: &Capture) -> PathBuf {
+        self.root.join(&capture.path)
+    }
+
+    /// Files a frame, with `metadata` embedded in the file, and indexes it.
+    pub fn add(
+        &mut self,
+        metadata: &CaptureMetadata,
+        reading: &SensorData,
+        tags: &[&str],
+    ) -> Result<Capture, Error> {
+        let (device, captured_at) = (metadata.client_id.as_str(), metadata.captured_at);
+        let SensorData::Camera { format, .. } = reading;
+        let (data, (width, height), extension) = match format.pixel_format {
+            PixelFormat::Jpeg => {
+                let (jpeg, size) = flock_imaging::reading_to_jpeg(reading, 100)?;
+                (jpeg, size, "jpg")
+            }
+            _ => {
+                let img = flock_imaging::decode_reading(reading)?;
+                (flock_imaging::to_png(&img)?, img.dimensions(), "png")
+            }
+        };
+
+        let data = metadata::embed(&data, metadata)?;
+
+        let path = self.free_path(device, captured_at, extension);
+        let file = self.root.join(&path);
+        fs::create_dir_all(file.parent().expect("always in a directory"))?;
+        fs::write(&file, &data)?;
+        let capture = Capture {
+            id: 0,
+            device: device.into(),
+            captured_at,
+            path,
+            pixel_format: format.pixel_format,
+            width,
+            height,
+            size: data.len() as u64,
+            sensor_config: metadata.sensor_config.clone(),
+            tags: tags.iter().map(|&tag| tag.into()).collect(),
+        };
+        match self.insert(&capture) {
+            Ok(id) => Ok(Capture { id, ..capture }),
+            Err(err) => {
+                // Don't leave files behind the index doesn't know about
+                let _ = fs::remove_file(self.path(&capture));
+                Err(err)
+            }
+        }
+    }
+
+    pub fn get(&self, id: i64) -> Result<Option<Capture>, Error> {
+        let sql = format!("SELECT {} FROM captures WHERE id = ?", COLUMNS);
+        let capture = self.db.query_row(&sql, [id], capture).optional()?;
+        capture.map(|capture| self.with_tags(capture)).transpose()
+    }
+
+    pub fn query(&self, query: &Query) -> Result<Vec<Capture>, Error> {
+        let mut sql = format!("SELECT {} FROM captures WHERE 1 = 1", COLUMNS);
+        let mut values: Vec<Value> = vec![];
+        if let Some(device) = &query.device {
+            sql += " AND device = ?";
+            values.push(device.clone().into());
+        }
+        if let Some(from) = query.from {
+            sql += " AND captured_at >= ?";
+            values.push((from as i64).into());
+        }
+        if let Some(to) = query.to {
+            sql += " AND captured_at <= ?";
+            values.push((to as i64).into());
+        }
+        if let Some(tag) = &query.tag {
+            sql += " AND id IN (SELECT capture_id FROM tags WHERE tag = ?)";
+            values.push(tag.clone().into());
+        }
+        sql += if query.newest_first {
+            " ORDER BY captured_at DESC, id DESC"
+        } else {
+            " ORDER BY captured_at, id"
+        };
+        if let Some(limit) = query.limit {
+            sql += " LIMIT ?";
+            values.push((limit as i64).into());
+        }
+
+        let mut statement = self.db.prepare(&sql)?;
+        let captures = statement
+            .query_map(rusqlite::params_from_iter(values), capture)?
+            .collect::<Result<Vec<_>, _>>()?;
+        captures
+            .into_iter()
+            .map(|capture| self.with_tags(capture))
+            .collect()
+    }
+
+    /// Devices with captures in the library.
+    pub fn devices(&self) -> Result<Vec<String>, Error> {
+        let mut statement = self
+            .db
+            .prepare("SELECT DISTINCT device FROM captures ORDER BY device")?;
+        let devices = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(devices)
+    }
+
+    pub fn tag(&mut self, id: i64, tag: &str) -> Result<(), Error> {
+        self.db.execute(
+            "INSERT OR IGNORE INTO tags (capture_id, tag) VALUES (?, ?)",
+            params![id, tag],
+        )?;
+        Ok(())
+    }
+
+    pub fn untag(&mut self, id: i64, tag: &str) -> Result<(), Error> {
+        self.db.execute(
+            "DELETE FROM tags WHERE capture_id = ? AND tag = ?",
+            params![id, tag],
+        )?;
+        Ok(())
+    }
+
+    /// Removes a capture and its file.
+    pub fn remove(&mut self, id: i64) -> Result<Option<Capture>, Error> {
+        let Some(capture) = self.get(id)? else {
+            return Ok(None);
+        };
+        self.remove_all(vec![capture])
+            .map(|mut removed| removed.pop())
+    }
+
+    /// Removes the captures `retention` doesn't keep, and returns them.
+    pub fn prune(&mut self, retention: &Retention) -> Result<Vec<Capture>, Error> {
+        self.prune_at(retention, millis(SystemTime::now()))
+    }
+
+    fn prune_at(&mut self, retention: &Retention, now: u64) -> Result<Vec<Capture>, Error> {
+        let mut doomed = BTreeSet::new();
+        if let Some(max_age) = retention.max_age {
+            let oldest = now.saturating_sub(max_age.as_millis() as u64);
+            let mut statement = self
+                .db
+                .prepare("SELECT id FROM captures WHERE captured_at < ?")?;
+            for id in statement.query_map([oldest as i64], |row| row.get::<_, i64>(0))? {
+                doomed.insert(id?);
+            }
+        }
+        if let Some(max_count) = retention.max_count {
+            let mut statement = self.db.prepare(
+                "SELECT id FROM (
+                    SELECT id, ROW_NUMBER() OVER (
+                        PARTITION BY device ORDER BY captured_at DESC, id DESC
+                    ) AS n
+                    FROM captures
+                ) WHERE n > ?",
+            )?;
+            for id in statement.query_map([max_count as i64], |row| row.get::<_, i64>(0))? {
+                doomed.insert(id?);
+            }
+        }
+        if let Some(max_bytes) = retention.max_bytes {
+            // Newest first, whatever the other rules keep counts towards the quota
+            let mut statement = self
+                .db
+                .prepare("SELECT id, size FROM captures ORDER BY captured_at DESC, id DESC")?;
+            let mut total = 0;
+            for row in statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))? {
+                let (id, size): (i64, i64) = row?;
+                if doomed.contains(&id) {
+                    continue;
+                }
+                total += size as u64;
+                if total > max_bytes {
+                    doomed.insert(id);
+                }
+            }
+        }
+
+        let captures = doomed
+            .into_iter()
+            .filter_map(|id| self.get(id).transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+        self.remove_all(captures)
+    }
+
+    fn remove_all(&mut self, captures: Vec<Capture>) -> Result<Vec<Capture>, Error> {
+        let tx = self.db.transaction()?;
+        for capture in &captures {
+            tx.execute("DELETE FROM captures WHERE id = ?", [capture.id])?;
+        }
+        tx.commit()?;
+        for capture in &captures {
+            let path = self.path(capture);
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+            // Only goes through once the day's directory is empty
+            if let Some(dir) = path.parent() {
+                let _ = fs::remove_dir(dir);
+            }
+        }
+        Ok(captures)
+    }
+
+    fn insert(&mut self, capture: &Capture) -> Result<i64, Error> {
+        let sensor_config = capture
+            .sensor_config
+            .as_ref()
+            .map(|config| serde_json::to_string(config).expect("configs always serialize"));
+        let tx = self.db.transaction()?;
+        tx.execute(
+            "INSERT INTO captures
+                (device, captured_at, path, pixel_format, width, height, size, sensor_config)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                capture.device,
+                capture.captured_at as i64,
+                capture.path.to_string_lossy(),
+                pixel_format_name(capture.pixel_format),
+                capture.width,
+                capture.height,
+                capture.size as i64,
+                sensor_config,
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        for tag in &capture.tags {
+            tx.execute(
+                "INSERT OR IGNORE INTO tags (capture_id, tag) VALUES (?, ?)",
+                params![id, tag],
+            )?;
+        }
+        tx.commit()?;
+        Ok(id)
+    }
+
+    fn with_tags(&self, capture: Capture) -> Result<Capture, Error> {
+        let mut statement = self
+            .db
+            .prepare_cached("SELECT tag FROM tags WHERE capture_id = ? ORDER BY tag")?;
+        let tags = statement
+            .query_map([capture.id], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(Capture { tags, ..capture })
+    }
+
+    /// A path for a new capture, counting up if another capture got there in the same
+    /// millisecond.
+    fn free_path(&self, device: &str, captured_at: u64, extension: &str) -> PathBuf {
+        let timestamp = flock_imaging::timelapse::utc(captured_at / 1000);
+        let (date, time) = timestamp.split_once(' ').expect("date and time");
+        let dir = Path::new(&flock_imaging::file_name(device)).join(date);
+        let stem = format!("{}-{:03}", time.replace(':', ""), captured_at % 1000);
+        (1..)
+            .map(|n| match n {
+                1 => dir.join(format!("{}.{}", stem, extension)),
+                n => dir.join(format!("{}-{}.{}", stem, n, extension)),
+            })
+            .find(|path| !self.root.join(path).exists())
+            .expect("some suffix is free")
+    }
+}
+
+fn capture(row: &Row) -> rusqlite::Result<Capture> {
+    let pixel_format: String = row.get(4)?;
+    let sensor_config: Option<String> = row.get(8)?;
+    let json_error = |column| {
+        move |err| rusqlite::Error::FromSqlConversionFailure(column, Type::Text, Box::new(err))
+    };
+    Ok(Capture {
+        id: row.get(0)?,
+        device: row.get(1)?,
+        captured_at: row.get::<_, i64>(2)? as u64,
+        path: PathBuf::from(row.get::<_, String>(3)?),
+        pixel_format: serde_json::from_value(pixel_format.into()).map_err(json_error(4))?,
+        width: row.get(5)?,
+        height: row.get(6)?,
+        size: row.get::<_, i64>(7)? as u64,
+        sensor_config: sensor_config
+            .map(|json| serde_json::from_str(&json))
+            .transpose()
+            .map_err(json_error(8))?,
+        tags: vec![],
+    })
+}
+
+/// The name `PixelFormat` has on the wire.
+fn pixel_format_name(format: PixelFormat) -> String {
+    match serde_json::to_value(format) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => unreachable!("unit variants serialize as strings"),
+    }
+}
+
+fn millis(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flock_api::FrameFormat;
+
+    /// A library in a fresh temporary directory, removed afterwards
+    struct TempLibrary(PathBuf);
+
+    impl TempLibrary {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("flock-store-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+
+        fn open(&self) -> Store {
+            Store::open(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for TempLibrary {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn gray(level: u8) -> SensorData {
+        SensorData::Camera {
+            frame_buffer: vec![level; 4 * 3],
+            format: FrameFormat {
+                pixel_format: PixelFormat::Grayscale,
+                width: 4,
+                height: 3,
+            },
+        }
+    }
+
+    fn config(brightness: i8) -> SensorConfig {
+        SensorConfig::Camera(flock_api::CameraSensorConfig {
+            brightness,
+            contrast: 0,
+            saturation: 0,
+            sharpness: 0,
+            de_noise: 0,
+            special_effect: 0,
+            wb_mode: 0,
+            awb: true,
+            awb_gain: true,
+            gain_ceiling: 0,
+            lens_correction: true,
+            horizontal_mirror: false,
+            vertical_flip: false,
+        })
+    }
+
+    fn from(device: &str, captured_at: u64) -> CaptureMetadata {
+        CaptureMetadata {
+            client_id: device.into(),
+            captured_at,
+            sensor: None,
+            sensor_config: None,
+        }
+    }
+
+    fn ids(captures: &[Capture]) -> Vec<i64> {
+        captures.iter().map(|c| c.id).collect()
+    }
+
+    #[test]
+    fn frames_are_filed_and_indexed() {
+        let library = TempLibrary::new("filed");
+        let mut store = library.open();
+        let capture = store
+            .add(
+                &CaptureMetadata {
+                    sensor_config: Some(config(2)),
+                    ..from("cam/1", 1_660_000_000_123)
+                },
+                &gray(9),
+                &["door"],
+            )
+            .unwrap();
+        assert_eq!(capture.path, Path::new("cam_1/2022-08-08/230640-123.png"));
+        let img = flock_imaging::image::open(store.path(&capture)).unwrap();
+        assert_eq!((img.width(), img.height()), (4, 3));
+        let embedded = metadata::read_file(&store.path(&capture)).unwrap().unwrap();
+        assert_eq!(embedded.client_id, "cam/1");
+        assert_eq!(embedded.captured_at, 1_660_000_000_123);
+
+        // Another frame in the same millisecond, and the index surviving a reopen
+        let twin = store
+            .add(&from("cam/1", 1_660_000_000_123), &gray(9), &[])
+            .unwrap();
+        assert_eq!(twin.path, Path::new("cam_1/2022-08-08/230640-123-2.png"));
+        drop(store);
+        let store = library.open();
+        let read = store.get(capture.id).unwrap().unwrap();
+        assert_eq!(read.pixel_format, PixelFormat::Grayscale);
+        assert_eq!((read.width, read.height, read.size), (4, 3, capture.size));
+        assert_eq!(read.tags, ["door"]);
+        assert!(matches!(
+            read.sensor_config,
+            Some(SensorConfig::Camera(c)) if c.brightness == 2
+        ));
+        assert_eq!(store.devices().unwrap(), ["cam/1"]);
+    }
+
+    #[test]
+    fn queries_by_device_time_and_tag() {
+        let library = TempLibrary::new("query");
+        let mut store = library.open();
+        let a1 = store.add(&from("a", 1000), &gray(0), &["x"]).unwrap();
+        let b1 = store.add(&from("b", 2000), &gray(0), &[]).unwrap();
+        let a2 = store.add(&from("a", 3000), &gray(0), &[]).unwrap();
+        store.tag(a2.id, "x").unwrap();
+        store.tag(b1.id, "y").unwrap();
+
+        let query = |query: Query| ids(&store.query(&query).unwrap());
+        assert_eq!(query(Query::default()), [a1.id, b1.id, a2.id]);
+        let device = Some("a".to_string());
+        assert_eq!(
+            query(Query {
+                device: device.clone(),
+                ..Default::default()
+            }),
+            [a1.id, a2.id]
+        );
+        assert_eq!(
+            query(Query {
+                from: Some(1500),
+                to: Some(3000),
+                ..Default::default()
+            }),
+            [b1.id, a2.id]
+        );
+        assert_eq!(
+            query(Query {
+                tag: Some("x".into()),
+                newest_first: true,
+                limit: Some(1),
+                ..Default::default()
+            }),
+            [a2.id]
+        );
+
+        store.untag(a2.id, "x").unwrap();
+        assert_eq!(
+            store.get(a2.id).unwrap().unwrap().tags,
+            Vec::<String>::new()
+        );
+        assert!(store.remove(a1.id).unwrap().is_some());
+        assert!(!store.path(&a1).exists());
+        assert!(store.get(a1.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn retention_removes_the_oldest() {
+        let library = TempLibrary::new("retention");
+        let mut store = library.open();
+        let mut added = vec![];
+        for (device, at) in [("a", 1000), ("a", 2000), ("a", 3000), ("b", 4000)] {
+            added.push(store.add(&from(device, at), &gray(0), &[]).unwrap());
+        }
+        let size = added[0].size;
+
+        let by_age = Retention {
+            max_age: Some(Duration::from_millis(8500)),
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&store.prune_at(&by_age, 10_000).unwrap()),
+            [added[0].id]
+        );
+        assert!(!store.path(&added[0]).exists());
+
+        let by_count = Retention {
+            max_count: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&store.prune_at(&by_count, 10_000).unwrap()),
+            [added[1].id]
+        );
+
+        let by_quota = Retention {
+            max_bytes: Some(size),
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&store.prune_at(&by_quota, 10_000).unwrap()),
+            [added[2].id]
+        );
+        assert_eq!(ids(&store.query(&Query::default()).unwrap()), [added[3].id]);
+    }
+}